@@ -0,0 +1,1059 @@
+//! API client for the Pocket Ark server endpoints
+
+use super::{
+    headers::{self, X_TOKEN},
+    lookup::LookupReport,
+    HttpClient, CREATE_ACCOUNT_ENDPOINT, LOGIN_ENDPOINT, TUNNEL_ENDPOINT, UPGRADE_ENDPOINT,
+    USER_AGENT,
+};
+use crate::{
+    proxy::ProxyConfig,
+    transport::{ServerStream, UpgradeTransport, WebSocketIo},
+};
+use futures::future::BoxFuture;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Body, HeaderMap, StatusCode,
+};
+use log::{debug, error, warn};
+use parking_lot::RwLock;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Formatter},
+    future::Future,
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::{client::generate_key, derive_accept_key},
+        protocol::Role,
+    },
+    WebSocketStream,
+};
+use url::{Position, Url};
+
+/// Request structure for creating a new user
+#[derive(Debug, Serialize)]
+pub struct CreateUserRequest {
+    /// The email for the user to create
+    pub email: String,
+    /// The username for the user to create
+    pub username: String,
+    /// The password for the user to create
+    pub password: String,
+}
+
+/// Request structure for creating a new user
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginUserRequest {
+    /// The email for the user to login
+    pub email: String,
+    /// The password for the user to login
+    pub password: String,
+}
+
+/// Authentication token
+pub type AuthToken = Arc<str>;
+
+/// Response structure for a token auth response
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    /// The authorization token
+    pub token: String,
+}
+
+/// Errors that can occur when using the [`PocketArkApi`]
+#[derive(Debug, Error)]
+pub enum ApiError {
+    /// The server url was invalid
+    #[error("Invalid Connection URL: {0}")]
+    InvalidHostTarget(#[from] url::ParseError),
+    /// Initial HTTP request failure
+    #[error("Request failed: {0}")]
+    RequestFailed(reqwest::Error),
+    /// Server responded with an error status, includes the response text
+    /// if the server provided one
+    #[error("Server error response: {0} {1}")]
+    ErrorResponse(reqwest::Error, String),
+    /// Server response was malformed
+    #[error("Invalid server response: {0}")]
+    InvalidResponse(reqwest::Error),
+    /// Upgrading the connection failed
+    #[error("Upgrade failed: {0}")]
+    UpgradeFailure(reqwest::Error),
+    /// Failed to open a tunnel through the proxy
+    #[error("Failed to connect through proxy: {0}")]
+    ProxyConnect(std::io::Error),
+    /// Upgrading the connection through a proxy tunnel failed
+    #[error("Upgrade through proxy failed: {0}")]
+    TunnelledUpgradeFailure(hyper::Error),
+    /// Server rejected an upgrade sent through a proxy tunnel, includes
+    /// the response text if the server provided one
+    #[error("Server rejected upgrade: {0} {1}")]
+    UpgradeRejected(StatusCode, String),
+    /// Server didn't complete the WebSocket handshake correctly
+    #[error("Server WebSocket handshake response was invalid")]
+    InvalidWebSocketHandshake,
+    /// Server wasn't a valid pocket relay server
+    #[error("Server identifier was incorrect (Not a PocketArk server?)")]
+    NotPocketArk,
+    /// Server version is too old
+    #[error("Server version is too outdated ({0}) this client requires servers of version {1} or greater")]
+    ServerOutdated(Version, Version),
+    /// Endpoint requires an authentication token but the client
+    /// has not been authenticated
+    #[error("Missing authentication token")]
+    MissingToken,
+    /// Endpoint requires an association token but the server
+    /// did not provide one
+    #[error("Missing association token")]
+    MissingAssociation,
+    /// Every attempt at looking up the server failed
+    #[error("Failed to connect to server: {0}")]
+    LookupFailed(LookupReport),
+    /// Server rejected the authentication token and there are no
+    /// credentials available to re-authenticate with
+    #[error("No credentials available for re-authentication")]
+    ReauthUnavailable,
+}
+
+impl ApiError {
+    /// Whether the error is the server rejecting the authentication
+    /// token with a 401 or 403 status
+    pub fn is_auth_rejected(&self) -> bool {
+        match self {
+            ApiError::ErrorResponse(err, _) => err.status().is_some_and(is_auth_status),
+            ApiError::UpgradeRejected(status, _) => is_auth_status(*status),
+            _ => false,
+        }
+    }
+
+    /// Whether the error is the server or an intermediary refusing the
+    /// upgrade itself rather than the request failing, such as responding
+    /// without upgrading or with a 426 or 501 status
+    fn is_upgrade_rejected(&self) -> bool {
+        let status = match self {
+            ApiError::UpgradeFailure(_) => return true,
+            ApiError::ErrorResponse(err, _) => err.status(),
+            ApiError::UpgradeRejected(status, _) => Some(*status),
+            _ => None,
+        };
+
+        status.is_some_and(|status| {
+            status.is_success()
+                || matches!(
+                    status,
+                    StatusCode::UPGRADE_REQUIRED | StatusCode::NOT_IMPLEMENTED
+                )
+        })
+    }
+}
+
+/// Whether the status is the server rejecting authentication
+///
+/// ## Arguments
+/// * `status` - The response status
+pub(super) fn is_auth_status(status: StatusCode) -> bool {
+    matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+}
+
+/// Callback provided by the host to obtain login credentials when the
+/// server rejects the authentication token, [None] cancels re-authentication
+pub type CredentialCallback =
+    Arc<dyn Fn() -> BoxFuture<'static, Option<LoginUserRequest>> + Send + Sync>;
+
+/// Credentials used to re-authenticate when the server rejects
+/// the authentication token
+#[derive(Clone)]
+pub enum ReauthCredentials {
+    /// Stored login credentials
+    Stored(LoginUserRequest),
+    /// Callback to obtain the login credentials from the host
+    Callback(CredentialCallback),
+}
+
+impl Debug for ReauthCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Credentials are intentionally omitted
+        match self {
+            ReauthCredentials::Stored(_) => f.write_str("Stored"),
+            ReauthCredentials::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+/// Client for the Pocket Ark server API, holds the details required
+/// for making requests to each of the server endpoints
+///
+/// Clones of the client share the same authentication token. When
+/// re-authentication credentials are provided, requests rejected due to
+/// the authentication token are retried once using a fresh token
+#[derive(Debug, Clone)]
+pub struct PocketArkApi {
+    /// HTTP client for making requests with
+    http_client: HttpClient,
+    /// Base URL of the connected server
+    base_url: Url,
+    /// Optional association token
+    association: Option<String>,
+    /// Authentication token if the client has authenticated
+    token: Arc<RwLock<Option<AuthToken>>>,
+    /// Credentials for re-authenticating
+    reauth: Arc<RwLock<Option<ReauthCredentials>>>,
+    /// Lock held while re-authenticating so that concurrent rejected
+    /// requests only re-authenticate once
+    reauth_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Token that a proxied request was still rejected with after
+    /// re-authenticating, rejected proxied requests don't re-authenticate
+    /// again until the token changes
+    pub(super) proxy_rejected_token: Arc<RwLock<Option<AuthToken>>>,
+    /// Transport used for upgrading the server connections
+    upgrade_transport: Arc<RwLock<UpgradeTransport>>,
+}
+
+impl PocketArkApi {
+    /// Creates a new API client for the server at the provided URL
+    ///
+    /// ## Arguments
+    /// * `http_client` - The HTTP client to connect with
+    /// * `base_url`    - The server base URL (Connection URL)
+    /// * `association` - Optional client association token
+    pub fn new(http_client: HttpClient, base_url: Url, association: Option<String>) -> Self {
+        Self {
+            http_client,
+            base_url,
+            association,
+            token: Default::default(),
+            reauth: Default::default(),
+            reauth_lock: Default::default(),
+            proxy_rejected_token: Default::default(),
+            upgrade_transport: Arc::new(RwLock::new(UpgradeTransport::Raw)),
+        }
+    }
+
+    /// The HTTP client used for making requests
+    pub fn http_client(&self) -> &HttpClient {
+        &self.http_client
+    }
+
+    /// The server base URL
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// The association token if the server provided one
+    pub fn association(&self) -> Option<&str> {
+        self.association.as_deref()
+    }
+
+    /// The transport used for upgrading the server connections
+    pub fn upgrade_transport(&self) -> UpgradeTransport {
+        *self.upgrade_transport.read()
+    }
+
+    /// Sets the transport used for upgrading the server connections,
+    /// raw upgrades that are refused still fall back to a WebSocket
+    /// upgrade for that connection without changing the transport
+    ///
+    /// ## Arguments
+    /// * `transport` - The transport to use
+    pub fn set_upgrade_transport(&self, transport: UpgradeTransport) {
+        *self.upgrade_transport.write() = transport;
+    }
+
+    /// The authentication token if the client has authenticated
+    pub fn token(&self) -> Option<AuthToken> {
+        self.token.read().clone()
+    }
+
+    /// Sets the authentication token to use for authenticated requests
+    ///
+    /// ## Arguments
+    /// * `token` - The authentication token
+    pub fn set_token(&self, token: AuthToken) {
+        *self.token.write() = Some(token);
+    }
+
+    /// Sets the credentials used to re-authenticate when the server
+    /// rejects the authentication token
+    ///
+    /// ## Arguments
+    /// * `credentials` - The credentials, [None] to disable re-authentication
+    pub fn set_reauth_credentials(&self, credentials: Option<ReauthCredentials>) {
+        *self.reauth.write() = credentials;
+    }
+
+    /// Checks whether credentials are available for re-authenticating
+    pub(super) fn has_reauth_credentials(&self) -> bool {
+        self.reauth.read().is_some()
+    }
+
+    /// Logs in using the re-authentication credentials and replaces
+    /// the authentication token
+    ///
+    /// ## Arguments
+    /// * `rejected` - The token that was rejected by the server
+    pub(super) async fn reauthenticate(
+        &self,
+        rejected: Option<AuthToken>,
+    ) -> Result<AuthToken, ApiError> {
+        let _guard = self.reauth_lock.lock().await;
+
+        // Cloned so the lock isn't held across the login
+        let credentials = self
+            .reauth
+            .read()
+            .clone()
+            .ok_or(ApiError::ReauthUnavailable)?;
+
+        // Another request already replaced the rejected token
+        if let Some(current) = self.token() {
+            if rejected.as_ref() != Some(&current) {
+                return Ok(current);
+            }
+        }
+
+        let request = match credentials {
+            ReauthCredentials::Stored(request) => request,
+            ReauthCredentials::Callback(callback) => {
+                callback().await.ok_or(ApiError::ReauthUnavailable)?
+            }
+        };
+
+        let token = self.login_user(request).await?;
+        self.set_token(token.clone());
+        Ok(token)
+    }
+
+    /// Runs the provided action, if the action fails due to the server
+    /// rejecting the authentication token the client re-authenticates
+    /// and the action is retried once
+    ///
+    /// ## Arguments
+    /// * `action` - The action to run
+    async fn with_reauth<T, F, Fut>(&self, action: F) -> Result<T, ApiError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let token = self.token();
+
+        match action().await {
+            Err(err) if err.is_auth_rejected() && self.has_reauth_credentials() => {
+                warn!("Server rejected authentication token, re-authenticating");
+
+                if let Err(reauth_err) = self.reauthenticate(token).await {
+                    error!("Failed to re-authenticate: {}", reauth_err);
+                    return Err(err);
+                }
+
+                action().await
+            }
+            result => result,
+        }
+    }
+
+    /// Creates a URL for the provided path relative to the server base URL
+    ///
+    /// ## Arguments
+    /// * `path` - The path relative to the base URL
+    pub fn endpoint_url(&self, path: &str) -> Result<Url, ApiError> {
+        Ok(self.base_url.join(path)?)
+    }
+
+    /// Obtains the authentication token header value
+    pub(super) fn token_header(&self) -> Result<HeaderValue, ApiError> {
+        let token = self.token().ok_or(ApiError::MissingToken)?;
+        Ok(HeaderValue::from_str(&token).expect("Invalid token"))
+    }
+
+    /// Attempts to create a new user account, returns the
+    /// authentication token on success
+    ///
+    /// ## Arguments
+    /// * `request` - The account creation request
+    pub async fn create_user(&self, request: CreateUserRequest) -> Result<AuthToken, ApiError> {
+        self.token_request(CREATE_ACCOUNT_ENDPOINT, &request).await
+    }
+
+    /// Attempts to login to a user account, returns the
+    /// authentication token on success
+    ///
+    /// ## Arguments
+    /// * `request` - The account login request
+    pub async fn login_user(&self, request: LoginUserRequest) -> Result<AuthToken, ApiError> {
+        self.token_request(LOGIN_ENDPOINT, &request).await
+    }
+
+    /// Sends a request to an endpoint that responds with an
+    /// authentication token
+    ///
+    /// ## Arguments
+    /// * `endpoint` - The endpoint to request
+    /// * `request`  - The request body
+    async fn token_request<R: Serialize>(
+        &self,
+        endpoint: &str,
+        request: &R,
+    ) -> Result<AuthToken, ApiError> {
+        let endpoint_url = self.endpoint_url(endpoint)?;
+
+        // Send the HTTP request and get its response
+        let response = self
+            .http_client
+            .client()
+            .post(endpoint_url)
+            .json(request)
+            .send()
+            .await
+            .map_err(ApiError::RequestFailed)?;
+
+        // Handle server error responses
+        let response = error_for_status(response).await?;
+
+        let response: TokenResponse = response.json().await.map_err(ApiError::InvalidResponse)?;
+        Ok(Arc::<str>::from(response.token.as_str()))
+    }
+
+    /// Creates a BlazeSDK upgraded stream using HTTP upgrades
+    /// with the Pocket Relay server, uses the current upgrade transport
+    pub async fn create_server_stream(&self) -> Result<ServerStream, ApiError> {
+        self.with_reauth(|| self.try_create_server_stream()).await
+    }
+
+    /// Single attempt at [`PocketArkApi::create_server_stream`]
+    async fn try_create_server_stream(&self) -> Result<ServerStream, ApiError> {
+        let mut headers: HeaderMap<HeaderValue> =
+            [(HeaderName::from_static(X_TOKEN), self.token_header()?)]
+                .into_iter()
+                .collect();
+
+        // Include association token
+        if let Some(association) = &self.association {
+            headers.insert(
+                HeaderName::from_static(headers::ASSOCIATION),
+                HeaderValue::from_str(association).expect("Invalid association token"),
+            );
+        }
+
+        self.upgrade(UPGRADE_ENDPOINT, "blaze", headers).await
+    }
+
+    /// Creates a networking tunnel for game packets, uses the current
+    /// upgrade transport
+    pub async fn create_server_tunnel(&self) -> Result<ServerStream, ApiError> {
+        self.with_reauth(|| self.try_create_server_tunnel()).await
+    }
+
+    /// Single attempt at [`PocketArkApi::create_server_tunnel`]
+    async fn try_create_server_tunnel(&self) -> Result<ServerStream, ApiError> {
+        let association = self
+            .association
+            .as_ref()
+            .ok_or(ApiError::MissingAssociation)?;
+
+        let headers: HeaderMap<HeaderValue> = [(
+            HeaderName::from_static(headers::ASSOCIATION),
+            HeaderValue::from_str(association).expect("Invalid association token"),
+        )]
+        .into_iter()
+        .collect();
+
+        self.upgrade(TUNNEL_ENDPOINT, "tunnel", headers).await
+    }
+
+    /// Upgrades a connection to an endpoint using the provided protocol,
+    /// falls back to a WebSocket upgrade for this connection only if the
+    /// raw upgrade is refused
+    ///
+    /// ## Arguments
+    /// * `endpoint` - The endpoint to upgrade
+    /// * `protocol` - The protocol to upgrade to
+    /// * `headers`  - Additional headers to include
+    async fn upgrade(
+        &self,
+        endpoint: &str,
+        protocol: &'static str,
+        headers: HeaderMap,
+    ) -> Result<ServerStream, ApiError> {
+        if self.upgrade_transport() == UpgradeTransport::WebSocket {
+            return self.upgrade_websocket(endpoint, protocol, headers).await;
+        }
+
+        let err = match self.upgrade_raw(endpoint, protocol, headers.clone()).await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        // Only a refused upgrade can be solved by a WebSocket, other failures
+        // such as server errors shouldn't switch the transport
+        if !err.is_upgrade_rejected() {
+            return Err(err);
+        }
+
+        warn!("Raw upgrade failed, attempting WebSocket upgrade: {}", err);
+
+        self.upgrade_websocket(endpoint, protocol, headers)
+            .await
+            .map_err(|ws_err| {
+                debug!("WebSocket upgrade failed: {}", ws_err);
+                err
+            })
+    }
+
+    /// Upgrades a connection using a raw HTTP upgrade to the protocol
+    ///
+    /// ## Arguments
+    /// * `endpoint` - The endpoint to upgrade
+    /// * `protocol` - The protocol to upgrade to
+    /// * `headers`  - Additional headers to include
+    async fn upgrade_raw(
+        &self,
+        endpoint: &str,
+        protocol: &'static str,
+        mut headers: HeaderMap,
+    ) -> Result<ServerStream, ApiError> {
+        let endpoint_url = self.endpoint_url(endpoint)?;
+
+        // Headers to provide when upgrading
+        headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static(protocol));
+
+        self.send_upgrade(endpoint_url, headers)
+            .await
+            .map(|(_, stream)| stream)
+    }
+
+    /// Upgrades a connection to a WebSocket carrying the protocol
+    ///
+    /// ## Arguments
+    /// * `endpoint` - The endpoint to upgrade
+    /// * `protocol` - The protocol carried by the WebSocket
+    /// * `headers`  - Additional headers to include
+    async fn upgrade_websocket(
+        &self,
+        endpoint: &str,
+        protocol: &'static str,
+        mut headers: HeaderMap,
+    ) -> Result<ServerStream, ApiError> {
+        let endpoint_url = self.endpoint_url(endpoint)?;
+        let key = generate_key();
+
+        // Headers for the WebSocket handshake
+        headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(
+            header::SEC_WEBSOCKET_VERSION,
+            HeaderValue::from_static("13"),
+        );
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(protocol),
+        );
+        headers.insert(
+            header::SEC_WEBSOCKET_KEY,
+            HeaderValue::from_str(&key).expect("Invalid WebSocket key"),
+        );
+
+        let (response_headers, upgraded) = self.send_upgrade(endpoint_url, headers).await?;
+
+        // Ensure the server completed the WebSocket handshake
+        let accept = derive_accept_key(key.as_bytes());
+        if response_headers
+            .get(header::SEC_WEBSOCKET_ACCEPT)
+            .map_or(true, |value| value.as_bytes() != accept.as_bytes())
+        {
+            return Err(ApiError::InvalidWebSocketHandshake);
+        }
+
+        // Ensure the server selected the requested protocol
+        if response_headers
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .map_or(true, |value| value.as_bytes() != protocol.as_bytes())
+        {
+            return Err(ApiError::InvalidWebSocketHandshake);
+        }
+
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+
+        Ok(Box::new(WebSocketIo::new(socket)))
+    }
+
+    /// Sends an upgrade request returning the response headers and the
+    /// upgraded connection, connections through an HTTP proxy to an HTTP
+    /// server are sent through a CONNECT tunnel
+    ///
+    /// ## Arguments
+    /// * `url`     - The URL to upgrade
+    /// * `headers` - The request headers including the upgrade headers
+    async fn send_upgrade(
+        &self,
+        url: Url,
+        headers: HeaderMap,
+    ) -> Result<(HeaderMap, ServerStream), ApiError> {
+        if let Some(proxy) = self
+            .http_client
+            .proxy()
+            .filter(|proxy| proxy.requires_tunnel(&url))
+        {
+            return send_tunnelled_upgrade(proxy, url, headers).await;
+        }
+
+        // Send the HTTP request and get its response
+        let response = self
+            .http_client
+            .client()
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(ApiError::RequestFailed)?;
+
+        // Handle server error responses
+        let response = error_for_status(response).await?;
+        let headers = response.headers().clone();
+
+        // Upgrade the connection
+        let upgraded = response.upgrade().await.map_err(ApiError::UpgradeFailure)?;
+
+        Ok((headers, Box::new(upgraded)))
+    }
+}
+
+/// Sends an upgrade request through a CONNECT tunnel opened using the proxy
+///
+/// ## Arguments
+/// * `proxy`   - The HTTP proxy
+/// * `url`     - The URL to upgrade
+/// * `headers` - The request headers including the upgrade headers
+async fn send_tunnelled_upgrade(
+    proxy: &ProxyConfig,
+    url: Url,
+    headers: HeaderMap,
+) -> Result<(HeaderMap, ServerStream), ApiError> {
+    let host = url.host_str().ok_or(url::ParseError::EmptyHost)?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let stream = proxy
+        .connect_tunnel(host, port)
+        .await
+        .map_err(ApiError::ProxyConnect)?;
+
+    let (mut sender, connection) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(ApiError::TunnelledUpgradeFailure)?;
+
+    // Drive the connection until the upgrade completes
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("Tunnelled upgrade connection failed: {}", err);
+        }
+    });
+
+    let mut request = hyper::Request::get(&url[Position::BeforePath..])
+        .body(Body::empty())
+        .expect("Invalid upgrade request");
+
+    let request_headers = request.headers_mut();
+    *request_headers = headers;
+    request_headers.insert(
+        header::HOST,
+        HeaderValue::from_str(&format!("{}:{}", host, port)).expect("Invalid host header"),
+    );
+    request_headers.insert(header::USER_AGENT, HeaderValue::from_static(USER_AGENT));
+
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(ApiError::TunnelledUpgradeFailure)?;
+
+    let status = response.status();
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap_or_default();
+        return Err(ApiError::UpgradeRejected(
+            status,
+            String::from_utf8_lossy(&body).to_string(),
+        ));
+    }
+
+    let headers = response.headers().clone();
+    let upgraded = hyper::upgrade::on(response)
+        .await
+        .map_err(ApiError::TunnelledUpgradeFailure)?;
+
+    Ok((headers, Box::new(upgraded)))
+}
+
+/// Converts error status responses into [`ApiError::ErrorResponse`]
+/// including the response text
+///
+/// ## Arguments
+/// * `response` - The response to check
+pub(super) async fn error_for_status(
+    response: reqwest::Response,
+) -> Result<reqwest::Response, ApiError> {
+    if let Err(err) = response.error_for_status_ref() {
+        let text = response.text().await.ok();
+        return Err(ApiError::ErrorResponse(err, text.unwrap_or_default()));
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::{
+        error_for_status, send_tunnelled_upgrade, ApiError, LoginUserRequest, PocketArkApi,
+        ReauthCredentials,
+    };
+    use crate::{
+        api::{create_http_client, headers::X_TOKEN, HttpClientConfig},
+        proxy::{test::read_head, ProxyConfig, ProxyKind},
+        transport::UpgradeTransport,
+    };
+    use futures::future::join_all;
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Request, Response, Server, StatusCode,
+    };
+    use parking_lot::Mutex;
+    use serde_json::json;
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+    use url::Url;
+
+    /// Starts a proxy that answers a CONNECT request with the provided response,
+    /// once the tunnel is established the proxy acts as the server answering
+    /// the upgrade request with the upgrade response and echoing the upgraded
+    /// connection. The task provides the upgrade request
+    async fn start_proxy(
+        connect_response: &'static [u8],
+        upgrade_response: &'static [u8],
+    ) -> (ProxyConfig, JoinHandle<Option<String>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let connect = read_head(&mut stream).await;
+            assert!(connect.starts_with("CONNECT server.test:80 HTTP/1.1\r\n"));
+            stream.write_all(connect_response).await.unwrap();
+
+            if !connect_response.starts_with(b"HTTP/1.1 200") {
+                return None;
+            }
+
+            let upgrade = read_head(&mut stream).await;
+            stream.write_all(upgrade_response).await.unwrap();
+
+            let mut buffer = [0u8; 64];
+            while let Ok(count @ 1..) = stream.read(&mut buffer).await {
+                stream.write_all(&buffer[..count]).await.unwrap();
+            }
+
+            Some(upgrade)
+        });
+
+        let config = ProxyConfig {
+            kind: ProxyKind::Http,
+            host: Ipv4Addr::LOCALHOST.to_string(),
+            port,
+            auth: None,
+        };
+
+        (config, task)
+    }
+
+    /// Upgrade request headers
+    fn upgrade_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, "Upgrade".parse().unwrap());
+        headers.insert(header::UPGRADE, "blaze".parse().unwrap());
+        headers
+    }
+
+    /// Tests upgrading a connection through an established tunnel
+    #[tokio::test]
+    async fn test_tunnelled_upgrade() {
+        let (proxy, task) = start_proxy(
+            b"HTTP/1.1 200 Connection established\r\n\r\n",
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: blaze\r\n\r\n",
+        )
+        .await;
+
+        let url = "http://server.test/api/server/upgrade".parse().unwrap();
+        let (headers, mut stream) = send_tunnelled_upgrade(&proxy, url, upgrade_headers())
+            .await
+            .unwrap();
+        assert_eq!(headers[header::UPGRADE], "blaze");
+
+        // Upgraded connection is carried through the tunnel
+        stream.write_all(b"ping").await.unwrap();
+        let mut data = [0u8; 4];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"ping");
+        drop(stream);
+
+        let upgrade = task.await.unwrap().unwrap();
+        assert!(upgrade.starts_with("GET /api/server/upgrade HTTP/1.1\r\n"));
+        assert!(upgrade.contains("upgrade: blaze\r\n"));
+        assert!(upgrade.contains("host: server.test:80\r\n"));
+    }
+
+    /// Tests that the proxy requiring authentication fails the upgrade
+    #[tokio::test]
+    async fn test_tunnelled_upgrade_proxy_auth_required() {
+        let (proxy, _task) =
+            start_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n", b"").await;
+
+        let url = "http://server.test/api/server/upgrade".parse().unwrap();
+        let result = send_tunnelled_upgrade(&proxy, url, upgrade_headers()).await;
+
+        assert!(matches!(result, Err(ApiError::ProxyConnect(_))));
+    }
+
+    /// Tests that the server refusing the upgrade through the tunnel is
+    /// reported as a rejected upgrade
+    #[tokio::test]
+    async fn test_tunnelled_upgrade_rejected() {
+        let (proxy, _task) = start_proxy(
+            b"HTTP/1.1 200 Connection established\r\n\r\n",
+            b"HTTP/1.1 426 Upgrade Required\r\nContent-Length: 7\r\n\r\nrefused",
+        )
+        .await;
+
+        let url = "http://server.test/api/server/upgrade".parse().unwrap();
+        let err = match send_tunnelled_upgrade(&proxy, url, upgrade_headers()).await {
+            Ok(_) => panic!("Upgrade should be rejected"),
+            Err(err) => err,
+        };
+
+        assert!(matches!(
+            &err,
+            ApiError::UpgradeRejected(StatusCode::UPGRADE_REQUIRED, body) if body == "refused"
+        ));
+        assert!(err.is_upgrade_rejected());
+    }
+
+    /// Starts a server that responds to every request with the provided
+    /// status and body, provides the upgrade header of each request
+    async fn start_upgrade_server(
+        status: StatusCode,
+        body: &'static str,
+    ) -> (PocketArkApi, Arc<Mutex<Vec<String>>>) {
+        let upgrades: Arc<Mutex<Vec<String>>> = Default::default();
+
+        let make_service = make_service_fn({
+            let upgrades = upgrades.clone();
+            move |_| {
+                let upgrades = upgrades.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let upgrade = request
+                            .headers()
+                            .get(header::UPGRADE)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string();
+                        upgrades.lock().push(upgrade);
+
+                        let mut response = Response::new(Body::from(body));
+                        *response.status_mut() = status;
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        (PocketArkApi::new(http_client, url, None), upgrades)
+    }
+
+    /// Tests that a bad request with an error body is reported without
+    /// falling back to a WebSocket upgrade
+    #[tokio::test]
+    async fn test_upgrade_bad_request() {
+        let (api, upgrades) =
+            start_upgrade_server(StatusCode::BAD_REQUEST, "Missing association").await;
+
+        let err = match api.upgrade("upgrade", "blaze", HeaderMap::new()).await {
+            Ok(_) => panic!("Upgrade should fail"),
+            Err(err) => err,
+        };
+
+        assert!(matches!(
+            &err,
+            ApiError::ErrorResponse(err, body)
+                if err.status() == Some(StatusCode::BAD_REQUEST) && body == "Missing association"
+        ));
+        assert_eq!(*upgrades.lock(), ["blaze"]);
+        assert_eq!(api.upgrade_transport(), UpgradeTransport::Raw);
+    }
+
+    /// Tests that a refused raw upgrade falls back to a WebSocket upgrade
+    /// for that connection without changing the transport
+    #[tokio::test]
+    async fn test_upgrade_required_fallback() {
+        let (api, upgrades) = start_upgrade_server(StatusCode::UPGRADE_REQUIRED, "").await;
+
+        let err = match api.upgrade("upgrade", "blaze", HeaderMap::new()).await {
+            Ok(_) => panic!("Upgrade should fail"),
+            Err(err) => err,
+        };
+
+        assert!(err.is_upgrade_rejected());
+        assert_eq!(*upgrades.lock(), ["blaze", "websocket"]);
+        assert_eq!(api.upgrade_transport(), UpgradeTransport::Raw);
+    }
+
+    /// State of the server started by [`start_auth_server`]
+    #[derive(Default)]
+    pub(crate) struct AuthServer {
+        /// Whether refreshed tokens are accepted, when false every
+        /// authenticated request is rejected
+        accept_fresh: bool,
+        /// Number of logins
+        pub(crate) logins: AtomicUsize,
+        /// Number of authenticated requests
+        pub(crate) requests: AtomicUsize,
+    }
+
+    /// Starts a server that issues a fresh token on login and rejects
+    /// requests to `/data` that don't use a fresh token. Logins are slow
+    /// so that concurrent requests are rejected before the login completes
+    pub(crate) async fn start_auth_server(accept_fresh: bool) -> (PocketArkApi, Arc<AuthServer>) {
+        let state = Arc::new(AuthServer {
+            accept_fresh,
+            ..Default::default()
+        });
+
+        let make_service = make_service_fn({
+            let state = state.clone();
+            move |_| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let state = state.clone();
+                        async move {
+                            if request.uri().path() == "/api/server/login" {
+                                tokio::time::sleep(Duration::from_millis(50)).await;
+                                let login = state.logins.fetch_add(1, Ordering::SeqCst) + 1;
+                                let body = json!({ "token": format!("fresh-{login}") });
+                                return Ok::<_, Infallible>(Response::new(Body::from(
+                                    body.to_string(),
+                                )));
+                            }
+
+                            state.requests.fetch_add(1, Ordering::SeqCst);
+
+                            let fresh = request
+                                .headers()
+                                .get(X_TOKEN)
+                                .and_then(|value| value.to_str().ok())
+                                .is_some_and(|token| token.starts_with("fresh"));
+
+                            let mut response = Response::new(Body::from("data"));
+                            if !(fresh && state.accept_fresh) {
+                                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                            }
+                            Ok(response)
+                        }
+                    }))
+                }
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let api = PocketArkApi::new(http_client, url, None);
+        api.set_token(Arc::from("stale"));
+        api.set_reauth_credentials(Some(ReauthCredentials::Stored(LoginUserRequest {
+            email: "test@example.com".to_string(),
+            password: "password".to_string(),
+        })));
+
+        (api, state)
+    }
+
+    /// Authenticated request to the `/data` endpoint of the [`start_auth_server`]
+    async fn get_data(api: &PocketArkApi) -> Result<String, ApiError> {
+        let response = api
+            .http_client()
+            .client()
+            .get(api.endpoint_url("data")?)
+            .header(X_TOKEN, api.token_header()?)
+            .send()
+            .await
+            .map_err(ApiError::RequestFailed)?;
+
+        let response = error_for_status(response).await?;
+        response.text().await.map_err(ApiError::InvalidResponse)
+    }
+
+    /// Tests that a rejected request re-authenticates and is retried
+    #[tokio::test]
+    async fn test_reauth_retry() {
+        let (api, state) = start_auth_server(true).await;
+
+        let data = api.with_reauth(|| get_data(&api)).await.unwrap();
+
+        assert_eq!(data, "data");
+        assert_eq!(api.token().as_deref(), Some("fresh-1"));
+        assert_eq!(state.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 2);
+    }
+
+    /// Tests that a request rejected again after re-authenticating fails
+    /// instead of re-authenticating in a loop
+    #[tokio::test]
+    async fn test_reauth_rejected_twice() {
+        let (api, state) = start_auth_server(false).await;
+
+        let err = api.with_reauth(|| get_data(&api)).await.unwrap_err();
+
+        assert!(err.is_auth_rejected());
+        assert_eq!(state.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 2);
+    }
+
+    /// Tests that concurrent rejected requests only re-authenticate once
+    #[tokio::test]
+    async fn test_reauth_concurrent() {
+        let (api, state) = start_auth_server(true).await;
+
+        let results = join_all((0..5).map(|_| {
+            let api = api.clone();
+            async move { api.with_reauth(|| get_data(&api)).await }
+        }))
+        .await;
+
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(api.token().as_deref(), Some("fresh-1"));
+        assert_eq!(state.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,356 @@
+//! Loading of the client identity used to authenticate with the server
+
+use openssl::{
+    asn1::Asn1Time,
+    error::ErrorStack,
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::X509,
+};
+use reqwest::Identity;
+use std::{ffi::c_int, path::Path};
+use thiserror::Error;
+
+/// Errors that can occur when loading the client identity
+#[derive(Debug, Error)]
+pub enum ClientIdentityError {
+    /// Failed to read the identity file
+    #[error("Failed to read identity: {0}")]
+    Read(#[from] std::io::Error),
+    /// Failed to create the identity
+    #[error("Failed to create identity: {0}")]
+    Create(#[from] reqwest::Error),
+    /// Identity contents were malformed
+    #[error("Invalid identity: {0}")]
+    Invalid(#[from] ErrorStack),
+    /// Password for the identity or private key was incorrect
+    #[error("Incorrect identity password")]
+    WrongPassword,
+    /// Identity didn't contain a certificate
+    #[error("Identity is missing a certificate")]
+    MissingCertificate,
+    /// Identity didn't contain a private key
+    #[error("Identity is missing a private key")]
+    MissingPrivateKey,
+    /// Private key doesn't belong to the certificate
+    #[error("Identity private key does not match the certificate")]
+    KeyMismatch,
+    /// Certificate has expired, includes the expiry date
+    #[error("Identity certificate expired on {0}")]
+    Expired(String),
+}
+
+/// Attempts to read a client identity from the provided file path,
+/// the file must be either a .p12 / .pfx (PKCS12) format with a blank
+/// password or a PEM file containing both the certificate and private key
+///
+/// ## Arguments
+/// * `path` - The path to read the identity from
+pub fn read_client_identity(path: &Path) -> Result<Identity, ClientIdentityError> {
+    // Read the identity file bytes
+    let bytes = std::fs::read(path).map_err(ClientIdentityError::Read)?;
+
+    if is_pem(&bytes) {
+        client_identity_from_pem(&bytes, &bytes, None)
+    } else {
+        client_identity_from_pkcs12(&bytes, "")
+    }
+}
+
+/// Attempts to read a password protected .p12 / .pfx (PKCS12) client
+/// identity from the provided file path
+///
+/// ## Arguments
+/// * `path`     - The path to read the identity from
+/// * `password` - The identity password
+pub fn read_client_identity_with_password(
+    path: &Path,
+    password: &str,
+) -> Result<Identity, ClientIdentityError> {
+    let bytes = std::fs::read(path).map_err(ClientIdentityError::Read)?;
+    client_identity_from_pkcs12(&bytes, password)
+}
+
+/// Attempts to read a client identity from a PEM certificate file and a
+/// PEM private key file, the certificate file may include the rest of
+/// the certificate chain after the client certificate
+///
+/// ## Arguments
+/// * `cert_path` - The path to the certificate file
+/// * `key_path`  - The path to the private key file
+/// * `password`  - Password if the private key is encrypted
+pub fn read_pem_client_identity(
+    cert_path: &Path,
+    key_path: &Path,
+    password: Option<&str>,
+) -> Result<Identity, ClientIdentityError> {
+    let cert = std::fs::read(cert_path).map_err(ClientIdentityError::Read)?;
+    let key = std::fs::read(key_path).map_err(ClientIdentityError::Read)?;
+    client_identity_from_pem(&cert, &key, password)
+}
+
+/// Creates a client identity from in-memory .p12 / .pfx (PKCS12) bytes
+///
+/// ## Arguments
+/// * `bytes`    - The PKCS12 bytes
+/// * `password` - The identity password, blank if the identity has none
+pub fn client_identity_from_pkcs12(
+    bytes: &[u8],
+    password: &str,
+) -> Result<Identity, ClientIdentityError> {
+    let pkcs12 = Pkcs12::from_der(bytes)?;
+
+    // A blank password also tries no password
+    let parsed = pkcs12.parse2(password).map_err(|err| {
+        if is_wrong_password(&err) {
+            ClientIdentityError::WrongPassword
+        } else {
+            ClientIdentityError::Invalid(err)
+        }
+    })?;
+
+    // Certificates are only matched to a private key so a missing key must be checked first
+    let key = parsed.pkey.ok_or(ClientIdentityError::MissingPrivateKey)?;
+    let cert = parsed.cert.ok_or(ClientIdentityError::MissingCertificate)?;
+
+    create_identity(&cert, &key, parsed.ca)
+}
+
+/// Creates a client identity from in-memory PEM certificate and private
+/// key bytes, both may be the same bytes if they are stored together
+///
+/// ## Arguments
+/// * `cert`     - The PEM certificate chain starting with the client certificate
+/// * `key`      - The PEM private key
+/// * `password` - Password if the private key is encrypted
+pub fn client_identity_from_pem(
+    cert: &[u8],
+    key: &[u8],
+    password: Option<&str>,
+) -> Result<Identity, ClientIdentityError> {
+    let mut chain = X509::stack_from_pem(cert)?.into_iter();
+    let cert = chain
+        .next()
+        .ok_or(ClientIdentityError::MissingCertificate)?;
+
+    if !key.windows(11).any(|window| window == b"PRIVATE KEY") {
+        return Err(ClientIdentityError::MissingPrivateKey);
+    }
+
+    // Encrypted keys fail to load when the password is missing or incorrect
+    let encrypted = key.windows(9).any(|window| window == b"ENCRYPTED");
+
+    let key = match password {
+        Some(password) => PKey::private_key_from_pem_passphrase(key, password.as_bytes()),
+        // Callback provides an empty password rather than OpenSSL prompting the terminal
+        None => PKey::private_key_from_pem_callback(key, |_| Ok(0)),
+    }
+    .map_err(|err| {
+        if encrypted {
+            ClientIdentityError::WrongPassword
+        } else {
+            ClientIdentityError::Invalid(err)
+        }
+    })?;
+
+    let mut ca = Stack::new()?;
+    for cert in chain {
+        ca.push(cert)?;
+    }
+
+    create_identity(&cert, &key, Some(ca))
+}
+
+/// Validates the certificate and private key then creates an identity
+/// from them, the identity is passed to the HTTP client as PKCS12
+///
+/// ## Arguments
+/// * `cert` - The client certificate
+/// * `key`  - The certificate private key
+/// * `ca`   - The rest of the certificate chain
+fn create_identity(
+    cert: &X509,
+    key: &PKey<Private>,
+    ca: Option<Stack<X509>>,
+) -> Result<Identity, ClientIdentityError> {
+    let now = Asn1Time::days_from_now(0)?;
+    if cert.not_after() < now {
+        return Err(ClientIdentityError::Expired(cert.not_after().to_string()));
+    }
+
+    if !cert.public_key()?.public_eq(key) {
+        return Err(ClientIdentityError::KeyMismatch);
+    }
+
+    let mut builder = Pkcs12::builder();
+    builder.pkey(key).cert(cert);
+
+    if let Some(ca) = ca {
+        builder.ca(ca);
+    }
+
+    let der = builder.build2("")?.to_der()?;
+    Ok(Identity::from_pkcs12_der(&der, "")?)
+}
+
+/// OpenSSL library code for the PKCS12 routines
+const ERR_LIB_PKCS12: c_int = 35;
+/// OpenSSL library code for the EVP (digital envelope) routines
+const ERR_LIB_EVP: c_int = 6;
+/// PKCS12 reason for a failed MAC check
+const PKCS12_R_MAC_VERIFY_FAILURE: c_int = 113;
+/// PKCS12 reason for failing to decrypt the contents
+const PKCS12_R_PKCS12_CIPHERFINAL_ERROR: c_int = 116;
+/// EVP reason for failing to decrypt
+const EVP_R_BAD_DECRYPT: c_int = 100;
+
+/// Whether the error from parsing a PKCS12 identity is caused by the
+/// password, the MAC check or decryption failing. Other errors such as
+/// unsupported legacy algorithms are not a password problem
+///
+/// ## Arguments
+/// * `err` - The parsing error
+fn is_wrong_password(err: &ErrorStack) -> bool {
+    err.errors().iter().any(|err| {
+        matches!(
+            (err.library_code(), err.reason_code()),
+            (
+                ERR_LIB_PKCS12,
+                PKCS12_R_MAC_VERIFY_FAILURE | PKCS12_R_PKCS12_CIPHERFINAL_ERROR
+            ) | (ERR_LIB_EVP, EVP_R_BAD_DECRYPT)
+        )
+    })
+}
+
+/// Whether the identity bytes are PEM encoded
+///
+/// ## Arguments
+/// * `bytes` - The identity bytes
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes
+        .iter()
+        .skip_while(|byte| byte.is_ascii_whitespace())
+        .take(11)
+        .eq(b"-----BEGIN ".iter())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{client_identity_from_pem, client_identity_from_pkcs12, ClientIdentityError};
+    use crate::trust::test::{certificate, generate_key, self_signed};
+    use openssl::{asn1::Asn1Time, pkcs12::Pkcs12, symm::Cipher};
+
+    /// Legacy PKCS12 identity using RC2-40 for the certificates and 3DES for
+    /// the key (The Windows export default) protected by the password "secret",
+    /// created using `openssl pkcs12 -export -legacy`
+    const LEGACY_PKCS12: &[u8] = include_bytes!("fixtures/legacy-identity.p12");
+
+    /// Creates a PKCS12 identity protected by the provided password
+    fn pkcs12_fixture(password: &str) -> Vec<u8> {
+        let (cert, key) = self_signed();
+        let mut builder = Pkcs12::builder();
+        builder.pkey(&key).cert(&cert);
+        builder.build2(password).unwrap().to_der().unwrap()
+    }
+
+    /// Tests loading a password protected PKCS12 identity with the
+    /// correct, incorrect and missing passwords, along with identities
+    /// that are malformed, use legacy algorithms or are missing a key
+    #[test]
+    fn test_pkcs12_identity_password() {
+        let identity = pkcs12_fixture("secret");
+
+        assert!(client_identity_from_pkcs12(&identity, "secret").is_ok());
+        assert!(matches!(
+            client_identity_from_pkcs12(&identity, "wrong"),
+            Err(ClientIdentityError::WrongPassword)
+        ));
+        assert!(matches!(
+            client_identity_from_pkcs12(&identity, ""),
+            Err(ClientIdentityError::WrongPassword)
+        ));
+
+        // Identities without a password don't require one but reject a
+        // password that doesn't match
+        let identity = pkcs12_fixture("");
+        assert!(client_identity_from_pkcs12(&identity, "").is_ok());
+        assert!(matches!(
+            client_identity_from_pkcs12(&identity, "unused"),
+            Err(ClientIdentityError::WrongPassword)
+        ));
+
+        // Malformed identities aren't reported as a password problem
+        assert!(matches!(
+            client_identity_from_pkcs12(b"not an identity", "secret"),
+            Err(ClientIdentityError::Invalid(_))
+        ));
+
+        // Legacy algorithms unsupported by OpenSSL 3 aren't a password problem
+        assert!(matches!(
+            client_identity_from_pkcs12(LEGACY_PKCS12, "secret"),
+            Err(ClientIdentityError::Invalid(_))
+        ));
+        assert!(matches!(
+            client_identity_from_pkcs12(LEGACY_PKCS12, "wrong"),
+            Err(ClientIdentityError::WrongPassword)
+        ));
+
+        // Identities without a private key
+        let (cert, _) = self_signed();
+        let mut builder = Pkcs12::builder();
+        builder.cert(&cert);
+        let identity = builder.build2("secret").unwrap().to_der().unwrap();
+        assert!(matches!(
+            client_identity_from_pkcs12(&identity, "secret"),
+            Err(ClientIdentityError::MissingPrivateKey)
+        ));
+    }
+
+    /// Tests loading a PEM identity with an encrypted private key using
+    /// the correct, incorrect and missing passwords, along with identities
+    /// missing a key, using the wrong key or using an expired certificate
+    #[test]
+    fn test_pem_identity_password() {
+        let (cert, key) = self_signed();
+        let cert = cert.to_pem().unwrap();
+        let encrypted = key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+            .unwrap();
+
+        assert!(client_identity_from_pem(&cert, &encrypted, Some("secret")).is_ok());
+        assert!(matches!(
+            client_identity_from_pem(&cert, &encrypted, Some("wrong")),
+            Err(ClientIdentityError::WrongPassword)
+        ));
+        assert!(matches!(
+            client_identity_from_pem(&cert, &encrypted, None),
+            Err(ClientIdentityError::WrongPassword)
+        ));
+
+        let plain = key.private_key_to_pem_pkcs8().unwrap();
+        assert!(client_identity_from_pem(&cert, &plain, None).is_ok());
+
+        // Certificate without a private key block
+        assert!(matches!(
+            client_identity_from_pem(&cert, &cert, None),
+            Err(ClientIdentityError::MissingPrivateKey)
+        ));
+
+        // Private key for a different certificate
+        let other = generate_key().private_key_to_pem_pkcs8().unwrap();
+        assert!(matches!(
+            client_identity_from_pem(&cert, &other, None),
+            Err(ClientIdentityError::KeyMismatch)
+        ));
+
+        // Certificate that has already expired
+        let expired = certificate(&key, None, &Asn1Time::from_unix(86400).unwrap())
+            .to_pem()
+            .unwrap();
+        assert!(matches!(
+            client_identity_from_pem(&expired, &plain, None),
+            Err(ClientIdentityError::Expired(_))
+        ));
+    }
+}
@@ -0,0 +1,658 @@
+//! Server lookups along with the diagnostics for failed lookups
+
+use super::{
+    client::{error_for_status, ApiError, PocketArkApi},
+    features, HttpClient, DETAILS_ENDPOINT, SERVER_IDENT,
+};
+use crate::{
+    servers::tunnel_manager::TunnelTransport, transport::UpgradeTransport, MIN_SERVER_VERSION,
+};
+use hyper::{client::connect::dns::Name, header};
+use log::debug;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    Client,
+};
+use semver::Version;
+use serde::Deserialize;
+use std::{
+    error::Error as StdError,
+    fmt::{Display, Formatter},
+    io::ErrorKind,
+    net::SocketAddr,
+    str::FromStr,
+    time::Duration,
+};
+use thiserror::Error;
+use url::Url;
+
+/// Error from resolving a host name, wraps the resolver error so that DNS
+/// failures can be told apart from other connection failures
+#[derive(Debug, Error)]
+#[error("Failed to resolve host: {0}")]
+struct DnsError(std::io::Error);
+
+/// DNS resolver for the HTTP client, resolution failures are reported
+/// as a [`DnsError`]
+pub(super) struct DnsResolver;
+
+impl Resolve for DnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            match tokio::net::lookup_host((name.as_str(), 0)).await {
+                Ok(addrs) => {
+                    let addrs: Vec<SocketAddr> = addrs.collect();
+                    Ok(Box::new(addrs.into_iter()) as Addrs)
+                }
+                Err(err) => Err(Box::new(DnsError(err)) as Box<dyn StdError + Send + Sync>),
+            }
+        })
+    }
+}
+
+/// Details provided by the server. These are the only fields
+/// that we need the rest are ignored by this client.
+#[derive(Deserialize)]
+struct ServerDetails {
+    /// The Pocket Relay version of the server
+    version: Version,
+    /// Server identifier checked to ensure its a proper server
+    #[serde(default)]
+    ident: Option<String>,
+    /// Association token if the server supports providing one
+    association: Option<String>,
+    /// Tunnel port if the server provides one
+    tunnel_port: Option<u16>,
+    /// Optional capabilities section, parsed leniently by [`ServerCapabilities::from_value`]
+    #[serde(default)]
+    capabilities: serde_json::Value,
+}
+
+/// Optional capabilities reported by the server. Older servers don't report
+/// capabilities so every field is optional, unknown or malformed values are
+/// ignored rather than failing the lookup
+#[derive(Debug, Clone, Default)]
+pub struct ServerCapabilities {
+    /// Optional feature flags enabled on the server, see [`features`]
+    pub features: Vec<String>,
+    /// Tunnel transports supported by the server, [None] if not reported
+    pub tunnel_transports: Option<Vec<TunnelTransport>>,
+    /// Max number of sockets the server supports in a tunnel pool
+    pub max_pool_size: Option<usize>,
+    /// Display name of the server
+    pub name: Option<String>,
+    /// Message of the day set by the server
+    pub motd: Option<String>,
+}
+
+impl ServerCapabilities {
+    /// Parses the capabilities section of the server details
+    ///
+    /// ## Arguments
+    /// * `value` - The capabilities section
+    fn from_value(value: &serde_json::Value) -> Self {
+        let string = |key: &str| {
+            value
+                .get(key)
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+        };
+
+        let features = value
+            .get("features")
+            .and_then(|value| value.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|value| value.as_str())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let tunnel_transports = value
+            .get("tunnel_transports")
+            .and_then(|value| value.as_array())
+            .and_then(|values| {
+                let transports: Vec<TunnelTransport> = values
+                    .iter()
+                    .filter_map(|value| value.as_str())
+                    .filter_map(|value| match value.to_ascii_lowercase().as_str() {
+                        "udp" => Some(TunnelTransport::Udp),
+                        "http" => Some(TunnelTransport::Http),
+                        _ => None,
+                    })
+                    .collect();
+
+                // A list of only unknown transports is treated as not reported
+                if transports.is_empty() && !values.is_empty() {
+                    None
+                } else {
+                    Some(transports)
+                }
+            });
+
+        let max_pool_size = value
+            .get("max_pool_size")
+            .and_then(|value| value.as_u64())
+            .and_then(|value| usize::try_from(value).ok());
+
+        Self {
+            features,
+            tunnel_transports,
+            max_pool_size,
+            name: string("name"),
+            motd: string("motd"),
+        }
+    }
+
+    /// Checks whether the server reported the provided feature flag
+    ///
+    /// ## Arguments
+    /// * `feature` - The feature flag, see [`features`]
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features
+            .iter()
+            .any(|value| value.eq_ignore_ascii_case(feature))
+    }
+
+    /// Checks whether the server supports the provided tunnel transport,
+    /// servers that don't report their transports are assumed to support
+    /// every transport
+    ///
+    /// ## Arguments
+    /// * `transport` - The tunnel transport
+    pub fn supports_tunnel(&self, transport: TunnelTransport) -> bool {
+        self.tunnel_transports
+            .as_ref()
+            .map_or(true, |transports| transports.contains(&transport))
+    }
+
+    /// Size of the tunnel socket pool to use, limited by the max pool
+    /// size reported by the server
+    ///
+    /// ## Arguments
+    /// * `default` - The pool size used by the client
+    pub fn tunnel_pool_size(&self, default: usize) -> usize {
+        self.max_pool_size
+            .map_or(default, |max| max.clamp(1, default))
+    }
+}
+
+/// Data from completing a lookup contains the resolved address
+/// from the connection to the server as well as the server
+/// version obtained from the server
+#[derive(Debug, Clone)]
+pub struct LookupData {
+    /// Server url
+    pub url: Url,
+    /// The server version
+    pub version: Version,
+    /// Association token if the server supports providing one
+    pub association: Option<String>,
+    /// Tunnel port if the server provides one
+    pub tunnel_port: Option<u16>,
+    /// Optional capabilities reported by the server
+    pub capabilities: ServerCapabilities,
+}
+
+impl PocketArkApi {
+    /// Attempts to lookup a server at the provided host to see if its a
+    /// Pocket Ark server, creates an API client for the server on success
+    ///
+    /// When the host doesn't include a scheme both HTTP and HTTPS are
+    /// attempted (HTTPS first when port 443 is specified), if every attempt
+    /// fails [`ApiError::LookupFailed`] provides a report of each attempt
+    ///
+    /// ## Arguments
+    /// * `http_client` - The HTTP client to connect with
+    /// * `host`        - The server host (Connection URL)
+    pub async fn lookup(
+        http_client: HttpClient,
+        host: &str,
+    ) -> Result<(Self, LookupData), ApiError> {
+        let mut report = LookupReport::default();
+
+        for url in lookup_candidates(host)? {
+            match lookup_url(http_client.client(), &url).await {
+                Ok(details) => {
+                    let capabilities = ServerCapabilities::from_value(&details.capabilities);
+                    let websocket = capabilities.has_feature(features::WEBSOCKET);
+
+                    let data = LookupData {
+                        url: url.clone(),
+                        version: details.version,
+                        association: details.association.clone(),
+                        tunnel_port: details.tunnel_port,
+                        capabilities,
+                    };
+
+                    let api = Self::new(http_client, url, details.association);
+
+                    // Prefer WebSocket upgrades when the server advertises support
+                    if websocket {
+                        api.set_upgrade_transport(UpgradeTransport::WebSocket);
+                    }
+
+                    return Ok((api, data));
+                }
+                // Server was reached but isn't usable, the other scheme won't help
+                Err(err @ (ApiError::NotPocketArk | ApiError::ServerOutdated(..))) => {
+                    return Err(err)
+                }
+                Err(err) => {
+                    debug!("Lookup attempt for {} failed: {}", url, err);
+                    report.attempts.push(LookupAttempt {
+                        kind: LookupFailureKind::from_error(&err),
+                        url,
+                        message: err.to_string(),
+                    });
+                }
+            }
+        }
+
+        Err(ApiError::LookupFailed(report))
+    }
+
+    /// Requests the current version of the server, fails with the same
+    /// errors as [`PocketArkApi::lookup`] if the server is no longer a
+    /// supported Pocket Ark server
+    pub async fn server_version(&self) -> Result<Version, ApiError> {
+        lookup_url(self.http_client().client(), self.base_url())
+            .await
+            .map(|details| details.version)
+    }
+}
+
+/// Time to wait for each lookup attempt before giving up
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates the URLs to attempt for a server lookup from the provided host
+///
+/// ## Arguments
+/// * `host` - The server host (Connection URL)
+fn lookup_candidates(host: &str) -> Result<Vec<Url>, ApiError> {
+    let mut url = String::new();
+
+    // Whether a scheme was inferred
+    let mut inferred_scheme = false;
+
+    // Fill in missing scheme portion
+    if !host.starts_with("http://") && !host.starts_with("https://") {
+        url.push_str("http://");
+
+        inferred_scheme = true;
+    }
+
+    url.push_str(host);
+
+    // Ensure theres a trailing slash (URL path will be interpeted incorrectly without)
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+
+    let url = Url::from_str(&url)?;
+
+    if !inferred_scheme {
+        return Ok(vec![url]);
+    }
+
+    let mut https_url = url.clone();
+    let _ = https_url.set_scheme("https");
+
+    // Prefer https if the 443 port was specified
+    Ok(if url.port().is_some_and(|port| port == 443) {
+        vec![https_url, url]
+    } else {
+        vec![url, https_url]
+    })
+}
+
+/// Requests the server details from the server at the provided URL
+///
+/// ## Arguments
+/// * `http_client` - The HTTP client to connect with
+/// * `url`         - The server base URL
+async fn lookup_url(http_client: &Client, url: &Url) -> Result<ServerDetails, ApiError> {
+    let info_url = url.join(DETAILS_ENDPOINT)?;
+
+    // Send the HTTP request and get its response
+    let response = http_client
+        .get(info_url)
+        .header(header::ACCEPT, "application/json")
+        .timeout(LOOKUP_TIMEOUT)
+        .send()
+        .await
+        .map_err(ApiError::RequestFailed)?;
+
+    // Debug printing of response details for debug builds
+    #[cfg(debug_assertions)]
+    {
+        debug!("Response Status: {}", response.status());
+        debug!("HTTP Version: {:?}", response.version());
+        debug!("Content Length: {:?}", response.content_length());
+        debug!("HTTP Headers: {:?}", response.headers());
+    }
+
+    // Ensure the response wasn't a non 200 response
+    let response = error_for_status(response).await?;
+
+    // Parse the JSON serialized server details
+    let details = response
+        .json::<ServerDetails>()
+        .await
+        .map_err(ApiError::InvalidResponse)?;
+
+    // Handle invalid server ident
+    if details.ident.as_deref() != Some(SERVER_IDENT) {
+        return Err(ApiError::NotPocketArk);
+    }
+
+    // Ensure the server is a supported version
+    if details.version < MIN_SERVER_VERSION {
+        return Err(ApiError::ServerOutdated(
+            details.version,
+            MIN_SERVER_VERSION,
+        ));
+    }
+
+    // Debug logging association aquire
+    #[cfg(debug_assertions)]
+    {
+        if let Some(association) = &details.association {
+            debug!("Aquired association token: {}", association);
+        }
+    }
+
+    Ok(details)
+}
+
+/// Classification of a failed lookup attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupFailureKind {
+    /// Server host name could not be resolved
+    Dns,
+    /// Server actively refused the connection
+    ConnectionRefused,
+    /// TLS handshake failed or the server certificate was rejected
+    Tls,
+    /// Server didn't respond in time
+    Timeout,
+    /// Connection failed for another reason
+    Connection,
+    /// Server responded with an error status
+    ErrorResponse,
+    /// Server response was not valid server details
+    InvalidResponse,
+}
+
+impl LookupFailureKind {
+    /// Stable code for the failure kind, can be used by clients
+    /// to show help for the failure
+    pub fn code(&self) -> &'static str {
+        match self {
+            LookupFailureKind::Dns => "LOOKUP_DNS",
+            LookupFailureKind::ConnectionRefused => "LOOKUP_CONNECTION_REFUSED",
+            LookupFailureKind::Tls => "LOOKUP_TLS",
+            LookupFailureKind::Timeout => "LOOKUP_TIMEOUT",
+            LookupFailureKind::Connection => "LOOKUP_CONNECTION",
+            LookupFailureKind::ErrorResponse => "LOOKUP_ERROR_RESPONSE",
+            LookupFailureKind::InvalidResponse => "LOOKUP_INVALID_RESPONSE",
+        }
+    }
+
+    /// Classifies the error from a lookup attempt
+    ///
+    /// ## Arguments
+    /// * `err` - The lookup error
+    fn from_error(err: &ApiError) -> Self {
+        match err {
+            ApiError::RequestFailed(err) => Self::from_request_error(err),
+            ApiError::ErrorResponse(..) => LookupFailureKind::ErrorResponse,
+            _ => LookupFailureKind::InvalidResponse,
+        }
+    }
+
+    /// Classifies a request error by walking its source chain for the
+    /// underlying DNS, TLS, hyper and IO errors
+    ///
+    /// ## Arguments
+    /// * `err` - The request error
+    fn from_request_error(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return LookupFailureKind::Timeout;
+        }
+
+        let mut source: Option<&(dyn StdError + 'static)> = Some(err);
+        while let Some(current) = source {
+            if current.is::<DnsError>() {
+                return LookupFailureKind::Dns;
+            }
+
+            if current.is::<native_tls::Error>() {
+                return LookupFailureKind::Tls;
+            }
+
+            if current
+                .downcast_ref::<hyper::Error>()
+                .is_some_and(hyper::Error::is_timeout)
+            {
+                return LookupFailureKind::Timeout;
+            }
+
+            if let Some(io_err) = current.downcast_ref::<std::io::Error>() {
+                match io_err.kind() {
+                    ErrorKind::ConnectionRefused => return LookupFailureKind::ConnectionRefused,
+                    ErrorKind::TimedOut => return LookupFailureKind::Timeout,
+                    _ => {}
+                }
+            }
+
+            source = current.source();
+        }
+
+        LookupFailureKind::Connection
+    }
+}
+
+/// Failed attempt at looking up a server
+#[derive(Debug, Clone)]
+pub struct LookupAttempt {
+    /// The URL that was attempted
+    pub url: Url,
+    /// Classification of the failure
+    pub kind: LookupFailureKind,
+    /// The error message
+    pub message: String,
+}
+
+/// Report of the attempts made while looking up a server
+#[derive(Debug, Clone, Default)]
+pub struct LookupReport {
+    /// The failed attempts in the order they were made
+    pub attempts: Vec<LookupAttempt>,
+}
+
+impl Display for LookupReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (index, attempt) in self.attempts.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(
+                f,
+                "{} [{}]: {}",
+                attempt.url,
+                attempt.kind.code(),
+                attempt.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lookup_candidates, LookupFailureKind, ServerCapabilities};
+    use crate::{
+        api::{create_http_client, ApiError, HttpClientConfig},
+        servers::tunnel_manager::TunnelTransport,
+    };
+    use serde_json::json;
+    use std::{net::Ipv4Addr, time::Duration};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    /// Tests that servers without a capabilities section are assumed to
+    /// support everything the client does
+    #[test]
+    fn test_capabilities_missing_section() {
+        for value in [json!(null), json!({}), json!("invalid")] {
+            let capabilities = ServerCapabilities::from_value(&value);
+
+            assert!(capabilities.features.is_empty());
+            assert!(capabilities.tunnel_transports.is_none());
+            assert!(capabilities.supports_tunnel(TunnelTransport::Udp));
+            assert!(capabilities.supports_tunnel(TunnelTransport::Http));
+            assert_eq!(capabilities.tunnel_pool_size(4), 4);
+            assert!(capabilities.name.is_none() && capabilities.motd.is_none());
+        }
+    }
+
+    /// Tests that unknown keys and values are ignored
+    #[test]
+    fn test_capabilities_unknown_keys() {
+        let capabilities = ServerCapabilities::from_value(&json!({
+            "features": ["WebSocket", 12, "future_feature"],
+            "tunnel_transports": ["udp", "quic"],
+            "max_pool_size": 2,
+            "name": "Server",
+            "unknown": { "nested": true },
+        }));
+
+        assert!(capabilities.has_feature("websocket"));
+        assert!(capabilities.has_feature("future_feature"));
+        assert_eq!(capabilities.features.len(), 2);
+        assert_eq!(
+            capabilities.tunnel_transports,
+            Some(vec![TunnelTransport::Udp])
+        );
+        assert!(!capabilities.supports_tunnel(TunnelTransport::Http));
+        assert_eq!(capabilities.tunnel_pool_size(4), 2);
+        assert_eq!(capabilities.name.as_deref(), Some("Server"));
+
+        // Only unknown transports is treated as not reported
+        let capabilities = ServerCapabilities::from_value(&json!({
+            "tunnel_transports": ["quic"],
+        }));
+        assert!(capabilities.tunnel_transports.is_none());
+    }
+
+    /// Tests that a wrong typed max pool size is ignored without
+    /// affecting the other capabilities
+    #[test]
+    fn test_capabilities_wrong_typed_max_pool_size() {
+        for max_pool_size in [json!("2"), json!(-1), json!(1.5), json!(null)] {
+            let capabilities = ServerCapabilities::from_value(&json!({
+                "max_pool_size": max_pool_size,
+                "motd": "Welcome",
+            }));
+
+            assert_eq!(capabilities.max_pool_size, None);
+            assert_eq!(capabilities.tunnel_pool_size(4), 4);
+            assert_eq!(capabilities.motd.as_deref(), Some("Welcome"));
+        }
+    }
+
+    /// Tests the URLs attempted for hosts with and without a scheme
+    #[test]
+    fn test_lookup_candidates() {
+        let candidates = |host: &str| -> Vec<String> {
+            lookup_candidates(host)
+                .unwrap()
+                .into_iter()
+                .map(|url| url.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            candidates("example.com"),
+            ["http://example.com/", "https://example.com/"]
+        );
+        assert_eq!(
+            candidates("example.com:8080/relay"),
+            [
+                "http://example.com:8080/relay/",
+                "https://example.com:8080/relay/"
+            ]
+        );
+
+        // HTTPS is attempted first when the HTTPS port is specified
+        assert_eq!(
+            candidates("example.com:443"),
+            ["https://example.com/", "http://example.com:443/"]
+        );
+
+        // Explicit schemes are only attempted as provided
+        assert_eq!(candidates("https://example.com"), ["https://example.com/"]);
+        assert_eq!(candidates("http://example.com/"), ["http://example.com/"]);
+
+        assert!(lookup_candidates("http://exa mple.com").is_err());
+    }
+
+    /// Sends a request to the URL and classifies the failure
+    async fn classify(url: &str) -> LookupFailureKind {
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let err = http_client
+            .client()
+            .get(url)
+            .timeout(Duration::from_millis(500))
+            .send()
+            .await
+            .unwrap_err();
+
+        LookupFailureKind::from_error(&ApiError::RequestFailed(err))
+    }
+
+    /// Tests classifying failed lookup requests from their error sources
+    #[tokio::test]
+    async fn test_lookup_failure_kind() {
+        // Nothing listening on the port
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        assert_eq!(
+            classify(&format!("http://127.0.0.1:{port}/")).await,
+            LookupFailureKind::ConnectionRefused
+        );
+
+        assert_eq!(
+            classify("http://pocket-ark.invalid/").await,
+            LookupFailureKind::Dns
+        );
+
+        // Server that never responds
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(
+            classify(&format!("http://127.0.0.1:{port}/")).await,
+            LookupFailureKind::Timeout
+        );
+
+        // Plain HTTP server answering a HTTPS request
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+            }
+        });
+        assert_eq!(
+            classify(&format!("https://127.0.0.1:{port}/")).await,
+            LookupFailureKind::Tls
+        );
+
+        assert_eq!(
+            LookupFailureKind::from_error(&ApiError::NotPocketArk),
+            LookupFailureKind::InvalidResponse
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! API logic for HTTP requests that are sent to the Pocket Relay server
+//!
+//! The [`PocketArkApi`] client and its errors are defined in `client`,
+//! client identity loading in `identity`, server lookups and diagnostics
+//! in `lookup` and proxying of the game HTTP requests in `proxy`
+
+use self::lookup::DnsResolver;
+use crate::proxy::ProxyConfig;
+use reqwest::{Certificate, Client, Identity};
+use std::sync::Arc;
+
+mod client;
+mod identity;
+mod lookup;
+mod proxy;
+
+pub use self::{
+    client::{
+        ApiError, AuthToken, CreateUserRequest, CredentialCallback, LoginUserRequest, PocketArkApi,
+        ReauthCredentials, TokenResponse,
+    },
+    identity::{
+        client_identity_from_pem, client_identity_from_pkcs12, read_client_identity,
+        read_client_identity_with_password, read_pem_client_identity, ClientIdentityError,
+    },
+    lookup::{LookupAttempt, LookupData, LookupFailureKind, LookupReport, ServerCapabilities},
+    proxy::{ProxyBody, REPLAY_BODY_LIMIT},
+};
+
+/// Endpoint used for requesting the server details
+pub const DETAILS_ENDPOINT: &str = "api/server";
+/// Endpoint for upgrading the server connection
+pub const UPGRADE_ENDPOINT: &str = "api/server/upgrade";
+/// Endpoint for creating an account
+pub const CREATE_ACCOUNT_ENDPOINT: &str = "api/server/create";
+/// Endpoint for logging into an account
+pub const LOGIN_ENDPOINT: &str = "api/server/login";
+/// Endpoint for creating a connection tunnel
+pub const TUNNEL_ENDPOINT: &str = "api/server/tunnel";
+
+/// Server identifier for validation
+pub const SERVER_IDENT: &str = "POCKET_ARK_SERVER";
+
+/// Client user agent created from the name and version
+pub const USER_AGENT: &str = concat!("PocketArkClient/v", env!("CARGO_PKG_VERSION"));
+
+/// Headers used by the client
+pub mod headers {
+    /// Header used for association tokens
+    pub const ASSOCIATION: &str = "x-association";
+    /// Header used for auth tokens
+    pub const X_TOKEN: &str = "x-token";
+}
+
+/// Feature flags that servers can report in their [`ServerCapabilities`]
+pub mod features {
+    /// Server supports upgrading connections over WebSocket
+    pub const WEBSOCKET: &str = "websocket";
+}
+
+/// Configuration for creating the HTTP client
+#[derive(Default)]
+pub struct HttpClientConfig {
+    /// Optional identity for the client to use
+    pub identity: Option<Identity>,
+    /// Optional proxy for the client to connect through
+    pub proxy: Option<ProxyConfig>,
+    /// Extra root certificates to trust in addition to the system roots,
+    /// see [`crate::trust::read_root_certificates`]
+    pub root_certificates: Vec<Certificate>,
+    /// Pinned self-signed server certificate, when set the client trusts
+    /// only this certificate and the system and extra root certificates are
+    /// ignored, see [`crate::trust::trust_on_first_use`]
+    pub pinned_certificate: Option<Certificate>,
+}
+
+/// HTTP client for connecting to the server, keeps the proxy the client
+/// was created with so that connections made outside of the client (i.e
+/// tunnelled upgrades) go through the same proxy
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    /// The underlying HTTP client
+    client: Client,
+    /// Proxy the client connects through
+    proxy: Option<ProxyConfig>,
+}
+
+impl HttpClient {
+    /// The underlying HTTP client
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The proxy the client connects through
+    pub fn proxy(&self) -> Option<&ProxyConfig> {
+        self.proxy.as_ref()
+    }
+}
+
+/// Creates a new HTTP client to use from the provided configuration
+///
+/// ## Arguments
+/// * `config` - The client configuration
+pub fn create_http_client(config: HttpClientConfig) -> Result<HttpClient, reqwest::Error> {
+    let mut builder = Client::builder().user_agent(USER_AGENT);
+
+    if let Some(identity) = config.identity {
+        builder = builder.identity(identity);
+    }
+
+    builder = builder.dns_resolver(Arc::new(DnsResolver));
+
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+
+    match config.pinned_certificate {
+        Some(certificate) => {
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(certificate);
+        }
+        None => {
+            for certificate in config.root_certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+    }
+
+    Ok(HttpClient {
+        client: builder.build()?,
+        proxy: config.proxy,
+    })
+}
@@ -0,0 +1,327 @@
+//! Proxying of the HTTP requests made by the game to the server
+
+use super::{
+    client::{is_auth_status, ApiError, PocketArkApi},
+    headers::X_TOKEN,
+};
+use bytes::Bytes;
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    Body, HeaderMap, Method, Response,
+};
+use log::{error, warn};
+use url::Url;
+
+impl PocketArkApi {
+    /// Proxies an HTTP request to the Pocket Relay server returning a
+    /// hyper response that can be served, the authentication token is
+    /// included with the request
+    ///
+    /// The response body is streamed from the server as it arrives. When
+    /// the server rejects the token the request is only retried if its
+    /// body can be replayed, otherwise the rejection is passed through.
+    /// Endpoints may also reject a valid token (i.e a forbidden resource)
+    /// so once the retry with a fresh token is rejected, rejections are
+    /// passed through without re-authenticating until the token changes
+    ///
+    /// ## Arguments
+    /// * `url`     - The server URL to request
+    /// * `method`  - The request method
+    /// * `body`    - The request body
+    /// * `headers` - The request headers
+    pub async fn proxy_http_request(
+        &self,
+        url: Url,
+        method: Method,
+        body: ProxyBody,
+        headers: HeaderMap,
+    ) -> Result<Response<Body>, ApiError> {
+        let token = self.token();
+        let replay = body.try_clone();
+
+        let response = self
+            .try_proxy_http_request(url.clone(), method.clone(), body, headers.clone())
+            .await?;
+
+        // Rejected responses are passed through to the game when they can't be retried
+        if !is_auth_status(response.status()) || !self.has_reauth_credentials() {
+            return Ok(response);
+        }
+
+        // Token was already refreshed and still rejected
+        if token.is_some() && *self.proxy_rejected_token.read() == token {
+            return Ok(response);
+        }
+
+        let Some(body) = replay else {
+            warn!("Server rejected authentication token for a streamed request, not retrying");
+            return Ok(response);
+        };
+
+        warn!("Server rejected authentication token, re-authenticating");
+
+        let token = match self.reauthenticate(token).await {
+            Ok(value) => value,
+            Err(err) => {
+                error!("Failed to re-authenticate: {}", err);
+                return Ok(response);
+            }
+        };
+
+        let response = self
+            .try_proxy_http_request(url, method, body, headers)
+            .await?;
+
+        if is_auth_status(response.status()) {
+            warn!("Server rejected the fresh authentication token, not re-authenticating again");
+            *self.proxy_rejected_token.write() = Some(token);
+        }
+
+        Ok(response)
+    }
+
+    /// Single attempt at [`PocketArkApi::proxy_http_request`]
+    async fn try_proxy_http_request(
+        &self,
+        url: Url,
+        method: Method,
+        body: ProxyBody,
+        mut headers: HeaderMap,
+    ) -> Result<Response<Body>, ApiError> {
+        // Remove conflicting headers
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(X_TOKEN, self.token_header()?);
+
+        // Streamed bodies of a known length keep their length rather than being chunked
+        if let ProxyBody::Streaming {
+            length: Some(length),
+            ..
+        } = &body
+        {
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(*length));
+        }
+
+        // Send the HTTP request and get its response
+        let mut request = self
+            .http_client()
+            .client()
+            .request(method, url)
+            // Include the request headers
+            .headers(headers);
+
+        match body {
+            ProxyBody::Empty => {}
+            ProxyBody::Buffered(body) => request = request.body(body),
+            ProxyBody::Streaming { body, .. } => {
+                request = request.body(reqwest::Body::wrap_stream(body))
+            }
+        }
+
+        let response = request.send().await.map_err(ApiError::RequestFailed)?;
+
+        // Extract response status and headers before its consumed to stream the body
+        let status = response.status();
+        let headers = response.headers().clone();
+
+        // Create new response streaming the proxy response body
+        let mut response = Response::new(Body::wrap_stream(response.bytes_stream()));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+
+        Ok(response)
+    }
+}
+
+/// Request bodies with a known length up to this size are buffered so
+/// that the request can be replayed after re-authenticating
+pub const REPLAY_BODY_LIMIT: u64 = 64 * 1024;
+
+/// Body of a request being proxied to the server
+#[derive(Debug)]
+pub enum ProxyBody {
+    /// Request has no body
+    Empty,
+    /// Fully buffered body, can be replayed
+    Buffered(Bytes),
+    /// Body streamed to the server as it arrives, cannot be replayed
+    Streaming {
+        /// The body to stream
+        body: Body,
+        /// The exact length of the body when known, kept separately
+        /// so that the body can be wrapped without losing it
+        length: Option<u64>,
+    },
+}
+
+impl ProxyBody {
+    /// Creates a proxy body from a request body. Bodies with a known length
+    /// up to [`REPLAY_BODY_LIMIT`] are buffered, larger bodies and bodies
+    /// without a known length are streamed
+    ///
+    /// ## Arguments
+    /// * `body` - The request body
+    pub async fn from_body(body: Body) -> Result<Self, hyper::Error> {
+        if body.is_end_stream() {
+            return Ok(ProxyBody::Empty);
+        }
+
+        let size_hint = body.size_hint();
+        let replayable = size_hint
+            .upper()
+            .is_some_and(|length| length <= REPLAY_BODY_LIMIT);
+
+        if !replayable {
+            return Ok(ProxyBody::Streaming {
+                body,
+                length: size_hint.exact(),
+            });
+        }
+
+        hyper::body::to_bytes(body).await.map(ProxyBody::Buffered)
+    }
+
+    /// Creates a copy of the body if the body can be replayed
+    fn try_clone(&self) -> Option<Self> {
+        match self {
+            ProxyBody::Empty => Some(ProxyBody::Empty),
+            ProxyBody::Buffered(body) => Some(ProxyBody::Buffered(body.clone())),
+            ProxyBody::Streaming { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProxyBody, REPLAY_BODY_LIMIT};
+    use crate::api::{
+        client::test::start_auth_server, create_http_client, HttpClientConfig, PocketArkApi,
+    };
+    use bytes::Bytes;
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Method, Request, Response, Server, StatusCode,
+    };
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+        sync::{atomic::Ordering, Arc},
+    };
+    use url::Url;
+
+    /// Tests that a proxied request rejected by the server is replayed
+    /// after re-authenticating
+    #[tokio::test]
+    async fn test_proxy_request_reauth() {
+        let (api, state) = start_auth_server(true).await;
+
+        let url = api.endpoint_url("data").unwrap();
+        let response = api
+            .proxy_http_request(
+                url,
+                Method::POST,
+                ProxyBody::Buffered(Bytes::from_static(b"body")),
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 2);
+    }
+
+    /// Tests that proxied requests still rejected after re-authenticating
+    /// don't re-authenticate again until the token changes
+    #[tokio::test]
+    async fn test_proxy_request_rejected_after_reauth() {
+        let (api, state) = start_auth_server(false).await;
+
+        let proxy = || async {
+            api.proxy_http_request(
+                api.endpoint_url("data").unwrap(),
+                Method::GET,
+                ProxyBody::Empty,
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap()
+            .status()
+        };
+
+        assert_eq!(proxy().await, StatusCode::UNAUTHORIZED);
+        assert_eq!(state.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 2);
+
+        // Fresh token was rejected so later rejections are passed through
+        for _ in 0..3 {
+            assert_eq!(proxy().await, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(state.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 5);
+
+        // Changing the token allows re-authenticating again
+        api.set_token(Arc::from("stale"));
+        assert_eq!(proxy().await, StatusCode::UNAUTHORIZED);
+        assert_eq!(state.logins.load(Ordering::SeqCst), 2);
+        assert_eq!(state.requests.load(Ordering::SeqCst), 7);
+    }
+
+    /// Tests that a body larger than the replay limit is streamed to the
+    /// server intact and keeps its content length
+    #[tokio::test]
+    async fn test_proxy_request_streamed_body() {
+        // Echoes the request body along with the received content length
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let length = request.headers().get(header::CONTENT_LENGTH).cloned();
+                let body = hyper::body::to_bytes(request.into_body()).await?;
+
+                let mut response = Response::new(Body::from(body));
+                if let Some(length) = length {
+                    response.headers_mut().insert("x-received-length", length);
+                }
+                Ok::<_, hyper::Error>(response)
+            }))
+        });
+
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let api = PocketArkApi::new(http_client, url.clone(), None);
+        api.set_token(Arc::from("token"));
+
+        let length = REPLAY_BODY_LIMIT as usize * 3 + 7;
+        let data: Vec<u8> = (0..length).map(|index| index as u8).collect();
+
+        let body = ProxyBody::from_body(Body::from(data.clone()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            body,
+            ProxyBody::Streaming {
+                length: Some(_),
+                ..
+            }
+        ));
+
+        let response = api
+            .proxy_http_request(url, Method::POST, body, HeaderMap::new())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("x-received-length").unwrap(),
+            &length.to_string()
+        );
+
+        let echoed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(echoed.len(), length);
+        assert!(echoed == data);
+    }
+}
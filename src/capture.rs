@@ -0,0 +1,635 @@
+//! Packet capture for debugging, writes the game side tunnel traffic and
+//! the Blaze stream traffic to a pcapng file that can be opened in Wireshark
+//!
+//! The local servers don't have access to the real packets so the IP, UDP
+//! and TCP headers are synthesized from the socket addresses. Tunnel packets
+//! are tagged with the pool index of their socket using a packet comment
+//!
+//! Capturing is toggled at runtime using [`start_capture`] and [`stop_capture`],
+//! the capture stops automatically once the file reaches its size limit
+//!
+//! Packets are written to the file by a dedicated writer thread so that
+//! file writes never block the servers, packets are dropped if the writer
+//! falls too far behind
+
+use log::{error, warn};
+use parking_lot::Mutex;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Whether a capture is currently running, checked before building
+/// packets to avoid locking when not capturing
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// The current capture
+static CAPTURE: Mutex<Option<CaptureHandle>> = Mutex::new(None);
+
+/// ID to use for the next capture
+static NEXT_CAPTURE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Number of packets that can be queued for the writer thread
+const CAPTURE_QUEUE_SIZE: usize = 4096;
+
+/// Link type for raw IPv4 and IPv6 packets
+const LINKTYPE_RAW: u16 = 101;
+/// IP protocol number for TCP
+const IPPROTO_TCP: u8 = 6;
+/// IP protocol number for UDP
+const IPPROTO_UDP: u8 = 17;
+/// Largest payload that fits within a synthesized IP packet
+const MAX_PAYLOAD: usize = u16::MAX as usize - 60;
+
+/// Block type for the section header block
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+/// Block type for the interface description block
+const BLOCK_INTERFACE: u32 = 0x00000001;
+/// Block type for the enhanced packet block
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+/// Option code for a comment
+const OPT_COMMENT: u16 = 1;
+
+/// Handle to the running capture
+struct CaptureHandle {
+    /// ID of the capture
+    id: u64,
+    /// Sender for the packet blocks to write
+    tx: SyncSender<Vec<u8>>,
+    /// Number of packets dropped due to the queue being full
+    dropped: Arc<AtomicU64>,
+    /// Handle to the writer thread
+    writer: JoinHandle<()>,
+}
+
+/// Capture file owned by the writer thread
+struct Capture {
+    /// Writer for the capture file
+    writer: BufWriter<File>,
+    /// Number of bytes written to the file
+    written: u64,
+    /// Maximum number of bytes to write to the file
+    max_size: u64,
+}
+
+impl Capture {
+    /// Writes a pcapng block with the provided type and body, the body
+    /// must already be padded to a multiple of 4 bytes
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+        let length = (body.len() + 12) as u32;
+        self.writer.write_all(&block_type.to_le_bytes())?;
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.written += length as u64;
+        Ok(())
+    }
+
+    /// Writes the queued packet blocks until the capture is stopped or
+    /// the file reaches its size limit, then flushes the file
+    ///
+    /// ## Arguments
+    /// * `id`      - The ID of the capture
+    /// * `rx`      - Receiver for the packet blocks
+    /// * `dropped` - Number of packets dropped due to the queue being full
+    fn run(mut self, id: u64, rx: Receiver<Vec<u8>>, dropped: Arc<AtomicU64>) {
+        for body in rx {
+            // Stop capturing once the next packet would exceed the limit
+            if self.written + body.len() as u64 + 12 > self.max_size {
+                warn!("Capture file reached its size limit, stopping capture");
+                end_capture(id);
+                break;
+            }
+
+            if let Err(err) = self.write_block(BLOCK_ENHANCED_PACKET, &body) {
+                error!("Failed to write capture packet, stopping capture: {}", err);
+                end_capture(id);
+                break;
+            }
+        }
+
+        if let Err(err) = self.writer.flush() {
+            error!("Failed to flush capture file: {}", err);
+        }
+
+        let dropped = dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Capture dropped {} packets due to slow writes", dropped);
+        }
+    }
+}
+
+/// Starts capturing to a new pcapng file at `path` replacing any
+/// currently running capture. The current capture is finished before
+/// the new file is created so it can safely be replaced
+///
+/// ## Arguments
+/// * `path`     - The path for the capture file
+/// * `max_size` - Maximum size in bytes of the capture file
+pub async fn start_capture(path: impl AsRef<Path>, max_size: u64) -> std::io::Result<()> {
+    stop_capture().await;
+
+    let file = File::create(path)?;
+    let mut capture = Capture {
+        writer: BufWriter::new(file),
+        written: 0,
+        max_size,
+    };
+
+    // Section header, byte order magic, version 1.0 and unknown section length
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    capture.write_block(BLOCK_SECTION_HEADER, &body)?;
+
+    // Interface with raw IP packets and no snap length
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    capture.write_block(BLOCK_INTERFACE, &body)?;
+
+    let id = NEXT_CAPTURE_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = sync_channel(CAPTURE_QUEUE_SIZE);
+    let dropped = Arc::new(AtomicU64::new(0));
+
+    let writer = std::thread::Builder::new()
+        .name("capture-writer".to_string())
+        .spawn({
+            let dropped = dropped.clone();
+            move || capture.run(id, rx, dropped)
+        })?;
+
+    // Another capture may have been started while this one was created
+    let previous = {
+        let guard = &mut *CAPTURE.lock();
+        CAPTURING.store(true, Ordering::Relaxed);
+        guard.replace(CaptureHandle {
+            id,
+            tx,
+            dropped,
+            writer,
+        })
+    };
+
+    if let Some(previous) = previous {
+        finish_capture(previous).await;
+    }
+
+    Ok(())
+}
+
+/// Stops the current capture, waits for the queued packets to be
+/// written and the capture file to be flushed
+pub async fn stop_capture() {
+    let capture = {
+        let guard = &mut *CAPTURE.lock();
+        CAPTURING.store(false, Ordering::Relaxed);
+        guard.take()
+    };
+
+    if let Some(capture) = capture {
+        finish_capture(capture).await;
+    }
+}
+
+/// Closes the queue for a capture and waits for its writer thread
+/// to write the remaining packets, the writer thread is joined on
+/// the blocking thread pool
+///
+/// ## Arguments
+/// * `capture` - The capture to finish
+async fn finish_capture(capture: CaptureHandle) {
+    let CaptureHandle { tx, writer, .. } = capture;
+    drop(tx);
+
+    match tokio::task::spawn_blocking(move || writer.join()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(_)) => error!("Capture writer thread panicked"),
+        Err(err) => error!("Failed to join capture writer thread: {}", err),
+    }
+}
+
+/// Ends the capture with the provided ID from its writer thread, other
+/// captures started in the meantime are left running
+///
+/// ## Arguments
+/// * `id` - The ID of the capture
+fn end_capture(id: u64) {
+    let guard = &mut *CAPTURE.lock();
+    if guard.as_ref().is_some_and(|capture| capture.id == id) {
+        CAPTURING.store(false, Ordering::Relaxed);
+        // Writer thread is the current thread so its handle is only dropped
+        *guard = None;
+    }
+}
+
+/// Whether a capture is currently running
+pub fn is_capturing() -> bool {
+    CAPTURING.load(Ordering::Relaxed)
+}
+
+/// Captures a UDP packet
+///
+/// ## Arguments
+/// * `src`     - The source address
+/// * `dst`     - The destination address
+/// * `payload` - The packet payload
+/// * `comment` - Comment to attach to the packet, empty for no comment
+pub(crate) fn capture_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8], comment: &str) {
+    if !is_capturing() {
+        return;
+    }
+
+    let payload = &payload[..payload.len().min(MAX_PAYLOAD)];
+
+    let mut transport = Vec::with_capacity(8 + payload.len());
+    transport.extend_from_slice(&src.port().to_be_bytes());
+    transport.extend_from_slice(&dst.port().to_be_bytes());
+    transport.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    // Checksum is optional for UDP
+    transport.extend_from_slice(&0u16.to_be_bytes());
+    transport.extend_from_slice(payload);
+
+    write_packet(src.ip(), dst.ip(), IPPROTO_UDP, &transport, comment);
+}
+
+/// Direction of a TCP stream being captured, tracks the sequence number
+/// so the stream can be reassembled
+pub(crate) struct TcpCaptureFlow {
+    /// The source address
+    src: SocketAddr,
+    /// The destination address
+    dst: SocketAddr,
+    /// Sequence number of the next segment
+    seq: u32,
+}
+
+impl TcpCaptureFlow {
+    /// Creates a new flow
+    ///
+    /// ## Arguments
+    /// * `src` - The source address
+    /// * `dst` - The destination address
+    pub(crate) fn new(src: SocketAddr, dst: SocketAddr) -> Self {
+        Self { src, dst, seq: 0 }
+    }
+
+    /// Captures a TCP segment containing the provided payload, the sequence
+    /// number is advanced even when not capturing
+    ///
+    /// ## Arguments
+    /// * `payload` - The segment payload
+    pub(crate) fn capture(&mut self, payload: &[u8]) {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(payload.len() as u32);
+
+        if !is_capturing() {
+            return;
+        }
+
+        for (index, payload) in payload.chunks(MAX_PAYLOAD).enumerate() {
+            let seq = seq.wrapping_add((index * MAX_PAYLOAD) as u32);
+
+            let mut transport = Vec::with_capacity(20 + payload.len());
+            transport.extend_from_slice(&self.src.port().to_be_bytes());
+            transport.extend_from_slice(&self.dst.port().to_be_bytes());
+            transport.extend_from_slice(&seq.to_be_bytes());
+            // Acknowledgement number
+            transport.extend_from_slice(&0u32.to_be_bytes());
+            // Header length of 5 words and the PSH flag
+            transport.extend_from_slice(&[0x50, 0x08]);
+            // Window size
+            transport.extend_from_slice(&u16::MAX.to_be_bytes());
+            // Checksum and urgent pointer
+            transport.extend_from_slice(&[0, 0, 0, 0]);
+            transport.extend_from_slice(payload);
+
+            write_packet(self.src.ip(), self.dst.ip(), IPPROTO_TCP, &transport, "");
+        }
+    }
+}
+
+/// Wraps the transport segment in an IP header and writes it to
+/// the capture file
+///
+/// ## Arguments
+/// * `src`       - The source IP
+/// * `dst`       - The destination IP
+/// * `protocol`  - The transport protocol
+/// * `transport` - The transport segment
+/// * `comment`   - Comment to attach to the packet, empty for no comment
+fn write_packet(src: IpAddr, dst: IpAddr, protocol: u8, transport: &[u8], comment: &str) {
+    let packet = ip_packet(src, dst, protocol, transport);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or_default();
+
+    let body = packet_block_body(&packet, timestamp, comment);
+
+    let guard = &*CAPTURE.lock();
+    let Some(capture) = guard else {
+        return;
+    };
+
+    // Packets are dropped rather than waiting on the writer
+    if let Err(TrySendError::Full(_)) = capture.tx.try_send(body) {
+        capture.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Creates an IP packet containing the transport segment, an IPv6 header
+/// is used if either of the addresses is IPv6
+///
+/// ## Arguments
+/// * `src`       - The source IP
+/// * `dst`       - The destination IP
+/// * `protocol`  - The transport protocol
+/// * `transport` - The transport segment
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, transport: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + transport.len());
+
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let length = (20 + transport.len()) as u16;
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&length.to_be_bytes());
+            header[8] = 64;
+            header[9] = protocol;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            packet.extend_from_slice(&header);
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+            packet.push(protocol);
+            packet.push(64);
+            packet.extend_from_slice(&to_v6(src).octets());
+            packet.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+
+    packet.extend_from_slice(transport);
+    packet
+}
+
+/// Creates the body of an enhanced packet block for the packet, the
+/// body is padded to a multiple of 4 bytes
+///
+/// ## Arguments
+/// * `packet`    - The IP packet
+/// * `timestamp` - Timestamp of the packet in microseconds
+/// * `comment`   - Comment to attach to the packet, empty for no comment
+fn packet_block_body(packet: &[u8], timestamp: u64, comment: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(32 + packet.len() + comment.len());
+    // Interface ID
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    // Captured and original lengths
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    pad_to_word(&mut body);
+
+    if !comment.is_empty() {
+        body.extend_from_slice(&OPT_COMMENT.to_le_bytes());
+        body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+        pad_to_word(&mut body);
+        // End of options
+        body.extend_from_slice(&[0, 0, 0, 0]);
+    }
+
+    body
+}
+
+/// Pads the buffer with zeros to a multiple of 4 bytes
+fn pad_to_word(buffer: &mut Vec<u8>) {
+    let padding = (4 - buffer.len() % 4) % 4;
+    buffer.resize(buffer.len() + padding, 0);
+}
+
+/// Computes the checksum for an IPv4 header
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        capture_udp, ip_packet, ipv4_checksum, packet_block_body, start_capture, stop_capture,
+        TcpCaptureFlow, BLOCK_ENHANCED_PACKET, BLOCK_INTERFACE, BLOCK_SECTION_HEADER, IPPROTO_TCP,
+        IPPROTO_UDP, MAX_PAYLOAD, OPT_COMMENT,
+    };
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    /// Reads a little endian u32 from the buffer at `offset`
+    fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Splits a pcapng file into its blocks, checking that the leading
+    /// and trailing lengths of each block match and are word aligned
+    fn read_blocks(file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+
+        while offset < file.len() {
+            let block_type = read_u32(file, offset);
+            let length = read_u32(file, offset + 4) as usize;
+            assert_eq!(length % 4, 0, "block length must be word aligned");
+            assert_eq!(read_u32(file, offset + length - 4) as usize, length);
+
+            blocks.push((block_type, &file[offset + 8..offset + length - 4]));
+            offset += length;
+        }
+
+        assert_eq!(offset, file.len());
+        blocks
+    }
+
+    /// Extracts the IP packet from an enhanced packet block body
+    fn block_packet(body: &[u8]) -> &[u8] {
+        let length = read_u32(body, 12) as usize;
+        assert_eq!(read_u32(body, 16) as usize, length);
+        &body[20..20 + length]
+    }
+
+    /// Tests the checksum against a known IPv4 header
+    #[test]
+    fn test_ipv4_checksum() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(ipv4_checksum(&header), 0xb861);
+    }
+
+    /// Tests the synthesized IPv4 header
+    #[test]
+    fn test_ipv4_packet() {
+        let src = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let packet = ip_packet(src, dst, IPPROTO_UDP, &[1, 2, 3]);
+
+        assert_eq!(packet.len(), 23);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 23);
+        assert_eq!(packet[9], IPPROTO_UDP);
+        assert_eq!(&packet[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&packet[16..20], &[10, 0, 0, 2]);
+        assert_eq!(&packet[20..], &[1, 2, 3]);
+
+        // Checksum over a header including its checksum is zero
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+    }
+
+    /// Tests that an IPv6 header is used when either address is IPv6
+    /// with IPv4 addresses mapped into IPv6
+    #[test]
+    fn test_ipv6_packet() {
+        let src = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let packet = ip_packet(src, dst, IPPROTO_TCP, &[1, 2, 3, 4, 5]);
+
+        assert_eq!(packet.len(), 45);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 5);
+        assert_eq!(packet[6], IPPROTO_TCP);
+        assert_eq!(&packet[8..24], &Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(
+            &packet[24..40],
+            &Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped().octets()
+        );
+        assert_eq!(&packet[40..], &[1, 2, 3, 4, 5]);
+    }
+
+    /// Tests that the packet data and comment option are padded to words
+    #[test]
+    fn test_packet_block_padding() {
+        let body = packet_block_body(&[1, 2, 3, 4, 5], 0x1_0000_0002, "abc");
+
+        // 20 byte header, 5 byte packet padded to 8, 7 byte option
+        // padded to 8 then the 4 byte end of options
+        assert_eq!(body.len(), 40);
+        assert_eq!(read_u32(&body, 4), 1);
+        assert_eq!(read_u32(&body, 8), 2);
+        assert_eq!(block_packet(&body), &[1, 2, 3, 4, 5]);
+        assert_eq!(&body[25..28], &[0, 0, 0]);
+        assert_eq!(&body[28..30], &OPT_COMMENT.to_le_bytes());
+        assert_eq!(&body[30..32], &3u16.to_le_bytes());
+        assert_eq!(&body[32..36], b"abc\0");
+        assert_eq!(&body[36..40], &[0, 0, 0, 0]);
+
+        // No options are written without a comment
+        let body = packet_block_body(&[1, 2, 3, 4], 0, "");
+        assert_eq!(body.len(), 24);
+    }
+
+    /// Tests capturing to a file, replacing a running capture writing to
+    /// the same path, and the UDP and TCP headers written to the file with
+    /// TCP sequence numbers advancing across the split segments
+    #[tokio::test]
+    async fn test_capture_file() {
+        let path =
+            std::env::temp_dir().join(format!("pocket-ark-capture-{}.pcapng", std::process::id()));
+
+        let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 3659));
+        let server = SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2), 42132));
+
+        // Packets from the replaced capture must not end up in the file
+        start_capture(&path, u64::MAX).await.unwrap();
+        capture_udp(client, server, b"replaced", "");
+        start_capture(&path, u64::MAX).await.unwrap();
+
+        capture_udp(client, server, b"hello", "socket 1");
+
+        let mut flow = TcpCaptureFlow::new(client, server);
+        flow.capture(&vec![7u8; MAX_PAYLOAD + 10]);
+        flow.capture(&[8u8; 3]);
+
+        stop_capture().await;
+
+        let file = std::fs::read(&path).unwrap();
+        _ = std::fs::remove_file(&path);
+
+        let blocks = read_blocks(&file);
+        let types: Vec<u32> = blocks.iter().map(|(ty, _)| *ty).collect();
+        assert_eq!(
+            types,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+            ]
+        );
+
+        // UDP packet with its comment
+        let udp_block = blocks[2].1;
+        let packet = block_packet(udp_block);
+        assert_eq!(packet[9], IPPROTO_UDP);
+        let udp = &packet[20..];
+        assert_eq!(u16::from_be_bytes([udp[0], udp[1]]), 3659);
+        assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), 42132);
+        assert_eq!(u16::from_be_bytes([udp[4], udp[5]]), 13);
+        assert_eq!(&udp[8..], b"hello");
+        assert!(udp_block
+            .windows(b"socket 1".len())
+            .any(|window| window == b"socket 1"));
+
+        // TCP segments split at the maximum payload
+        let segments: Vec<(u32, usize)> = blocks[3..]
+            .iter()
+            .map(|(_, body)| {
+                let packet = block_packet(body);
+                assert_eq!(packet[9], IPPROTO_TCP);
+                let tcp = &packet[20..];
+                assert_eq!(u16::from_be_bytes([tcp[0], tcp[1]]), 3659);
+                assert_eq!(u16::from_be_bytes([tcp[2], tcp[3]]), 42132);
+                assert_eq!(tcp[12], 0x50);
+                let seq = u32::from_be_bytes(tcp[4..8].try_into().unwrap());
+                (seq, tcp.len() - 20)
+            })
+            .collect();
+
+        let max = MAX_PAYLOAD as u32;
+        assert_eq!(segments, [(0, MAX_PAYLOAD), (max, 10), (max + 10, 3)]);
+    }
+}
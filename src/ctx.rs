@@ -1,20 +1,39 @@
 //! Shared context state that the app should store and pass to the
 //! various servers when they are started
 
-use url::Url;
-
-use crate::api::AuthToken;
+use crate::{
+    api::{LookupData, PocketArkApi, ServerCapabilities},
+    servers::http_middleware::HttpMiddleware,
+};
 
 /// Shared context
 pub struct ClientContext {
-    /// HTTP client for the client to make requests with
-    pub http_client: reqwest::Client,
-    /// Base URL of the connected server
-    pub base_url: Url,
-    /// Optional association token
-    pub association: Option<String>,
+    /// API client for the connected server, must be authenticated
+    /// before the servers are started
+    pub api: PocketArkApi,
     /// Optional tunnel port for tunnel V2 if available
     pub tunnel_port: Option<u16>,
-    /// Authentication token
-    pub token: AuthToken,
+    /// Capabilities reported by the server during the lookup, the
+    /// servers consult these when choosing their behaviour
+    pub capabilities: ServerCapabilities,
+    /// Middleware for the HTTP proxy server, requests pass through
+    /// the middleware in order
+    pub http_middleware: Vec<Box<dyn HttpMiddleware>>,
+}
+
+impl ClientContext {
+    /// Creates a context for the server from the API client and the data
+    /// obtained when looking up the server, without any middleware
+    ///
+    /// ## Arguments
+    /// * `api`    - The authenticated API client
+    /// * `lookup` - The server lookup data
+    pub fn new(api: PocketArkApi, lookup: &LookupData) -> Self {
+        Self {
+            api,
+            tunnel_port: lookup.tunnel_port,
+            capabilities: lookup.capabilities.clone(),
+            http_middleware: Vec::new(),
+        }
+    }
 }
@@ -0,0 +1,237 @@
+//! Discovery of Pocket Ark servers on the local network
+//!
+//! A probe message is broadcast over UDP to the discovery port, servers
+//! reply to the probe with the port and scheme of their HTTP server. Each
+//! reply is then validated using a server lookup to ensure it is actually
+//! a Pocket Ark server
+//!
+//! # Discovery Reply
+//!
+//! Replies are JSON objects:
+//!
+//! ```json
+//! { "ident": "POCKET_ARK_SERVER", "port": 80, "https": false }
+//! ```
+
+use crate::api::{HttpClient, LookupData, PocketArkApi, SERVER_IDENT};
+use futures::future::join_all;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+/// The default port servers listen for discovery probes on
+pub const DISCOVERY_PORT: u16 = 42150;
+/// Message broadcast to discover servers
+pub const DISCOVERY_PROBE: &[u8] = b"POCKET_ARK_DISCOVERY";
+
+/// Configuration for discovery
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Address to send the probe to, defaults to the broadcast address
+    /// but can be a specific address such as a local responder
+    pub target: SocketAddr,
+    /// How long to wait for replies to the probe
+    pub timeout: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            target: SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Server that was found through discovery
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    /// Lookup data from validating the server
+    pub lookup: LookupData,
+    /// Address the discovery reply came from
+    pub responder: SocketAddr,
+    /// Time between sending the probe and receiving the reply
+    pub latency: Duration,
+}
+
+/// Reply to a discovery probe
+#[derive(Deserialize)]
+struct DiscoveryReply {
+    /// Server identifier
+    ident: String,
+    /// Port of the server HTTP server
+    port: u16,
+    /// Whether the server HTTP server uses HTTPS
+    #[serde(default)]
+    https: bool,
+}
+
+/// Discovers servers on the local network, servers that reply but fail
+/// validation are excluded from the results
+///
+/// ## Arguments
+/// * `http_client` - The HTTP client to validate servers with
+/// * `config`      - The discovery configuration
+pub async fn discover_servers(
+    http_client: HttpClient,
+    config: &DiscoveryConfig,
+) -> std::io::Result<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+
+    let start = Instant::now();
+    let deadline = start + config.timeout;
+
+    socket.send_to(DISCOVERY_PROBE, config.target).await?;
+
+    // Hosts of the servers that have replied along with the reply latency
+    let mut replies: Vec<(String, SocketAddr, Duration)> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut buffer = [0u8; 512];
+
+    while let Ok(result) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (count, responder) = match result {
+            Ok(value) => value,
+            // Errors such as ICMP port unreachable resets shouldn't end discovery
+            Err(err) => {
+                warn!("Failed to receive discovery reply: {}", err);
+                continue;
+            }
+        };
+        let latency = start.elapsed();
+
+        let reply: DiscoveryReply = match serde_json::from_slice(&buffer[..count]) {
+            Ok(value) => value,
+            Err(err) => {
+                debug!("Invalid discovery reply from {}: {}", responder, err);
+                continue;
+            }
+        };
+
+        if reply.ident != SERVER_IDENT {
+            debug!("Discovery reply from {} had unknown ident", responder);
+            continue;
+        }
+
+        let scheme = if reply.https { "https" } else { "http" };
+        let host = format!("{}://{}:{}", scheme, responder.ip(), reply.port);
+
+        if seen.insert(host.clone()) {
+            replies.push((host, responder, latency));
+        }
+    }
+
+    // Validate the replies using a server lookup
+    let servers = join_all(replies.into_iter().map(|(host, responder, latency)| {
+        let http_client = http_client.clone();
+        async move {
+            match PocketArkApi::lookup(http_client, &host).await {
+                Ok((_, lookup)) => Some(DiscoveredServer {
+                    lookup,
+                    responder,
+                    latency,
+                }),
+                Err(err) => {
+                    debug!("Discovered server {} failed validation: {}", host, err);
+                    None
+                }
+            }
+        }
+    }))
+    .await;
+
+    Ok(servers.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{discover_servers, DiscoveryConfig, DISCOVERY_PROBE};
+    use crate::{
+        api::{create_http_client, HttpClientConfig, SERVER_IDENT},
+        MIN_SERVER_VERSION,
+    };
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use serde_json::json;
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+    use tokio::net::UdpSocket;
+
+    /// Starts a HTTP server that answers every request with the server details
+    fn start_details_server() -> u16 {
+        let body = json!({ "version": MIN_SERVER_VERSION, "ident": SERVER_IDENT }).to_string();
+
+        let make_service = make_service_fn(move |_| {
+            let body = body.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let response = Response::new(Body::from(body.clone()));
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+
+        port
+    }
+
+    /// Tests discovery against a loopback responder that sends invalid,
+    /// unknown, duplicate and valid replies, only the valid server should
+    /// be discovered
+    #[tokio::test]
+    async fn test_loopback_responder() {
+        let http_port = start_details_server();
+
+        let responder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target = responder.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 64];
+            let (count, addr) = responder.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..count], DISCOVERY_PROBE);
+
+            let valid = format!(
+                r#"{{"ident":"{}","port":{},"https":false}}"#,
+                SERVER_IDENT, http_port
+            );
+            let replies = [
+                "not json".to_string(),
+                r#"{"ident":"OTHER_SERVER","port":80}"#.to_string(),
+                valid.clone(),
+                valid,
+            ];
+
+            for reply in replies {
+                responder.send_to(reply.as_bytes(), addr).await.unwrap();
+            }
+        });
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let config = DiscoveryConfig {
+            target,
+            timeout: Duration::from_millis(500),
+        };
+
+        let servers = discover_servers(http_client, &config).await.unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].responder, target);
+        assert_eq!(servers[0].lookup.url.port(), Some(http_port));
+    }
+}
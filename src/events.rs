@@ -0,0 +1,270 @@
+//! Typed event stream for the lifecycle of the local servers and their
+//! connections, clients can subscribe to this to display the state of
+//! the servers instead of relying on the log output
+
+use crate::{
+    health::ServerHealth,
+    servers::{
+        blaze_frame::{FrameDirection, FrameHeader},
+        supervisor::ServerKind,
+        tunnel_manager::TunnelTransport,
+    },
+};
+use semver::Version;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+/// Number of events that can be buffered for slow receivers before
+/// they start to miss events
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Sender for the event channel, created on first use
+static EVENT_SENDER: OnceLock<broadcast::Sender<ServerEvent>> = OnceLock::new();
+
+/// Events emitted by the servers
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// Server successfully bound its local socket
+    ServerBound {
+        /// The server that was bound
+        kind: ServerKind,
+        /// The address the server bound to
+        addr: SocketAddr,
+    },
+    /// Server failed to bind its local socket
+    ServerBindFailed {
+        /// The server that failed to bind
+        kind: ServerKind,
+        /// The address the server attempted to bind
+        addr: SocketAddr,
+        /// The bind error message
+        error: String,
+    },
+    /// Game client connected to the Blaze server
+    BlazeConnectionOpened {
+        /// Address of the game client
+        peer: SocketAddr,
+    },
+    /// Game client connection to the Blaze server was closed
+    BlazeConnectionClosed {
+        /// Address of the game client
+        peer: SocketAddr,
+        /// Error message if the connection closed due to an error
+        error: Option<String>,
+    },
+    /// Blaze frame passed through the Blaze server, only emitted
+    /// when frame inspection is enabled
+    BlazeFrame {
+        /// Address of the game client
+        peer: SocketAddr,
+        /// Direction the frame was travelling
+        direction: FrameDirection,
+        /// The decoded frame header
+        header: FrameHeader,
+    },
+    /// HTTP request was proxied to the server
+    HttpRequestProxied {
+        /// The request method
+        method: String,
+        /// The request path and query
+        path: String,
+        /// The response status code, [None] if the request failed
+        status: Option<u16>,
+    },
+    /// QoS server resolved the address for a client
+    QosAddressResolved {
+        /// Address the QoS request came from
+        peer: SocketAddr,
+        /// The resolved public address
+        address: Ipv4Addr,
+    },
+    /// Tunnel completed its handshake with the server
+    TunnelConnected {
+        /// The tunnel server that connected
+        kind: ServerKind,
+        /// The tunnel ID if the tunnel variant provides one
+        tunnel_id: Option<u32>,
+    },
+    /// Tunnel is going to attempt to reconnect
+    TunnelReconnecting {
+        /// The tunnel server that is reconnecting
+        kind: ServerKind,
+        /// Number of consecutive failed attempts
+        attempt_errors: u8,
+        /// Delay before the next attempt
+        delay: Duration,
+        /// Error message if the previous attempt failed
+        error: Option<String>,
+    },
+    /// Tunnel manager switched the active tunnel transport
+    TunnelTransportChanged {
+        /// The new active transport, [None] if the tunnel stopped
+        transport: Option<TunnelTransport>,
+    },
+    /// Health monitor detected a change in the server reachability
+    ServerHealthChanged {
+        /// The new server health
+        health: ServerHealth,
+        /// Error message if the health check failed
+        error: Option<String>,
+    },
+    /// Health monitor detected that the server version changed
+    ServerVersionChanged {
+        /// The previously known version
+        previous: Version,
+        /// The current version
+        current: Version,
+    },
+    /// Server stopped due to an error it could not recover from
+    FatalError {
+        /// The server that stopped
+        kind: ServerKind,
+        /// The error message
+        error: String,
+    },
+}
+
+/// Obtains the event sender, creating the channel if it doesn't exist
+fn sender() -> &'static broadcast::Sender<ServerEvent> {
+    EVENT_SENDER.get_or_init(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0)
+}
+
+/// Subscribes to the server events, only events emitted after
+/// subscribing will be received
+pub fn subscribe() -> broadcast::Receiver<ServerEvent> {
+    sender().subscribe()
+}
+
+/// Emits an event to all the current subscribers
+///
+/// ## Arguments
+/// * `event` - The event to emit
+pub(crate) fn emit(event: ServerEvent) {
+    // Sending only fails when there are no subscribers
+    _ = sender().send(event);
+}
+
+/// Emits the appropriate bound or bind failed event for the result
+/// of binding a server socket, the result is passed through
+///
+/// ## Arguments
+/// * `kind`   - The server that was bound
+/// * `addr`   - The address the server attempted to bind
+/// * `result` - The result of binding
+pub(crate) fn emit_bind_result<T>(
+    kind: ServerKind,
+    addr: SocketAddr,
+    result: std::io::Result<T>,
+) -> std::io::Result<T> {
+    emit(match &result {
+        Ok(_) => ServerEvent::ServerBound { kind, addr },
+        Err(err) => ServerEvent::ServerBindFailed {
+            kind,
+            addr,
+            error: err.to_string(),
+        },
+    });
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{emit, emit_bind_result, subscribe, ServerEvent, EVENT_CHANNEL_CAPACITY};
+    use crate::servers::supervisor::ServerKind;
+    use semver::Version;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+    /// Creates an event that can be told apart from events emitted
+    /// by other tests sharing the channel, `test` identifies the test
+    fn marker(test: u64, id: u64) -> ServerEvent {
+        ServerEvent::ServerVersionChanged {
+            previous: Version::new(0, 0, 0),
+            current: Version::new(999, test, id),
+        }
+    }
+
+    /// Receives events until a marker for `test` is found, returning its ID
+    async fn recv_marker(rx: &mut Receiver<ServerEvent>, test: u64) -> u64 {
+        loop {
+            match rx.recv().await {
+                Ok(ServerEvent::ServerVersionChanged { current, .. })
+                    if current.major == 999 && current.minor == test =>
+                {
+                    return current.patch
+                }
+                // Other tests may flood the shared channel
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => panic!("event channel closed"),
+            }
+        }
+    }
+
+    /// Tests that subscribers only receive events emitted after subscribing
+    #[tokio::test]
+    async fn test_emit_subscribe() {
+        emit(marker(1, 1));
+
+        let mut first = subscribe();
+        let mut second = subscribe();
+        emit(marker(1, 2));
+
+        assert_eq!(recv_marker(&mut first, 1).await, 2);
+        assert_eq!(recv_marker(&mut second, 1).await, 2);
+    }
+
+    /// Tests that the bind result is passed through with the matching event
+    #[tokio::test]
+    async fn test_emit_bind_result() {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let mut rx = subscribe();
+
+        let result = emit_bind_result(
+            ServerKind::Redirector,
+            addr,
+            Err::<(), _>(std::io::Error::other("in use")),
+        );
+        assert!(result.is_err());
+
+        loop {
+            match rx.recv().await {
+                Ok(ServerEvent::ServerBindFailed {
+                    kind: ServerKind::Redirector,
+                    addr: event_addr,
+                    error,
+                }) if event_addr == addr => {
+                    assert_eq!(error, "in use");
+                    break;
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => panic!("event channel closed"),
+            }
+        }
+    }
+
+    /// Tests that slow receivers are told how many events they missed and
+    /// then continue receiving from the oldest buffered event
+    #[tokio::test]
+    async fn test_lagged_receiver() {
+        let mut rx = subscribe();
+
+        let total = EVENT_CHANNEL_CAPACITY as u64 + 10;
+        for id in 0..total {
+            emit(marker(2, id));
+        }
+
+        match rx.recv().await {
+            Err(RecvError::Lagged(missed)) => assert!(missed >= 10),
+            other => panic!("expected lagged receiver, got {other:?}"),
+        }
+
+        // Receiver continues with the events still in the buffer
+        let next = recv_marker(&mut rx, 2).await;
+        assert!(next >= 10 && next < total);
+    }
+}
@@ -0,0 +1,302 @@
+//! Background monitor for the health of the connected server
+//!
+//! The monitor periodically requests the server details to measure the
+//! round trip latency and to ensure the server is still a supported
+//! Pocket Ark server. Changes in reachability and server version are
+//! published through the [`crate::events`] stream so clients can warn
+//! the player before they attempt to join a match
+
+use crate::{
+    api::{ApiError, PocketArkApi},
+    events::{emit, ServerEvent},
+};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use semver::Version;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    select,
+    time::{interval, Instant, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Default interval between health checks
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Reachability of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerHealth {
+    /// Server responded with its details
+    Reachable,
+    /// Server could not be reached or responded with an error
+    Unreachable,
+    /// Server was reached but is no longer a supported Pocket Ark server
+    Incompatible,
+}
+
+/// Result of a single health check
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// The server reachability
+    pub health: ServerHealth,
+    /// The server version, [None] if the server was unreachable
+    pub version: Option<Version>,
+    /// Round trip latency of the details request, [None] if the
+    /// server was unreachable
+    pub latency: Option<Duration>,
+    /// Error message if the check failed
+    pub error: Option<String>,
+    /// When the check was completed
+    pub checked_at: SystemTime,
+}
+
+/// Handle to a running health monitor, the monitor is stopped
+/// when the handle is dropped
+pub struct HealthMonitor {
+    /// Token to stop the monitor task
+    stop: CancellationToken,
+    /// The most recent health check
+    latest: Arc<Mutex<Option<HealthCheck>>>,
+}
+
+impl HealthMonitor {
+    /// Starts monitoring the server in the background, must be called
+    /// from within a tokio runtime
+    ///
+    /// ## Arguments
+    /// * `api`      - The API client for the server
+    /// * `version`  - The server version from the initial lookup
+    /// * `interval` - Interval between health checks
+    pub fn start(api: PocketArkApi, version: Version, interval: Duration) -> Self {
+        let stop = CancellationToken::new();
+        let latest = Arc::new(Mutex::new(None));
+
+        tokio::spawn(run_monitor(
+            api,
+            version,
+            interval,
+            stop.clone(),
+            latest.clone(),
+        ));
+
+        Self { stop, latest }
+    }
+
+    /// The most recent health check, [None] until the first
+    /// check has completed
+    pub fn latest(&self) -> Option<HealthCheck> {
+        self.latest.lock().clone()
+    }
+
+    /// Stops the health monitor
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Runs the health checks until the monitor is stopped
+///
+/// ## Arguments
+/// * `api`      - The API client for the server
+/// * `version`  - The server version from the initial lookup
+/// * `period`   - Interval between health checks
+/// * `stop`     - Token to stop the monitor
+/// * `latest`   - Storage for the most recent health check
+async fn run_monitor(
+    api: PocketArkApi,
+    mut version: Version,
+    period: Duration,
+    stop: CancellationToken,
+    latest: Arc<Mutex<Option<HealthCheck>>>,
+) {
+    let mut ticker = interval(period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // The server was reachable during the initial lookup
+    let mut health = ServerHealth::Reachable;
+
+    loop {
+        select! {
+            _ = stop.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let check = select! {
+            _ = stop.cancelled() => break,
+            check = check_health(&api) => check,
+        };
+
+        debug!(
+            "Server health check: {:?} (latency: {:?})",
+            check.health, check.latency
+        );
+
+        if let Some(current) = &check.version {
+            if *current != version {
+                emit(ServerEvent::ServerVersionChanged {
+                    previous: version.clone(),
+                    current: current.clone(),
+                });
+                version = current.clone();
+            }
+        }
+
+        if check.health != health {
+            if let Some(error) = &check.error {
+                warn!("Server is now {:?}: {}", check.health, error);
+            }
+
+            emit(ServerEvent::ServerHealthChanged {
+                health: check.health,
+                error: check.error.clone(),
+            });
+            health = check.health;
+        }
+
+        *latest.lock() = Some(check);
+    }
+}
+
+/// Performs a single health check against the server
+///
+/// ## Arguments
+/// * `api` - The API client for the server
+async fn check_health(api: &PocketArkApi) -> HealthCheck {
+    let start = Instant::now();
+    let result = api.server_version().await;
+    let latency = start.elapsed();
+
+    let (health, version, latency, error) = match result {
+        Ok(version) => (ServerHealth::Reachable, Some(version), Some(latency), None),
+        Err(ApiError::ServerOutdated(version, minimum)) => {
+            let error = ApiError::ServerOutdated(version.clone(), minimum).to_string();
+            (
+                ServerHealth::Incompatible,
+                Some(version),
+                Some(latency),
+                Some(error),
+            )
+        }
+        Err(err @ ApiError::NotPocketArk) => (
+            ServerHealth::Incompatible,
+            None,
+            Some(latency),
+            Some(err.to_string()),
+        ),
+        Err(err) => (ServerHealth::Unreachable, None, None, Some(err.to_string())),
+    };
+
+    HealthCheck {
+        health,
+        version,
+        latency,
+        error,
+        checked_at: SystemTime::now(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_health, ServerHealth};
+    use crate::{
+        api::{create_http_client, HttpClientConfig, PocketArkApi, SERVER_IDENT},
+        MIN_SERVER_VERSION,
+    };
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    };
+    use semver::Version;
+    use serde_json::json;
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+    };
+    use url::Url;
+
+    /// Starts a server that responds to every request with the provided
+    /// status and body, returns an API client for the server
+    fn start_server(status: StatusCode, body: String) -> PocketArkApi {
+        let make_service = make_service_fn(move |_| {
+            let body = body.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let mut response = Response::new(Body::from(body.clone()));
+                    *response.status_mut() = status;
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        PocketArkApi::new(http_client, url, None)
+    }
+
+    /// Tests that supported servers are reachable with their version
+    #[tokio::test]
+    async fn test_reachable() {
+        let body = json!({ "version": MIN_SERVER_VERSION.to_string(), "ident": SERVER_IDENT });
+        let api = start_server(StatusCode::OK, body.to_string());
+
+        let check = check_health(&api).await;
+        assert_eq!(check.health, ServerHealth::Reachable);
+        assert_eq!(check.version, Some(MIN_SERVER_VERSION));
+        assert!(check.latency.is_some());
+        assert!(check.error.is_none());
+    }
+
+    /// Tests that outdated servers and servers that aren't Pocket Ark
+    /// servers are incompatible rather than unreachable
+    #[tokio::test]
+    async fn test_incompatible() {
+        let body = json!({ "version": "0.0.1", "ident": SERVER_IDENT });
+        let api = start_server(StatusCode::OK, body.to_string());
+
+        let check = check_health(&api).await;
+        assert_eq!(check.health, ServerHealth::Incompatible);
+        assert_eq!(check.version, Some(Version::new(0, 0, 1)));
+        assert!(check.latency.is_some());
+        assert!(check.error.is_some());
+
+        let body = json!({ "version": MIN_SERVER_VERSION.to_string(), "ident": "OTHER" });
+        let api = start_server(StatusCode::OK, body.to_string());
+
+        let check = check_health(&api).await;
+        assert_eq!(check.health, ServerHealth::Incompatible);
+        assert_eq!(check.version, None);
+        assert!(check.error.is_some());
+    }
+
+    /// Tests that error responses and failed connections are unreachable
+    #[tokio::test]
+    async fn test_unreachable() {
+        let api = start_server(StatusCode::INTERNAL_SERVER_ERROR, String::new());
+
+        let check = check_health(&api).await;
+        assert_eq!(check.health, ServerHealth::Unreachable);
+        assert_eq!(check.version, None);
+        assert_eq!(check.latency, None);
+        assert!(check.error.is_some());
+
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let check = check_health(&PocketArkApi::new(http_client, url, None)).await;
+        assert_eq!(check.health, ServerHealth::Unreachable);
+    }
+}
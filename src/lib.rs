@@ -14,9 +14,19 @@ pub use semver::Version;
 pub use url::Url;
 
 pub mod api;
+pub mod capture;
 pub mod ctx;
+pub mod discovery;
+pub mod events;
+pub mod health;
+pub mod profile;
+pub mod proxy;
 pub mod servers;
+pub mod session;
 pub mod ssl;
+pub mod stats;
+pub mod transport;
+pub mod trust;
 pub mod update;
 
 /// Version constant for the backend
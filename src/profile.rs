@@ -0,0 +1,514 @@
+//! Connection profiles for switching between multiple servers
+//!
+//! Profiles are stored as JSON within a directory chosen by the client.
+//! Individual profiles can be exported to a shareable profile file, the
+//! server of an imported profile is validated using a server lookup
+
+use crate::{
+    api::{create_http_client, ApiError, HttpClient, HttpClientConfig, LookupData, PocketArkApi},
+    servers::tunnel_manager::TunnelTransport,
+    trust::{fetch_server_certificate, CertificatePin, PinError, RootCertificateError},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use url::Url;
+
+/// Name of the file the profiles are stored in
+pub const PROFILES_FILE_NAME: &str = "profiles.json";
+/// Version of the shareable profile file format
+pub const PROFILE_FILE_VERSION: u32 = 1;
+
+/// Errors that can occur while working with profiles
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    /// Failed to read or write a profile file
+    #[error("Failed to access profile file: {0}")]
+    Io(#[from] std::io::Error),
+    /// Profile file contents were invalid
+    #[error("Invalid profile file: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Profile file was created by a newer version of the client
+    #[error("Unsupported profile file version: {0}")]
+    UnsupportedVersion(u32),
+    /// Profile with the same name already exists
+    #[error("Profile with the name \"{0}\" already exists")]
+    DuplicateName(String),
+    /// Profile with the name doesn't exist
+    #[error("Profile \"{0}\" not found")]
+    NotFound(String),
+    /// Server of an imported profile failed validation
+    #[error(transparent)]
+    Lookup(#[from] ApiError),
+    /// Server of an imported profile didn't match the profile certificate pin
+    #[error(transparent)]
+    Pin(#[from] PinError),
+    /// Failed to create the HTTP client trusting the pinned certificate
+    #[error(transparent)]
+    Certificate(#[from] RootCertificateError),
+}
+
+/// Profile describing how to connect to a server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionProfile {
+    /// Display name of the profile, unique within a [`ProfileStore`]
+    pub name: String,
+    /// The server connection URL
+    pub url: String,
+    /// Path to the client identity to use for the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_path: Option<PathBuf>,
+    /// Preferred tunnel transport for the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_transport: Option<TunnelTransport>,
+    /// Email of the account last used with the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Pinned certificate of the server, see [`crate::trust::trust_on_first_use`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate_pin: Option<CertificatePin>,
+}
+
+/// Shareable file containing a single profile
+#[derive(Serialize, Deserialize)]
+struct ProfileFile {
+    /// Version of the file format
+    version: u32,
+    /// The profile
+    profile: ConnectionProfile,
+}
+
+/// Store for the connection profiles
+#[derive(Debug)]
+pub struct ProfileStore {
+    /// Path to the profiles file
+    path: PathBuf,
+    /// The stored profiles
+    profiles: Vec<ConnectionProfile>,
+}
+
+impl ProfileStore {
+    /// Opens the profile store within the provided directory, the
+    /// store is empty if the profiles file doesn't exist yet
+    ///
+    /// ## Arguments
+    /// * `dir` - The directory containing the profiles file
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, ProfileError> {
+        let path = dir.as_ref().join(PROFILES_FILE_NAME);
+
+        let profiles = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self { path, profiles })
+    }
+
+    /// The stored profiles
+    pub fn profiles(&self) -> &[ConnectionProfile] {
+        &self.profiles
+    }
+
+    /// Finds a profile by name
+    ///
+    /// ## Arguments
+    /// * `name` - The profile name
+    pub fn get(&self, name: &str) -> Option<&ConnectionProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Adds a new profile and writes the store to disk
+    ///
+    /// ## Arguments
+    /// * `profile` - The profile to add
+    pub fn add(&mut self, profile: ConnectionProfile) -> Result<(), ProfileError> {
+        if self.get(&profile.name).is_some() {
+            return Err(ProfileError::DuplicateName(profile.name));
+        }
+
+        self.profiles.push(profile);
+        self.write()
+    }
+
+    /// Replaces an existing profile and writes the store to disk, the
+    /// replacement may use a different name
+    ///
+    /// ## Arguments
+    /// * `name`    - The name of the profile to replace
+    /// * `profile` - The replacement profile
+    pub fn update(&mut self, name: &str, profile: ConnectionProfile) -> Result<(), ProfileError> {
+        if profile.name != name && self.get(&profile.name).is_some() {
+            return Err(ProfileError::DuplicateName(profile.name));
+        }
+
+        let existing = self
+            .profiles
+            .iter_mut()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| ProfileError::NotFound(name.to_string()))?;
+
+        *existing = profile;
+        self.write()
+    }
+
+    /// Removes a profile and writes the store to disk
+    ///
+    /// ## Arguments
+    /// * `name` - The name of the profile to remove
+    pub fn remove(&mut self, name: &str) -> Result<ConnectionProfile, ProfileError> {
+        let index = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or_else(|| ProfileError::NotFound(name.to_string()))?;
+
+        let profile = self.profiles.remove(index);
+        self.write()?;
+        Ok(profile)
+    }
+
+    /// Writes the store to disk, the contents are written to a temporary
+    /// file which replaces the profiles file once complete
+    fn write(&self) -> Result<(), ProfileError> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let bytes = serde_json::to_vec_pretty(&self.profiles)?;
+        let temp_path = self.path.with_extension("json.tmp");
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+
+        std::fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Exports a profile to a shareable profile file. The identity path and
+/// email are specific to the local user so they are not exported
+///
+/// ## Arguments
+/// * `profile` - The profile to export
+/// * `path`    - The path to write the profile file to
+pub fn export_profile(
+    profile: &ConnectionProfile,
+    path: impl AsRef<Path>,
+) -> Result<(), ProfileError> {
+    let file = ProfileFile {
+        version: PROFILE_FILE_VERSION,
+        profile: ConnectionProfile {
+            identity_path: None,
+            email: None,
+            ..profile.clone()
+        },
+    };
+
+    let bytes = serde_json::to_vec_pretty(&file)?;
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+/// Imports a profile from a shareable profile file, the profile server is
+/// looked up to ensure its a valid server and the profile URL is replaced
+/// with the URL resolved by the lookup
+///
+/// When the profile has a certificate pin the server certificate is checked
+/// against the pin. Self-signed certificates are then trusted using a client
+/// trusting only the pinned certificate, connecting through the proxy of
+/// `http_client`, certificates issued by a CA are verified by `http_client`
+///
+/// ## Arguments
+/// * `http_client` - The HTTP client to validate the server with
+/// * `path`        - The path of the profile file
+pub async fn import_profile(
+    http_client: HttpClient,
+    path: impl AsRef<Path>,
+) -> Result<(ConnectionProfile, LookupData), ProfileError> {
+    let bytes = std::fs::read(path)?;
+    let file: ProfileFile = serde_json::from_slice(&bytes)?;
+
+    if file.version > PROFILE_FILE_VERSION {
+        return Err(ProfileError::UnsupportedVersion(file.version));
+    }
+
+    let mut profile = file.profile;
+
+    let http_client = match &profile.certificate_pin {
+        Some(pin) => pinned_client(&http_client, &profile.url, pin).await?,
+        None => http_client,
+    };
+
+    let (_, lookup) = PocketArkApi::lookup(http_client, &profile.url).await?;
+    profile.url = lookup.url.to_string();
+
+    Ok((profile, lookup))
+}
+
+/// Checks the certificate of the server at the URL against the pin and
+/// creates a client trusting only the pinned certificate when its
+/// self-signed, otherwise the provided client is used
+///
+/// ## Arguments
+/// * `http_client` - The HTTP client providing the proxy to connect through
+///   and verifying certificates issued by a CA
+/// * `url`         - The server URL
+/// * `pin`         - The pin to check the server certificate against
+async fn pinned_client(
+    http_client: &HttpClient,
+    url: &str,
+    pin: &CertificatePin,
+) -> Result<HttpClient, ProfileError> {
+    let url = Url::parse(url).map_err(ApiError::from)?;
+    let proxy = http_client.proxy();
+
+    let certificate = fetch_server_certificate(&url, proxy).await?;
+    certificate.verify(pin)?;
+
+    // Certificates issued by a CA can't be trusted on their own without the
+    // rest of the chain, these are verified using the normal roots instead
+    if !certificate.is_self_signed() {
+        return Ok(http_client.clone());
+    }
+
+    let http_client = create_http_client(HttpClientConfig {
+        proxy: proxy.cloned(),
+        pinned_certificate: Some(certificate.to_reqwest()?),
+        ..Default::default()
+    })
+    .map_err(RootCertificateError::Create)?;
+
+    Ok(http_client)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        export_profile, import_profile, ConnectionProfile, ProfileError, ProfileStore,
+        PROFILE_FILE_VERSION,
+    };
+    use crate::{
+        api::{create_http_client, HttpClientConfig, SERVER_IDENT},
+        servers::tunnel_manager::TunnelTransport,
+        session::test::test_dir,
+        trust::{
+            test::{certificate, generate_key, pin, self_signed, start_server},
+            CertificatePin, PinError, PinKind,
+        },
+    };
+    use openssl::asn1::Asn1Time;
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    /// Creates a profile with the provided name
+    fn profile(name: &str) -> ConnectionProfile {
+        ConnectionProfile {
+            name: name.to_string(),
+            url: "https://example.com/".to_string(),
+            identity_path: Some(PathBuf::from("/home/user/identity.p12")),
+            tunnel_transport: Some(TunnelTransport::Udp),
+            email: Some("user@example.com".to_string()),
+            certificate_pin: None,
+        }
+    }
+
+    /// Tests that profiles with an existing name are rejected when
+    /// adding or renaming a profile
+    #[test]
+    fn test_duplicate_name() {
+        let dir = test_dir("profile-duplicate");
+        let mut store = ProfileStore::open(&dir).unwrap();
+
+        store.add(profile("Home")).unwrap();
+        store.add(profile("Work")).unwrap();
+
+        assert!(matches!(
+            store.add(profile("Home")),
+            Err(ProfileError::DuplicateName(name)) if name == "Home"
+        ));
+        assert!(matches!(
+            store.update("Work", profile("Home")),
+            Err(ProfileError::DuplicateName(name)) if name == "Home"
+        ));
+        assert_eq!(store.profiles().len(), 2);
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Tests renaming a profile through an update, the rename is persisted
+    #[test]
+    fn test_rename() {
+        let dir = test_dir("profile-rename");
+        let mut store = ProfileStore::open(&dir).unwrap();
+        store.add(profile("Home")).unwrap();
+
+        // Updating without renaming isn't a duplicate of itself
+        let mut updated = profile("Home");
+        updated.url = "https://other.example.com/".to_string();
+        store.update("Home", updated).unwrap();
+
+        store.update("Home", profile("Renamed")).unwrap();
+
+        let store = ProfileStore::open(&dir).unwrap();
+        assert!(store.get("Home").is_none());
+        assert!(store.get("Renamed").is_some());
+        assert_eq!(store.profiles().len(), 1);
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Tests that updating or removing a missing profile fails
+    #[test]
+    fn test_not_found() {
+        let dir = test_dir("profile-not-found");
+        let mut store = ProfileStore::open(&dir).unwrap();
+
+        assert!(matches!(
+            store.update("Missing", profile("Missing")),
+            Err(ProfileError::NotFound(name)) if name == "Missing"
+        ));
+        assert!(matches!(
+            store.remove("Missing"),
+            Err(ProfileError::NotFound(name)) if name == "Missing"
+        ));
+
+        store.add(profile("Home")).unwrap();
+        assert_eq!(store.remove("Home").unwrap().name, "Home");
+        assert!(store.profiles().is_empty());
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Tests that exported profiles don't include the identity path or email
+    #[test]
+    fn test_export_strips_local_fields() {
+        let dir = test_dir("profile-export");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profile.json");
+
+        export_profile(&profile("Home"), &path).unwrap();
+
+        let file: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file["version"], json!(PROFILE_FILE_VERSION));
+
+        let exported = &file["profile"];
+        assert_eq!(exported["name"], json!("Home"));
+        assert_eq!(exported["url"], json!("https://example.com/"));
+        assert_eq!(exported["tunnel_transport"], json!("Udp"));
+        assert!(exported.get("identity_path").is_none());
+        assert!(exported.get("email").is_none());
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Tests that profile files from newer versions aren't imported
+    #[tokio::test]
+    async fn test_import_unsupported_version() {
+        let dir = test_dir("profile-import");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profile.json");
+
+        let file = json!({
+            "version": PROFILE_FILE_VERSION + 1,
+            "profile": { "name": "Home", "url": "https://example.com/" },
+        });
+        std::fs::write(&path, file.to_string()).unwrap();
+
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let result = import_profile(http_client, &path).await;
+        assert!(matches!(
+            result,
+            Err(ProfileError::UnsupportedVersion(version)) if version == PROFILE_FILE_VERSION + 1
+        ));
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Tests importing a profile for a self-signed server, the server is
+    /// only trusted when its certificate matches the profile pin
+    #[tokio::test]
+    async fn test_import_pinned() {
+        let (cert, key) = self_signed();
+        let (other, _) = self_signed();
+
+        let details = json!({ "version": "0.1.0", "ident": SERVER_IDENT });
+        let url = start_server(&cert, &key, &details.to_string()).await;
+
+        let dir = test_dir("profile-import-pinned");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profile.json");
+
+        let import = |pin: CertificatePin| {
+            let path = path.clone();
+            let url = url.clone();
+            async move {
+                let file = json!({
+                    "version": PROFILE_FILE_VERSION,
+                    "profile": { "name": "Home", "url": url.to_string(), "certificate_pin": pin },
+                });
+                std::fs::write(&path, file.to_string()).unwrap();
+
+                let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+                import_profile(http_client, &path).await
+            }
+        };
+
+        let expected = pin(cert, PinKind::Spki);
+        let (profile, lookup) = import(expected.clone()).await.unwrap();
+        assert_eq!(lookup.url, url);
+        assert_eq!(profile.certificate_pin, Some(expected));
+
+        let result = import(pin(other, PinKind::Spki)).await;
+        assert!(matches!(
+            result,
+            Err(ProfileError::Pin(PinError::Mismatch { .. }))
+        ));
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Tests importing a profile pinning a certificate issued by a CA, the
+    /// server certificate is verified against the roots of the client
+    #[tokio::test]
+    async fn test_import_pinned_ca_signed() {
+        let (ca, ca_key) = self_signed();
+        let key = generate_key();
+        let cert = certificate(
+            &key,
+            Some((&ca, &ca_key)),
+            &Asn1Time::days_from_now(1).unwrap(),
+        );
+
+        let details = json!({ "version": "0.1.0", "ident": SERVER_IDENT });
+        let url = start_server(&cert, &key, &details.to_string()).await;
+
+        let dir = test_dir("profile-import-pinned-ca");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profile.json");
+
+        let expected = pin(cert, PinKind::Spki);
+        let file = json!({
+            "version": PROFILE_FILE_VERSION,
+            "profile": { "name": "Home", "url": url.to_string(), "certificate_pin": expected },
+        });
+        std::fs::write(&path, file.to_string()).unwrap();
+
+        let http_client = create_http_client(HttpClientConfig {
+            root_certificates: vec![reqwest::Certificate::from_der(&ca.to_der().unwrap()).unwrap()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (profile, lookup) = import_profile(http_client, &path).await.unwrap();
+        assert_eq!(lookup.url, url);
+        assert_eq!(profile.certificate_pin, Some(expected));
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,496 @@
+//! Outbound proxy configuration for the connections to the server
+//!
+//! Requests made using the HTTP client are sent through the proxy by
+//! the client itself. Upgraded connections to HTTP servers through an
+//! HTTP proxy are instead established using a CONNECT tunnel, since
+//! forwarding proxies commonly strip the upgrade headers. Connections made
+//! outside of the HTTP client such as certificate probes are established
+//! through a CONNECT tunnel or a SOCKS5 connection depending on the proxy
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Formatter},
+    io::ErrorKind,
+    net::IpAddr,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use url::Url;
+
+/// Maximum length of the response to a CONNECT request
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// SOCKS protocol version
+const SOCKS_VERSION: u8 = 0x05;
+/// Version of the SOCKS username and password authentication
+const SOCKS_AUTH_VERSION: u8 = 0x01;
+/// SOCKS method for no authentication
+const SOCKS_METHOD_NONE: u8 = 0x00;
+/// SOCKS method for username and password authentication
+const SOCKS_METHOD_PASSWORD: u8 = 0x02;
+/// SOCKS reply when none of the offered methods are acceptable
+const SOCKS_METHOD_UNACCEPTABLE: u8 = 0xFF;
+/// SOCKS CONNECT command
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+/// SOCKS address type for IPv4 addresses
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+/// SOCKS address type for domain names
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+/// SOCKS address type for IPv6 addresses
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+
+/// Type of proxy server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProxyKind {
+    /// HTTP proxy using CONNECT for tunnels
+    Http,
+    /// SOCKS5 proxy, host names are resolved by the proxy
+    Socks5,
+}
+
+/// Credentials for authenticating with the proxy
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProxyAuth {
+    /// The proxy username
+    pub username: String,
+    /// The proxy password
+    pub password: String,
+}
+
+impl Debug for ProxyAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configuration for the proxy to connect through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// The type of proxy
+    pub kind: ProxyKind,
+    /// The proxy host
+    pub host: String,
+    /// The proxy port
+    pub port: u16,
+    /// Optional credentials for the proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<ProxyAuth>,
+}
+
+impl ProxyConfig {
+    /// Creates the proxy URL including the credentials
+    pub fn url(&self) -> Result<Url, url::ParseError> {
+        let scheme = match self.kind {
+            ProxyKind::Http => "http",
+            ProxyKind::Socks5 => "socks5h",
+        };
+
+        let mut url = Url::parse(&format!("{}://{}:{}", scheme, self.host, self.port))?;
+
+        if let Some(auth) = &self.auth {
+            // Setting credentials only fails for URLs without a host
+            _ = url.set_username(&auth.username);
+            _ = url.set_password(Some(&auth.password));
+        }
+
+        Ok(url)
+    }
+
+    /// Creates a proxy for the HTTP client that is used for all requests
+    pub fn to_reqwest(&self) -> Result<reqwest::Proxy, reqwest::Error> {
+        // Invalid hosts are reported by reqwest when given as a string
+        match self.url() {
+            Ok(url) => reqwest::Proxy::all(url),
+            Err(_) => reqwest::Proxy::all(format!("{}:{}", self.host, self.port)),
+        }
+    }
+
+    /// Whether upgraded connections to the provided server URL must be
+    /// established through a CONNECT tunnel instead of the HTTP client
+    ///
+    /// ## Arguments
+    /// * `url` - The server URL
+    pub(crate) fn requires_tunnel(&self, url: &Url) -> bool {
+        self.kind == ProxyKind::Http && url.scheme() == "http"
+    }
+
+    /// Connects to the target through the proxy using a CONNECT tunnel for
+    /// HTTP proxies or a SOCKS5 connection for SOCKS5 proxies
+    ///
+    /// ## Arguments
+    /// * `host` - The target host
+    /// * `port` - The target port
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        match self.kind {
+            ProxyKind::Http => self.connect_tunnel(host, port).await,
+            ProxyKind::Socks5 => self.connect_socks5(host, port).await,
+        }
+    }
+
+    /// Connects to the proxy and opens a CONNECT tunnel to the target
+    ///
+    /// ## Arguments
+    /// * `host` - The target host
+    /// * `port` - The target port
+    pub(crate) async fn connect_tunnel(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        let mut request = format!(
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+            host = host,
+            port = port
+        );
+
+        if let Some(auth) = &self.auth {
+            let credentials = STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the response headers a byte at a time to avoid reading
+        // any of the tunnelled data
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_CONNECT_RESPONSE {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Proxy CONNECT response too large",
+                ));
+            }
+
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok());
+
+        match status {
+            Some(200..=299) => Ok(stream),
+            Some(407) => Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Proxy authentication required",
+            )),
+            _ => Err(std::io::Error::other(format!(
+                "Proxy rejected CONNECT: {}",
+                status_line
+            ))),
+        }
+    }
+
+    /// Connects to the proxy and opens a SOCKS5 connection to the target,
+    /// host names are sent to the proxy to be resolved
+    ///
+    /// ## Arguments
+    /// * `host` - The target host
+    /// * `port` - The target port
+    pub(crate) async fn connect_socks5(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        // Offer password authentication only when credentials are available
+        let method = if self.auth.is_some() {
+            SOCKS_METHOD_PASSWORD
+        } else {
+            SOCKS_METHOD_NONE
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+
+        if reply[0] != SOCKS_VERSION {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Proxy is not a SOCKS5 proxy",
+            ));
+        }
+
+        match (reply[1], &self.auth) {
+            (SOCKS_METHOD_NONE, _) => {}
+            (SOCKS_METHOD_PASSWORD, Some(auth)) => {
+                let username = auth.username.as_bytes();
+                let password = auth.password.as_bytes();
+                let (Ok(username_len), Ok(password_len)) =
+                    (u8::try_from(username.len()), u8::try_from(password.len()))
+                else {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "SOCKS5 credentials too long",
+                    ));
+                };
+
+                let mut request = vec![SOCKS_AUTH_VERSION, username_len];
+                request.extend_from_slice(username);
+                request.push(password_len);
+                request.extend_from_slice(password);
+                stream.write_all(&request).await?;
+
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(std::io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Proxy authentication failed",
+                    ));
+                }
+            }
+            (SOCKS_METHOD_UNACCEPTABLE, _) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Proxy authentication required",
+                ))
+            }
+            (method, _) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Proxy selected unsupported SOCKS5 method {}", method),
+                ))
+            }
+        }
+
+        // IPv6 hosts from URLs are enclosed in brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let mut request = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => {
+                request.push(SOCKS_ATYP_IPV4);
+                request.extend_from_slice(&addr.octets());
+            }
+            Ok(IpAddr::V6(addr)) => {
+                request.push(SOCKS_ATYP_IPV6);
+                request.extend_from_slice(&addr.octets());
+            }
+            Err(_) => {
+                let length = u8::try_from(host.len()).map_err(|_| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "SOCKS5 host name too long")
+                })?;
+                request.push(SOCKS_ATYP_DOMAIN);
+                request.push(length);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // Reply header is followed by the bound address and port
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+
+        if reply[1] != 0 {
+            return Err(std::io::Error::other(format!(
+                "Proxy rejected SOCKS5 connect: reply {}",
+                reply[1]
+            )));
+        }
+
+        let address_len = match reply[3] {
+            SOCKS_ATYP_IPV4 => 4,
+            SOCKS_ATYP_IPV6 => 16,
+            SOCKS_ATYP_DOMAIN => stream.read_u8().await? as usize,
+            _ => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid SOCKS5 bound address",
+                ))
+            }
+        };
+
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{ProxyAuth, ProxyConfig, ProxyKind};
+    use std::{io::ErrorKind, net::Ipv4Addr};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    /// Reads the head of an HTTP request up to the blank line, shared
+    /// with the tests of the upgrades through a proxy
+    pub(crate) async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Starts a proxy that answers a single CONNECT request with the
+    /// provided response, the task provides the CONNECT request
+    async fn start_proxy(response: &'static [u8]) -> (ProxyConfig, JoinHandle<String>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_head(&mut stream).await;
+            stream.write_all(response).await.unwrap();
+            stream.flush().await.unwrap();
+            // Keep the connection open until the client is done
+            _ = stream.read_u8().await;
+            request
+        });
+
+        let config = ProxyConfig {
+            kind: ProxyKind::Http,
+            host: Ipv4Addr::LOCALHOST.to_string(),
+            port,
+            auth: Some(ProxyAuth {
+                username: "user".to_string(),
+                password: "pass".to_string(),
+            }),
+        };
+
+        (config, task)
+    }
+
+    /// Tests that an established tunnel is returned without consuming
+    /// any of the tunnelled data that follows the response
+    #[tokio::test]
+    async fn test_connect_tunnel_established() {
+        let (config, task) =
+            start_proxy(b"HTTP/1.1 200 Connection established\r\n\r\ntunnelled").await;
+
+        let mut stream = config.connect_tunnel("example.com", 80).await.unwrap();
+
+        let mut data = [0u8; 9];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"tunnelled");
+        drop(stream);
+
+        let request = task.await.unwrap();
+        assert!(request.starts_with("CONNECT example.com:80 HTTP/1.1\r\n"));
+        assert!(request.contains("Host: example.com:80\r\n"));
+        // Base64 of "user:pass"
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    /// Tests that the proxy requiring authentication is reported
+    #[tokio::test]
+    async fn test_connect_tunnel_auth_required() {
+        let (config, _task) =
+            start_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+
+        let err = config.connect_tunnel("example.com", 80).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    /// Tests that other proxy rejections are reported with the status line
+    #[tokio::test]
+    async fn test_connect_tunnel_rejected() {
+        let (config, _task) = start_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n").await;
+
+        let err = config.connect_tunnel("example.com", 80).await.unwrap_err();
+        assert!(err.to_string().contains("403 Forbidden"));
+    }
+
+    /// Starts a SOCKS5 proxy that accepts a single connection, authentication
+    /// succeeds when the provided credentials are used. The task provides the
+    /// connect request
+    async fn start_socks_proxy(
+        username: &'static str,
+        password: &'static str,
+    ) -> (u16, JoinHandle<Option<Vec<u8>>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 1, 0x02]);
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+            assert_eq!(stream.read_u8().await.unwrap(), 0x01);
+            let mut credentials = Vec::new();
+            for _ in 0..2 {
+                let length = stream.read_u8().await.unwrap();
+                let mut value = vec![0u8; length as usize];
+                stream.read_exact(&mut value).await.unwrap();
+                credentials.push(String::from_utf8(value).unwrap());
+            }
+
+            if credentials != [username, password] {
+                stream.write_all(&[0x01, 0x01]).await.unwrap();
+                return None;
+            }
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+            // Connect request for the domain "example.com" on port 80
+            let mut request = vec![0u8; 7 + "example.com".len()];
+            stream.read_exact(&mut request).await.unwrap();
+
+            // Bound address followed by the tunnelled data
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1F, 0x90])
+                .await
+                .unwrap();
+            stream.write_all(b"tunnelled").await.unwrap();
+            _ = stream.read_u8().await;
+
+            Some(request)
+        });
+
+        (port, task)
+    }
+
+    /// Creates the config for a SOCKS5 proxy using the test credentials
+    fn socks_config(port: u16) -> ProxyConfig {
+        ProxyConfig {
+            kind: ProxyKind::Socks5,
+            host: Ipv4Addr::LOCALHOST.to_string(),
+            port,
+            auth: Some(ProxyAuth {
+                username: "user".to_string(),
+                password: "pass".to_string(),
+            }),
+        }
+    }
+
+    /// Tests connecting through a SOCKS5 proxy with password authentication
+    #[tokio::test]
+    async fn test_connect_socks5() {
+        let (port, task) = start_socks_proxy("user", "pass").await;
+        let config = socks_config(port);
+
+        let mut stream = config.connect("example.com", 80).await.unwrap();
+
+        let mut data = [0u8; 9];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"tunnelled");
+        drop(stream);
+
+        let request = task.await.unwrap().unwrap();
+        assert_eq!(&request[..5], &[0x05, 0x01, 0x00, 0x03, 11]);
+        assert_eq!(&request[5..16], b"example.com");
+        assert_eq!(&request[16..], &80u16.to_be_bytes());
+    }
+
+    /// Tests that the SOCKS5 proxy rejecting the credentials is reported
+    #[tokio::test]
+    async fn test_connect_socks5_auth_failed() {
+        let (port, _task) = start_socks_proxy("other", "pass").await;
+        let config = socks_config(port);
+
+        let err = config.connect("example.com", 80).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+}
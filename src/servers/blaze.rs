@@ -1,27 +1,58 @@
 //! Server connected to by BlazeSDK clients (Majority of the game traffic)
 
-use super::{spawn_server_task, BLAZE_PORT};
-use crate::{api::create_server_stream, ctx::ClientContext};
+use super::{
+    blaze_frame::{is_frame_inspection_enabled, FrameDecoder, FrameDirection, FrameHeader},
+    supervisor::{ServerKind, ServerTasks},
+    ServerConfig,
+};
+use crate::{
+    capture::TcpCaptureFlow,
+    ctx::ClientContext,
+    events::{emit, emit_bind_result, ServerEvent},
+    stats,
+};
 use log::{debug, error};
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
-    io::copy_bidirectional,
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    select, try_join,
 };
+use tokio_util::sync::CancellationToken;
+
+/// Size of the buffer used when copying between the streams
+const COPY_BUFFER_SIZE: usize = 8 * 1024;
 
 /// Starts the blaze server
 ///
 /// ## Arguments
-/// * `ctx` - The client context
-pub async fn start_blaze_server(ctx: Arc<ClientContext>) -> std::io::Result<()> {
+/// * `ctx`    - The client context
+/// * `config` - The server configuration
+/// * `tasks`  - Collection to spawn connection tasks into
+pub async fn start_blaze_server(
+    ctx: Arc<ClientContext>,
+    config: Arc<ServerConfig>,
+    tasks: ServerTasks,
+) -> std::io::Result<()> {
+    let addr = config.blaze;
+
     // Bind the local socket for accepting connections
-    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, BLAZE_PORT)).await?;
+    let listener = emit_bind_result(ServerKind::Blaze, addr, TcpListener::bind(addr).await)?;
 
     // Accept connections
     loop {
-        let (client_stream, _) = listener.accept().await?;
+        let (client_stream, peer) = select! {
+            result = listener.accept() => result?,
+            // Stop accepting connections when shutting down
+            _ = tasks.shutdown_requested() => return Ok(()),
+        };
 
-        spawn_server_task(handle(client_stream, ctx.clone()));
+        tasks.spawn(handle(
+            client_stream,
+            peer,
+            ctx.clone(),
+            tasks.shutdown_token(),
+        ));
     }
 }
 
@@ -29,28 +60,329 @@ pub async fn start_blaze_server(ctx: Arc<ClientContext>) -> std::io::Result<()>
 ///
 /// ## Arguments
 /// * `client_stream` - The client stream to read and write from
+/// * `peer`          - The address of the client
 /// * `ctx`           - The client context
-async fn handle(mut client_stream: TcpStream, ctx: Arc<ClientContext>) {
+/// * `shutdown`      - Token cancelled when the server is shutting down
+async fn handle(
+    client_stream: TcpStream,
+    peer: SocketAddr,
+    ctx: Arc<ClientContext>,
+    shutdown: CancellationToken,
+) {
     debug!("Starting blaze connection");
 
+    emit(ServerEvent::BlazeConnectionOpened { peer });
+
+    let stats = stats::blaze_connection(peer);
+
     // Create a stream to the Pocket Relay server
-    let mut server_stream = match create_server_stream(
-        ctx.http_client.clone(),
-        &ctx.base_url,
-        Option::as_ref(&ctx.association),
-        ctx.token.clone(),
-    )
-    .await
-    {
+    let server_stream = match ctx.api.create_server_stream().await {
         Ok(stream) => stream,
         Err(err) => {
             error!("Failed to create server stream: {}", err);
+            let error = err.to_string();
+            emit(ServerEvent::BlazeConnectionClosed {
+                peer,
+                error: Some(error.clone()),
+            });
+            stats.finish(Some(error));
             return;
         }
     };
 
     debug!("Blaze connection linked");
 
+    // Flows for capturing the stream in each direction
+    let local_addr = client_stream.local_addr().unwrap_or(peer);
+    let mut to_server_flow = TcpCaptureFlow::new(peer, local_addr);
+    let mut from_server_flow = TcpCaptureFlow::new(local_addr, peer);
+
+    let (client_read, client_write) = split(client_stream);
+    let (server_read, server_write) = split(server_stream);
+
     // Copy the data between the streams
-    let _ = copy_bidirectional(&mut client_stream, &mut server_stream).await;
+    let counters = stats.counters();
+
+    // Whether to inspect the frames in each direction
+    let inspect = is_frame_inspection_enabled();
+
+    let result = try_join!(
+        copy_until_shutdown(
+            client_read,
+            server_write,
+            &shutdown,
+            |chunk| {
+                counters.record_to_server(chunk.len());
+                to_server_flow.capture(chunk);
+            },
+            |header| {
+                if inspect {
+                    inspect_frame(header, peer, FrameDirection::ToServer);
+                }
+            }
+        ),
+        copy_until_shutdown(
+            server_read,
+            client_write,
+            &shutdown,
+            |chunk| {
+                counters.record_from_server(chunk.len());
+                from_server_flow.capture(chunk);
+            },
+            |header| {
+                if inspect {
+                    inspect_frame(header, peer, FrameDirection::FromServer);
+                }
+            }
+        ),
+    );
+
+    let error = result.err().map(|err| err.to_string());
+
+    emit(ServerEvent::BlazeConnectionClosed {
+        peer,
+        error: error.clone(),
+    });
+    stats.finish(error);
+}
+
+/// Logs and emits an event for a decoded frame
+///
+/// ## Arguments
+/// * `header`    - The decoded frame header
+/// * `peer`      - Address of the game client
+/// * `direction` - The stream direction
+fn inspect_frame(header: FrameHeader, peer: SocketAddr, direction: FrameDirection) {
+    debug!("[{}] {:?} {}", peer, direction, header);
+    emit(ServerEvent::BlazeFrame {
+        peer,
+        direction,
+        header,
+    });
+}
+
+/// Copies bytes from `reader` to `writer` until the reader reaches EOF or
+/// a shutdown is requested. Once a shutdown is requested the copy continues
+/// until the end of the current frame so that a frame is never cut in half,
+/// the writer is then shutdown so the other end receives a clean FIN.
+///
+/// Each chunk is flushed once written as writers such as the WebSocket
+/// transport buffer their writes until flushed
+///
+/// ## Arguments
+/// * `reader`   - The reader to copy from
+/// * `writer`   - The writer to copy to
+/// * `shutdown` - Token cancelled when the server is shutting down
+/// * `on_chunk` - Called with each chunk once its written
+/// * `on_frame` - Called with the header of each frame in the stream
+async fn copy_until_shutdown<R, W, C, F>(
+    mut reader: R,
+    mut writer: W,
+    shutdown: &CancellationToken,
+    mut on_chunk: C,
+    mut on_frame: F,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    C: FnMut(&[u8]),
+    F: FnMut(FrameHeader),
+{
+    let mut buffer = [0u8; COPY_BUFFER_SIZE];
+    let mut decoder = FrameDecoder::default();
+    let mut total: u64 = 0;
+
+    loop {
+        let count = if shutdown.is_cancelled() {
+            let remaining = decoder.remaining();
+
+            // Stop at the frame boundary
+            if remaining == 0 {
+                break;
+            }
+
+            // Only read the rest of the current frame
+            let limit = remaining.min(COPY_BUFFER_SIZE as u64) as usize;
+            reader.read(&mut buffer[..limit]).await?
+        } else {
+            select! {
+                result = reader.read(&mut buffer) => result?,
+                // Continue to drain the current frame
+                _ = shutdown.cancelled() => continue,
+            }
+        };
+
+        // Reader has reached EOF
+        if count == 0 {
+            break;
+        }
+
+        let chunk = &buffer[..count];
+        writer.write_all(chunk).await?;
+        writer.flush().await?;
+        total += count as u64;
+        on_chunk(chunk);
+        decoder.push(chunk, &mut on_frame);
+    }
+
+    writer.shutdown().await?;
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::copy_until_shutdown;
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{duplex, AsyncWrite, AsyncWriteExt},
+        sync::mpsc,
+        time::timeout,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    /// Maximum time to wait for something that should happen promptly
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Writer that buffers its writes and only sends them when flushed
+    struct FlushWriter {
+        /// Bytes written since the last flush
+        buffer: Vec<u8>,
+        /// Sender for the flushed bytes
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        /// Whether the writer has been shutdown
+        shutdown: Arc<AtomicBool>,
+    }
+
+    impl FlushWriter {
+        /// Creates a writer returning the receiver for the flushed bytes
+        /// and the shutdown state
+        fn new() -> (Self, mpsc::UnboundedReceiver<Vec<u8>>, Arc<AtomicBool>) {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let writer = Self {
+                buffer: Vec::new(),
+                tx,
+                shutdown: shutdown.clone(),
+            };
+            (writer, rx, shutdown)
+        }
+    }
+
+    impl AsyncWrite for FlushWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.get_mut().buffer.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            if !this.buffer.is_empty() {
+                _ = this.tx.send(std::mem::take(&mut this.buffer));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            self.shutdown.store(true, Ordering::SeqCst);
+            self.as_mut().poll_flush(cx)
+        }
+    }
+
+    /// Encodes a frame header for a frame with the provided payload length
+    fn frame_header(length: u32) -> Vec<u8> {
+        let mut header = vec![0u8; 16];
+        header[..4].copy_from_slice(&length.to_be_bytes());
+        header
+    }
+
+    /// Tests that each chunk is flushed to the writer without waiting for
+    /// more data or the end of the stream
+    #[tokio::test]
+    async fn test_copy_flushes_chunks() {
+        let (mut client, reader) = duplex(1024);
+        let (writer, mut rx, _) = FlushWriter::new();
+
+        let shutdown = CancellationToken::new();
+        let copy = tokio::spawn(async move {
+            copy_until_shutdown(reader, writer, &shutdown, |_| {}, |_| {}).await
+        });
+
+        // Chunks must arrive while the stream is still open
+        client.write_all(b"first").await.unwrap();
+        assert_eq!(timeout(WAIT, rx.recv()).await.unwrap().unwrap(), b"first");
+
+        client.write_all(b"second").await.unwrap();
+        assert_eq!(timeout(WAIT, rx.recv()).await.unwrap().unwrap(), b"second");
+
+        drop(client);
+        let total = timeout(WAIT, copy).await.unwrap().unwrap().unwrap();
+        assert_eq!(total, 11);
+    }
+
+    /// Tests that a shutdown during a frame continues copying until the
+    /// end of that frame, never copies any of the next frame and shuts
+    /// down the writer
+    #[tokio::test]
+    async fn test_copy_drains_frame_on_shutdown() {
+        let (mut client, reader) = duplex(1024);
+        let (writer, mut rx, writer_shutdown) = FlushWriter::new();
+
+        let mut frame = frame_header(8);
+        frame.extend_from_slice(&[0xAA; 8]);
+
+        let shutdown = CancellationToken::new();
+        let copy = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                let mut frames = 0;
+                let total =
+                    copy_until_shutdown(reader, writer, &shutdown, |_| {}, |_| frames += 1).await;
+                (total, frames)
+            }
+        });
+
+        // Header and part of the payload are copied before the shutdown
+        client.write_all(&frame[..20]).await.unwrap();
+        assert_eq!(
+            timeout(WAIT, rx.recv()).await.unwrap().unwrap(),
+            &frame[..20]
+        );
+
+        shutdown.cancel();
+
+        // Copy continues waiting for the rest of the frame
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!copy.is_finished());
+        assert!(!writer_shutdown.load(Ordering::SeqCst));
+
+        // Rest of the frame is sent along with the start of the next frame
+        let mut rest = frame[20..].to_vec();
+        rest.extend_from_slice(&frame_header(4));
+        client.write_all(&rest).await.unwrap();
+
+        let (total, frames) = timeout(WAIT, copy).await.unwrap().unwrap();
+        assert_eq!(total.unwrap(), frame.len() as u64);
+        assert_eq!(frames, 1);
+        assert!(writer_shutdown.load(Ordering::SeqCst));
+
+        // Only the rest of the frame was forwarded
+        assert_eq!(rx.recv().await.unwrap(), &frame[20..]);
+        assert!(rx.recv().await.is_none());
+    }
 }
@@ -0,0 +1,658 @@
+//! Decoder for the Blaze packet frames passing through the Blaze server,
+//! the frames are only inspected for diagnostics and the bytes are never
+//! altered
+//!
+//! # Frame Header
+//!
+//! Each frame starts with a 16 byte header:
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                            Length                             |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |        Metadata Length        |           Component           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |            Command            |              Sequence         :
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! :   Sequence    | Type|  Flags  |    Options    |   Reserved    |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! The header is followed by `Metadata Length` bytes of metadata then
+//! `Length` bytes of payload
+//!
+//! The header does not contain an error code, error responses are identified
+//! by their [`FrameType::ErrorResponse`] type and the error code is carried
+//! in the first 4 bytes (big endian) of the frame metadata. The rest of the
+//! metadata is not decoded
+
+use std::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Length of the frame header
+const HEADER_LENGTH: usize = 16;
+/// Length of the error code at the start of the error response metadata
+const ERROR_CODE_LENGTH: usize = 4;
+
+/// Whether new Blaze connections should inspect their frames
+static INSPECT_FRAMES: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables frame inspection, only affects Blaze
+/// connections created after the change
+///
+/// ## Arguments
+/// * `enabled` - Whether to inspect frames
+pub fn set_frame_inspection(enabled: bool) {
+    INSPECT_FRAMES.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether frame inspection is enabled
+pub fn is_frame_inspection_enabled() -> bool {
+    INSPECT_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Direction a frame was travelling through the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    /// Frame sent by the game to the server
+    ToServer,
+    /// Frame sent by the server to the game
+    FromServer,
+}
+
+/// Type of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// Request expecting a response
+    Request,
+    /// Response to a request
+    Response,
+    /// Notification without a response
+    Notify,
+    /// Error response to a request
+    ErrorResponse,
+    /// Unknown frame type
+    Unknown(u8),
+}
+
+impl From<u8> for FrameType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => FrameType::Request,
+            1 => FrameType::Response,
+            2 => FrameType::Notify,
+            3 => FrameType::ErrorResponse,
+            value => FrameType::Unknown(value),
+        }
+    }
+}
+
+/// Decoded frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// Length of the frame payload
+    pub length: u32,
+    /// Length of the frame metadata
+    pub meta_length: u16,
+    /// The component of the frame
+    pub component: u16,
+    /// The command of the frame
+    pub command: u16,
+    /// Sequence number used to match responses to requests
+    pub seq: u32,
+    /// Type of the frame
+    pub ty: FrameType,
+    /// Frame options
+    pub options: u8,
+    /// Error code of an error response, [None] for other frame types
+    /// and error responses without the error code in their metadata
+    pub error_code: Option<u32>,
+}
+
+impl FrameHeader {
+    /// Decodes a frame header from the provided bytes
+    ///
+    /// ## Arguments
+    /// * `bytes` - The header bytes
+    fn decode(bytes: &[u8; HEADER_LENGTH]) -> Self {
+        Self {
+            length: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            meta_length: u16::from_be_bytes([bytes[4], bytes[5]]),
+            component: u16::from_be_bytes([bytes[6], bytes[7]]),
+            command: u16::from_be_bytes([bytes[8], bytes[9]]),
+            seq: u32::from_be_bytes([0, bytes[10], bytes[11], bytes[12]]),
+            ty: FrameType::from(bytes[13] >> 5),
+            options: bytes[14],
+            error_code: None,
+        }
+    }
+
+    /// Whether the error code should be read from the frame metadata
+    fn has_error_code(&self) -> bool {
+        matches!(self.ty, FrameType::ErrorResponse)
+            && self.meta_length as usize >= ERROR_CODE_LENGTH
+    }
+
+    /// Total length of the frame metadata and payload
+    fn contents_length(&self) -> u64 {
+        self.length as u64 + self.meta_length as u64
+    }
+
+    /// Name of the frame component if its a known component
+    pub fn component_name(&self) -> Option<&'static str> {
+        component_name(self.component)
+    }
+
+    /// Name of the frame command if its a known command
+    pub fn command_name(&self) -> Option<&'static str> {
+        command_name(self.component, self.command, self.ty)
+    }
+}
+
+impl Display for FrameHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.component_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{:#06x}", self.component)?,
+        }
+
+        match self.command_name() {
+            Some(name) => write!(f, "->{}", name)?,
+            None => write!(f, "->{:#06x}", self.command)?,
+        }
+
+        write!(
+            f,
+            " ({:?}, seq: {}, length: {}",
+            self.ty, self.seq, self.length
+        )?;
+
+        if let Some(error_code) = self.error_code {
+            write!(f, ", error: {:#x}", error_code)?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+/// Incremental decoder for the frames within a stream of bytes, only
+/// the frame headers and error codes are buffered and the rest of the
+/// frame contents are skipped
+#[derive(Default)]
+pub(crate) struct FrameDecoder {
+    /// Buffer for the current frame header
+    header: [u8; HEADER_LENGTH],
+    /// Number of bytes of the header that have been read
+    header_filled: usize,
+    /// Error response header waiting for its error code
+    pending: Option<FrameHeader>,
+    /// Buffer for the error code of the pending header
+    error_code: [u8; ERROR_CODE_LENGTH],
+    /// Number of bytes of the error code that have been read
+    error_code_filled: usize,
+    /// Number of bytes remaining of the current frame contents
+    skip: u64,
+}
+
+impl FrameDecoder {
+    /// Feeds bytes from the stream into the decoder, `on_frame` is called
+    /// with the header of each frame as soon as the header is complete,
+    /// error responses are held until their error code is complete
+    ///
+    /// ## Arguments
+    /// * `bytes`    - The next bytes from the stream
+    /// * `on_frame` - Callback for decoded frame headers
+    pub(crate) fn push(&mut self, mut bytes: &[u8], mut on_frame: impl FnMut(FrameHeader)) {
+        while !bytes.is_empty() {
+            // Skip the contents of the current frame
+            if self.skip > 0 {
+                let count = self.skip.min(bytes.len() as u64);
+                self.skip -= count;
+                bytes = &bytes[count as usize..];
+                continue;
+            }
+
+            // Fill the error code buffer
+            if let Some(header) = &mut self.pending {
+                let filled = self.error_code_filled;
+                let count = (ERROR_CODE_LENGTH - filled).min(bytes.len());
+                self.error_code[filled..filled + count].copy_from_slice(&bytes[..count]);
+                self.error_code_filled += count;
+                bytes = &bytes[count..];
+
+                if self.error_code_filled < ERROR_CODE_LENGTH {
+                    break;
+                }
+
+                header.error_code = Some(u32::from_be_bytes(self.error_code));
+                self.skip = header.contents_length() - ERROR_CODE_LENGTH as u64;
+
+                let header = *header;
+                self.pending = None;
+                self.error_code_filled = 0;
+
+                on_frame(header);
+                continue;
+            }
+
+            // Fill the header buffer
+            let count = (HEADER_LENGTH - self.header_filled).min(bytes.len());
+            self.header[self.header_filled..self.header_filled + count]
+                .copy_from_slice(&bytes[..count]);
+            self.header_filled += count;
+            bytes = &bytes[count..];
+
+            if self.header_filled < HEADER_LENGTH {
+                break;
+            }
+
+            let header = FrameHeader::decode(&self.header);
+            self.header_filled = 0;
+
+            if header.has_error_code() {
+                self.pending = Some(header);
+                continue;
+            }
+
+            self.skip = header.contents_length();
+
+            on_frame(header);
+        }
+    }
+
+    /// Number of bytes remaining until the end of the current frame, zero
+    /// when the decoder is at a frame boundary. While the header is still
+    /// incomplete only the remaining header bytes are known
+    pub(crate) fn remaining(&self) -> u64 {
+        if let Some(header) = &self.pending {
+            header.contents_length() - self.error_code_filled as u64
+        } else if self.header_filled > 0 {
+            (HEADER_LENGTH - self.header_filled) as u64
+        } else {
+            self.skip
+        }
+    }
+}
+
+/// Obtains the name of a known Blaze component
+///
+/// ## Arguments
+/// * `component` - The component
+pub fn component_name(component: u16) -> Option<&'static str> {
+    Some(match component {
+        0x1 => "Authentication",
+        0x4 => "GameManager",
+        0x5 => "Redirector",
+        0x7 => "Stats",
+        0x9 => "Util",
+        0xF => "Messaging",
+        0x19 => "AssociationLists",
+        0x1C => "GameReporting",
+        0x7802 => "UserSessions",
+        _ => return None,
+    })
+}
+
+/// Obtains the name of a known Blaze command, notifications use
+/// a separate set of commands. Only the commands of the Authentication,
+/// GameManager, Util and UserSessions components are known
+///
+/// ## Arguments
+/// * `component` - The component of the command
+/// * `command`   - The command
+/// * `ty`        - The frame type
+pub fn command_name(component: u16, command: u16, ty: FrameType) -> Option<&'static str> {
+    let notify = matches!(ty, FrameType::Notify);
+
+    Some(match (component, command, notify) {
+        // Authentication
+        (0x1, 0xA, false) => "CreateAccount",
+        (0x1, 0x14, false) => "UpdateAccount",
+        (0x1, 0x1D, false) => "ListUserEntitlements2",
+        (0x1, 0x1E, false) => "GetAccount",
+        (0x1, 0x1F, false) => "GrantEntitlement",
+        (0x1, 0x20, false) => "ListEntitlements",
+        (0x1, 0x21, false) => "HasEntitlement",
+        (0x1, 0x22, false) => "GetUseCount",
+        (0x1, 0x23, false) => "DecrementUseCount",
+        (0x1, 0x24, false) => "GetAuthToken",
+        (0x1, 0x25, false) => "GetHandoffToken",
+        (0x1, 0x28, false) => "Login",
+        (0x1, 0x29, false) => "AcceptTos",
+        (0x1, 0x2A, false) => "GetTosInfo",
+        (0x1, 0x2B, false) => "ModifyEntitlement2",
+        (0x1, 0x2C, false) => "ConsumeCode",
+        (0x1, 0x2D, false) => "PasswordForgot",
+        (0x1, 0x2E, false) => "GetTermsAndConditionsContent",
+        (0x1, 0x2F, false) => "GetPrivacyPolicyContent",
+        (0x1, 0x30, false) => "ListPersonaEntitlements2",
+        (0x1, 0x32, false) => "SilentLogin",
+        (0x1, 0x33, false) => "CheckAgeRequirement",
+        (0x1, 0x3C, false) => "ExpressLogin",
+        (0x1, 0x46, false) => "Logout",
+        (0x1, 0x50, false) => "CreatePersona",
+        (0x1, 0x5A, false) => "GetPersona",
+        (0x1, 0x64, false) => "ListPersonas",
+        (0x1, 0x6E, false) => "LoginPersona",
+        (0x1, 0x78, false) => "LogoutPersona",
+        (0x1, 0x8C, false) => "DeletePersona",
+        (0x1, 0x8D, false) => "DisablePersona",
+        (0x1, 0x8F, false) => "ListDeviceAccounts",
+        (0x1, 0x98, false) => "OriginLogin",
+        (0x1, 0xD2, false) => "ValidateSessionKey",
+        (0x1, 0xF1, false) => "AcceptLegalDocs",
+        (0x1, 0xF2, false) => "GetLegalDocsInfo",
+        (0x1, 0xF6, false) => "GetTermsOfServiceContent",
+        // GameManager
+        (0x4, 0x1, false) => "CreateGame",
+        (0x4, 0x2, false) => "DestroyGame",
+        (0x4, 0x3, false) => "AdvanceGameState",
+        (0x4, 0x4, false) => "SetGameSettings",
+        (0x4, 0x5, false) => "SetPlayerCapacity",
+        (0x4, 0x6, false) => "SetPresenceMode",
+        (0x4, 0x7, false) => "SetGameAttributes",
+        (0x4, 0x8, false) => "SetPlayerAttributes",
+        (0x4, 0x9, false) => "JoinGame",
+        (0x4, 0xB, false) => "RemovePlayer",
+        (0x4, 0xD, false) => "StartMatchmaking",
+        (0x4, 0xE, false) => "CancelMatchmaking",
+        (0x4, 0xF, false) => "FinalizeGameCreation",
+        (0x4, 0x11, false) => "ListGames",
+        (0x4, 0x12, false) => "SetPlayerCustomData",
+        (0x4, 0x13, false) => "ReplayGame",
+        (0x4, 0x14, false) => "ReturnDedicatedServerToPool",
+        (0x4, 0x15, false) => "JoinGameByGroup",
+        (0x4, 0x16, false) => "LeaveGameByGroup",
+        (0x4, 0x17, false) => "MigrateGame",
+        (0x4, 0x18, false) => "UpdateGameHostMigrationStatus",
+        (0x4, 0x19, false) => "ResetDedicatedServer",
+        (0x4, 0x1A, false) => "UpdateGameSession",
+        (0x4, 0x1B, false) => "BanPlayer",
+        (0x4, 0x1D, false) => "UpdateMeshConnection",
+        (0x4, 0x1F, false) => "RemovePlayerFromBannedList",
+        (0x4, 0x20, false) => "ClearBannedList",
+        (0x4, 0x21, false) => "GetBannedList",
+        (0x4, 0x26, false) => "AddQueuedPlayerToGame",
+        (0x4, 0x27, false) => "UpdateGameName",
+        (0x4, 0x64, false) => "GetGameListSnapshot",
+        (0x4, 0x65, false) => "GetGameListSubscription",
+        (0x4, 0x66, false) => "DestroyGameList",
+        (0x4, 0x67, false) => "GetFullGameData",
+        (0x4, 0x68, false) => "GetMatchmakingConfig",
+        (0x4, 0x69, false) => "GetGameDataFromId",
+        (0x4, 0x6A, false) => "AddAdminPlayer",
+        (0x4, 0x6B, false) => "RemoveAdminPlayer",
+        (0x4, 0x6C, false) => "SetPlayerTeam",
+        (0x4, 0x6D, false) => "ChangeGameTeamId",
+        (0x4, 0x6E, false) => "MigrateAdminPlayer",
+        (0x4, 0x6F, false) => "GetUserSetGameListSubscription",
+        (0x4, 0x70, false) => "SwapPlayersTeam",
+        (0x4, 0x96, false) => "RegisterDynamicDedicatedServerCreator",
+        (0x4, 0x97, false) => "UnregisterDynamicDedicatedServerCreator",
+        // GameManager notifications
+        (0x4, 0xA, true) => "MatchmakingFailed",
+        (0x4, 0xC, true) => "MatchmakingAsyncStatus",
+        (0x4, 0xF, true) => "GameCreated",
+        (0x4, 0x10, true) => "GameRemoved",
+        (0x4, 0x14, true) => "GameSetup",
+        (0x4, 0x15, true) => "PlayerJoining",
+        (0x4, 0x16, true) => "JoiningPlayerInitiateConnections",
+        (0x4, 0x17, true) => "PlayerJoiningQueue",
+        (0x4, 0x18, true) => "PlayerPromotedFromQueue",
+        (0x4, 0x19, true) => "PlayerClaimingReservation",
+        (0x4, 0x1E, true) => "PlayerJoinCompleted",
+        (0x4, 0x28, true) => "PlayerRemoved",
+        (0x4, 0x3C, true) => "HostMigrationFinished",
+        (0x4, 0x46, true) => "HostMigrationStart",
+        (0x4, 0x47, true) => "PlatformHostInitialized",
+        (0x4, 0x50, true) => "GameAttribChange",
+        (0x4, 0x5A, true) => "PlayerAttribChange",
+        (0x4, 0x5F, true) => "PlayerCustomDataChange",
+        (0x4, 0x64, true) => "GameStateChange",
+        (0x4, 0x6E, true) => "GameSettingsChange",
+        (0x4, 0x6F, true) => "GameCapacityChange",
+        (0x4, 0x70, true) => "GameReset",
+        (0x4, 0x71, true) => "GameReportingIdChange",
+        (0x4, 0x73, true) => "GameSessionUpdated",
+        (0x4, 0x74, true) => "GamePlayerStateChange",
+        (0x4, 0x75, true) => "GamePlayerTeamChange",
+        (0x4, 0x76, true) => "GameTeamIdChange",
+        (0x4, 0x77, true) => "ProcessQueue",
+        (0x4, 0xC9, true) => "GameListUpdate",
+        // Util
+        (0x9, 0x1, false) => "FetchClientConfig",
+        (0x9, 0x2, false) => "Ping",
+        (0x9, 0x3, false) => "SetClientData",
+        (0x9, 0x4, false) => "LocalizeStrings",
+        (0x9, 0x5, false) => "GetTelemetryServer",
+        (0x9, 0x6, false) => "GetTickerServer",
+        (0x9, 0x7, false) => "PreAuth",
+        (0x9, 0x8, false) => "PostAuth",
+        (0x9, 0xA, false) => "UserSettingsLoad",
+        (0x9, 0xB, false) => "UserSettingsSave",
+        (0x9, 0xC, false) => "UserSettingsLoadAll",
+        (0x9, 0x16, false) => "SetClientMetrics",
+        // UserSessions
+        (0x7802, 0x3, false) => "FetchExtendedData",
+        (0x7802, 0x5, false) => "UpdateExtendedDataAttribute",
+        (0x7802, 0x8, false) => "UpdateHardwareFlags",
+        (0x7802, 0xC, false) => "LookupUser",
+        (0x7802, 0xD, false) => "LookupUsers",
+        (0x7802, 0xE, false) => "LookupUsersByPrefix",
+        (0x7802, 0x14, false) => "UpdateNetworkInfo",
+        (0x7802, 0x17, false) => "LookupUserGeoIpData",
+        (0x7802, 0x18, false) => "OverrideUserGeoIpData",
+        (0x7802, 0x19, false) => "UpdateUserSessionClientData",
+        (0x7802, 0x1A, false) => "SetUserInfoAttribute",
+        (0x7802, 0x1B, false) => "ResetUserGeoIpData",
+        (0x7802, 0x20, false) => "LookupUserSessionId",
+        (0x7802, 0x21, false) => "FetchLastLocaleUsedAndAuthError",
+        (0x7802, 0x22, false) => "FetchUserFirstLastAuthTime",
+        (0x7802, 0x23, false) => "ResumeSession",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{command_name, FrameDecoder, FrameHeader, FrameType, HEADER_LENGTH};
+
+    /// Encodes a frame with the provided header values, metadata and payload
+    fn encode_frame(
+        component: u16,
+        command: u16,
+        seq: u32,
+        ty: u8,
+        meta: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LENGTH + meta.len() + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&(meta.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&component.to_be_bytes());
+        frame.extend_from_slice(&command.to_be_bytes());
+        frame.extend_from_slice(&seq.to_be_bytes()[1..]);
+        frame.push(ty << 5);
+        frame.push(0);
+        frame.push(0);
+        frame.extend_from_slice(meta);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Stream of frames used by the tests along with their expected headers
+    fn frames() -> (Vec<u8>, Vec<FrameHeader>) {
+        let frames = [
+            encode_frame(0x9, 0x7, 1, 0, &[], &[0xAA; 32]),
+            encode_frame(0x9, 0x7, 1, 1, &[0xBB; 4], &[0xCC; 300]),
+            // Header only frame
+            encode_frame(0x7802, 0x1, 0, 2, &[], &[]),
+            // Error response too short to hold an error code
+            encode_frame(0x4, 0x9, 0x00AB_CDEF, 3, &[0xDD; 2], &[0xEE; 5]),
+            encode_frame(0x1, 0x28, 7, 3, &[0x00, 0x00, 0x40, 0x0B, 0xFF], &[0xEE; 3]),
+        ];
+
+        let headers = vec![
+            FrameHeader {
+                length: 32,
+                meta_length: 0,
+                component: 0x9,
+                command: 0x7,
+                seq: 1,
+                ty: FrameType::Request,
+                options: 0,
+                error_code: None,
+            },
+            FrameHeader {
+                length: 300,
+                meta_length: 4,
+                component: 0x9,
+                command: 0x7,
+                seq: 1,
+                ty: FrameType::Response,
+                options: 0,
+                error_code: None,
+            },
+            FrameHeader {
+                length: 0,
+                meta_length: 0,
+                component: 0x7802,
+                command: 0x1,
+                seq: 0,
+                ty: FrameType::Notify,
+                options: 0,
+                error_code: None,
+            },
+            FrameHeader {
+                length: 5,
+                meta_length: 2,
+                component: 0x4,
+                command: 0x9,
+                seq: 0x00AB_CDEF,
+                ty: FrameType::ErrorResponse,
+                options: 0,
+                error_code: None,
+            },
+            FrameHeader {
+                length: 3,
+                meta_length: 5,
+                component: 0x1,
+                command: 0x28,
+                seq: 7,
+                ty: FrameType::ErrorResponse,
+                options: 0,
+                error_code: Some(0x400B),
+            },
+        ];
+
+        (frames.concat(), headers)
+    }
+
+    /// Feeds the stream through a decoder in chunks of the provided size
+    fn decode_chunked(stream: &[u8], chunk_size: usize) -> Vec<FrameHeader> {
+        let mut decoder = FrameDecoder::default();
+        let mut headers = Vec::new();
+
+        for chunk in stream.chunks(chunk_size) {
+            decoder.push(chunk, |header| headers.push(header));
+        }
+
+        assert_eq!(decoder.remaining(), 0, "chunk size {}", chunk_size);
+        headers
+    }
+
+    /// Coalesced frames within a single chunk are all decoded
+    #[test]
+    fn test_coalesced_frames() {
+        let (stream, expected) = frames();
+        assert_eq!(decode_chunked(&stream, stream.len()), expected);
+    }
+
+    /// Frames split across chunks at every possible boundary are decoded
+    #[test]
+    fn test_split_frames() {
+        let (stream, expected) = frames();
+        for chunk_size in [1, 2, 3, 7, 15, 16, 17, 31, 48, 64, 333] {
+            assert_eq!(decode_chunked(&stream, chunk_size), expected);
+        }
+
+        // Split at each position into two chunks
+        for split in 0..stream.len() {
+            let mut decoder = FrameDecoder::default();
+            let mut headers = Vec::new();
+            let (first, second) = stream.split_at(split);
+            decoder.push(first, |header| headers.push(header));
+            decoder.push(second, |header| headers.push(header));
+            assert_eq!(headers, expected, "split at {}", split);
+        }
+    }
+
+    /// Remaining bytes track the position within the current frame
+    #[test]
+    fn test_remaining() {
+        let frame = encode_frame(0x9, 0x2, 5, 0, &[0x1; 3], &[0x2; 10]);
+        let mut decoder = FrameDecoder::default();
+        assert_eq!(decoder.remaining(), 0);
+
+        // Partial header only knows the rest of the header
+        decoder.push(&frame[..10], |_| {});
+        assert_eq!(decoder.remaining(), 6);
+
+        // Complete header knows the frame contents
+        decoder.push(&frame[10..HEADER_LENGTH], |_| {});
+        assert_eq!(decoder.remaining(), 13);
+
+        decoder.push(&frame[HEADER_LENGTH..frame.len() - 1], |_| {});
+        assert_eq!(decoder.remaining(), 1);
+
+        decoder.push(&frame[frame.len() - 1..], |_| {});
+        assert_eq!(decoder.remaining(), 0);
+    }
+
+    /// Error responses are reported once their error code is complete
+    #[test]
+    fn test_error_code() {
+        let frame = encode_frame(0x1, 0x28, 7, 3, &[0x00, 0x01, 0x00, 0x02, 0xFF], &[0x2; 10]);
+        let mut decoder = FrameDecoder::default();
+        let mut headers = Vec::new();
+
+        // Header alone doesn't report the frame
+        decoder.push(&frame[..HEADER_LENGTH + 2], |header| headers.push(header));
+        assert!(headers.is_empty());
+        assert_eq!(decoder.remaining(), 13);
+
+        decoder.push(&frame[HEADER_LENGTH + 2..], |header| headers.push(header));
+        assert_eq!(decoder.remaining(), 0);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].ty, FrameType::ErrorResponse);
+        assert_eq!(headers[0].error_code, Some(0x0001_0002));
+        assert!(headers[0].to_string().ends_with(", error: 0x10002)"));
+    }
+
+    /// Commands are named by component and notifications use their own names
+    #[test]
+    fn test_command_name() {
+        let cases = [
+            (0x1, 0x28, FrameType::Request, Some("Login")),
+            (0x4, 0xD, FrameType::Response, Some("StartMatchmaking")),
+            (0x4, 0x14, FrameType::Notify, Some("GameSetup")),
+            (
+                0x4,
+                0x14,
+                FrameType::Request,
+                Some("ReturnDedicatedServerToPool"),
+            ),
+            (0x7802, 0x14, FrameType::Request, Some("UpdateNetworkInfo")),
+            (0x9, 0x2, FrameType::Notify, None),
+            (0x7, 0x1, FrameType::Request, None),
+        ];
+
+        for (component, command, ty, expected) in cases {
+            assert_eq!(command_name(component, command, ty), expected);
+        }
+    }
+}
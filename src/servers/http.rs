@@ -2,47 +2,69 @@
 //! makes along to the Pocket Relay server, since the game client
 //! is only capable of communicating over SSLv3
 
-use super::{spawn_server_task, HTTP_PORT};
+use super::{
+    http_middleware::{ProxyToken, RequestAction, RequestInfo, ServerUrl},
+    supervisor::{ServerKind, ServerTasks},
+    ServerConfig,
+};
 use crate::{
-    api::{headers::X_TOKEN, proxy_http_request},
+    api::ProxyBody,
     ctx::ClientContext,
+    events::{emit, emit_bind_result, ServerEvent},
+    stats::{self, HttpRequestStats, TrafficCounters},
 };
 use anyhow::Context;
+use futures::StreamExt;
 use hyper::{
-    body::HttpBody, header::HeaderValue, http::uri::PathAndQuery, server::conn::Http,
-    service::service_fn, Body, Request, Response, StatusCode,
+    http::uri::PathAndQuery, server::conn::Http, service::service_fn, Body, Request, Response,
+    StatusCode,
 };
 use log::error;
 use openssl::ssl::{Ssl, SslContext};
-use std::{convert::Infallible, net::Ipv4Addr, pin::Pin, sync::Arc};
-use tokio::net::{TcpListener, TcpStream};
+use parking_lot::Mutex;
+use std::{convert::Infallible, pin::Pin, sync::Arc, time::Instant};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+};
 use tokio_openssl::SslStream;
+use tokio_util::sync::CancellationToken;
+use url::Position;
 
 /// Starts the HTTP proxy server
 ///
 /// ## Arguments
-/// * `http_client` - The HTTP client passed around for sending the requests
-/// * `base_url`    - The server base URL to proxy requests to
-/// * `context`     - The SSL context to use when accepting clients
-/// * `token`       - The authentication token
+/// * `ctx`         - The client context, provides the API client and middleware
+/// * `ssl_context` - The SSL context to use when accepting clients
+/// * `config`      - The server configuration
+/// * `tasks`       - Collection to spawn connection tasks into
 pub async fn start_http_server(
     ctx: Arc<ClientContext>,
     ssl_context: SslContext,
+    config: Arc<ServerConfig>,
+    tasks: ServerTasks,
 ) -> std::io::Result<()> {
+    let addr = config.http;
+
     // Bind the local tcp socket for accepting connections
-    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, HTTP_PORT)).await?;
+    let listener = emit_bind_result(ServerKind::Http, addr, TcpListener::bind(addr).await)?;
 
     // Accept connections
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, _) = select! {
+            result = listener.accept() => result?,
+            // Stop accepting connections when shutting down
+            _ = tasks.shutdown_requested() => return Ok(()),
+        };
 
         let ssl = Ssl::new(&ssl_context).map_err(std::io::Error::other)?;
         let stream = SslStream::new(ssl, stream).map_err(std::io::Error::other)?;
 
         let ctx = ctx.clone();
+        let shutdown = tasks.shutdown_token();
 
-        spawn_server_task(async move {
-            if let Err(err) = serve_connection(stream, ctx).await {
+        tasks.spawn(async move {
+            if let Err(err) = serve_connection(stream, ctx, shutdown).await {
                 error!("Error while redirecting: {}", err);
             }
         });
@@ -50,35 +72,59 @@ pub async fn start_http_server(
 }
 
 /// Handles serving an HTTP connection the provided `stream`, also
-/// completes the accept stream process
+/// completes the accept stream process. When `shutdown` is cancelled
+/// the connection finishes any in-flight requests before closing
 pub async fn serve_connection(
     mut stream: SslStream<TcpStream>,
     ctx: Arc<ClientContext>,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<()> {
     Pin::new(&mut stream).accept().await?;
 
-    Http::new()
-        .serve_connection(
-            stream,
-            service_fn(move |request| handle(request, ctx.clone())),
-        )
-        .await
-        .context("Serve error")?;
+    let connection = Http::new().serve_connection(
+        stream,
+        service_fn(move |request| handle(request, ctx.clone())),
+    );
+    tokio::pin!(connection);
+
+    select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    }
+    .context("Serve error")?;
 
     Ok(())
 }
 
 /// Handles an HTTP request from the HTTP server proxying it along
-/// to the Pocket Relay server
+/// to the Pocket Relay server, the request and response pass through
+/// the registered middleware
 ///
 /// ## Arguments
-/// * `request`     - The HTTP request
-/// * `http_client` - The HTTP client to proxy the request with
-/// * `base_url`    - The server base URL (Connection URL)
+/// * `request` - The HTTP request
+/// * `ctx`     - The client context
 async fn handle(
     mut request: Request<Body>,
     ctx: Arc<ClientContext>,
 ) -> Result<Response<Body>, Infallible> {
+    request
+        .extensions_mut()
+        .insert(ServerUrl(ctx.api.base_url().clone()));
+
+    if let Some(token) = ctx.api.token() {
+        request.extensions_mut().insert(ProxyToken(token));
+    }
+
+    // Allow the middleware to modify or respond to the request
+    for middleware in &ctx.http_middleware {
+        if let RequestAction::Respond(response) = middleware.on_request(&mut request).await {
+            return Ok(response);
+        }
+    }
+
     let path_and_query = request
         .uri()
         // Extract the path and query portion of the url
@@ -92,7 +138,7 @@ async fn handle(
     let path_and_query = path_and_query.strip_prefix('/').unwrap_or(path_and_query);
 
     // Create the new url from the path
-    let url = match ctx.base_url.join(path_and_query) {
+    let url = match ctx.api.endpoint_url(path_and_query) {
         Ok(value) => value,
         Err(err) => {
             error!("Failed to create HTTP proxy URL: {}", err);
@@ -103,12 +149,43 @@ async fn handle(
         }
     };
 
-    let method = request.method().clone();
+    let method_name = request.method().to_string();
+    let path = url[Position::BeforePath..].to_string();
+    let recorder = Arc::new(RequestRecorder {
+        method: method_name.clone(),
+        path: path.clone(),
+        status: Mutex::new(None),
+        counters: TrafficCounters::default(),
+        start: Instant::now(),
+    });
 
-    let body = match request.body_mut().data().await.transpose() {
-        Ok(value) => value,
+    let (parts, body) = request.into_parts();
+    let info = RequestInfo {
+        method: parts.method.clone(),
+        uri: parts.uri,
+        headers: parts.headers.clone(),
+        extensions: Arc::new(parts.extensions),
+    };
+
+    let body = match ProxyBody::from_body(body).await {
+        Ok(ProxyBody::Buffered(body)) => {
+            recorder.counters.record_to_server(body.len());
+            ProxyBody::Buffered(body)
+        }
+        Ok(ProxyBody::Streaming { body, length }) => {
+            let recorder = recorder.clone();
+            ProxyBody::Streaming {
+                body: Body::wrap_stream(body.inspect(move |chunk| match chunk {
+                    Ok(chunk) => recorder.counters.record_to_server(chunk.len()),
+                    Err(_) => recorder.counters.record_error(),
+                })),
+                length,
+            }
+        }
+        Ok(body) => body,
         Err(err) => {
             error!("Failed to read HTTP request body: {}", err);
+            recorder.counters.record_error();
 
             let mut response = Response::default();
             *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
@@ -116,17 +193,37 @@ async fn handle(
         }
     };
 
-    let mut headers = request.headers().clone();
-    headers.insert(
-        X_TOKEN,
-        HeaderValue::from_str(&ctx.token).expect("Invalid token"),
-    );
-
     // Proxy the request to the server
-    let response = match proxy_http_request(&ctx.http_client, url, method, body, headers).await {
+    let response = ctx
+        .api
+        .proxy_http_request(url, parts.method, body, parts.headers)
+        .await;
+
+    let status = response
+        .as_ref()
+        .ok()
+        .map(|response| response.status().as_u16());
+
+    *recorder.status.lock() = status;
+
+    emit(ServerEvent::HttpRequestProxied {
+        method: method_name,
+        path,
+        status,
+    });
+
+    let mut response = match response {
         Ok(value) => value,
         Err(err) => {
             error!("Failed to proxy HTTP request: {}", err);
+            recorder.counters.record_error();
+
+            // Allow the middleware to provide a replacement response
+            for middleware in ctx.http_middleware.iter().rev() {
+                if let Some(response) = middleware.on_error(&info, &err).await {
+                    return Ok(response);
+                }
+            }
 
             let mut response = Response::default();
             *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
@@ -134,5 +231,379 @@ async fn handle(
         }
     };
 
-    Ok(response)
+    for middleware in ctx.http_middleware.iter().rev() {
+        middleware.on_response(&info, &mut response).await;
+    }
+
+    // Count the response body as its streamed to the game
+    Ok(response.map(|body| {
+        Body::wrap_stream(body.inspect(move |chunk| match chunk {
+            Ok(chunk) => recorder.counters.record_from_server(chunk.len()),
+            Err(_) => recorder.counters.record_error(),
+        }))
+    }))
+}
+
+/// Records the stats for a proxied request once both the request
+/// and response bodies have finished streaming
+struct RequestRecorder {
+    /// The request method
+    method: String,
+    /// The request path and query
+    path: String,
+    /// The response status code, [None] if the request failed
+    status: Mutex<Option<u16>>,
+    /// Traffic for the request
+    counters: TrafficCounters,
+    /// When the request started
+    start: Instant,
+}
+
+impl Drop for RequestRecorder {
+    fn drop(&mut self) {
+        stats::record_http_request(HttpRequestStats {
+            method: std::mem::take(&mut self.method),
+            path: std::mem::take(&mut self.path),
+            status: *self.status.get_mut(),
+            traffic: self.counters.load(),
+            duration: self.start.elapsed(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle;
+    use crate::{
+        api::{
+            create_http_client, headers::X_TOKEN, ApiError, HttpClientConfig, PocketArkApi,
+            REPLAY_BODY_LIMIT,
+        },
+        ctx::ClientContext,
+        servers::{
+            http_cache::{HttpCache, HttpCacheConfig},
+            http_middleware::{HttpMiddleware, RequestAction, RequestInfo, ServerUrl},
+        },
+    };
+    use futures::future::BoxFuture;
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use parking_lot::Mutex;
+    use std::{
+        convert::Infallible,
+        net::{Ipv4Addr, SocketAddr},
+        process,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use url::Url;
+
+    /// Middleware recording the order its hooks are called in
+    struct Recorder {
+        /// Name recorded for the middleware
+        name: &'static str,
+        /// Shared log of the hook calls
+        log: Arc<Mutex<Vec<String>>>,
+        /// Whether to respond to requests locally
+        respond: bool,
+        /// Whether to provide a response for errors
+        recover: bool,
+    }
+
+    impl Recorder {
+        /// Creates a recorder that continues requests and doesn't recover errors
+        fn new(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> Self {
+            Self {
+                name,
+                log: log.clone(),
+                respond: false,
+                recover: false,
+            }
+        }
+
+        /// Records a call to the hook
+        fn record(&self, hook: &str) {
+            self.log.lock().push(format!("{}:{}", self.name, hook));
+        }
+    }
+
+    impl HttpMiddleware for Recorder {
+        fn on_request<'a>(
+            &'a self,
+            request: &'a mut Request<Body>,
+        ) -> BoxFuture<'a, RequestAction> {
+            Box::pin(async move {
+                assert!(request.extensions().get::<ServerUrl>().is_some());
+                self.record("request");
+
+                if self.respond {
+                    RequestAction::Respond(Response::new(Body::from(self.name)))
+                } else {
+                    RequestAction::Continue
+                }
+            })
+        }
+
+        fn on_response<'a>(
+            &'a self,
+            _request: &'a RequestInfo,
+            _response: &'a mut Response<Body>,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(async move { self.record("response") })
+        }
+
+        fn on_error<'a>(
+            &'a self,
+            _request: &'a RequestInfo,
+            _error: &'a ApiError,
+        ) -> BoxFuture<'a, Option<Response<Body>>> {
+            Box::pin(async move {
+                self.record("error");
+                self.recover.then(|| Response::new(Body::from(self.name)))
+            })
+        }
+    }
+
+    /// Starts a server answering every request with an empty response
+    fn start_server() -> Url {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }))
+        });
+
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        url
+    }
+
+    /// Creates a context for the server at the URL using the middleware
+    fn context(url: Url, http_middleware: Vec<Box<dyn HttpMiddleware>>) -> Arc<ClientContext> {
+        let http_client = create_http_client(HttpClientConfig::default()).unwrap();
+        let api = PocketArkApi::new(http_client, url, None);
+        api.set_token(Arc::from("token"));
+
+        Arc::new(ClientContext {
+            api,
+            tunnel_port: None,
+            capabilities: Default::default(),
+            http_middleware,
+        })
+    }
+
+    /// Sends a request through the HTTP server handler, returns the
+    /// response status and body
+    async fn send(ctx: Arc<ClientContext>) -> (StatusCode, String) {
+        let request = Request::get("/api/test").body(Body::empty()).unwrap();
+        let response = handle(request, ctx).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Tests that requests pass through the middleware in order and
+    /// responses pass through in reverse order
+    #[tokio::test]
+    async fn test_middleware_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let ctx = context(
+            start_server(),
+            vec![
+                Box::new(Recorder::new("a", &log)),
+                Box::new(Recorder::new("b", &log)),
+            ],
+        );
+
+        let (status, _) = send(ctx).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            *log.lock(),
+            ["a:request", "b:request", "b:response", "a:response"]
+        );
+    }
+
+    /// Tests that responding to a request skips the remaining middleware
+    /// and the server
+    #[tokio::test]
+    async fn test_middleware_respond() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // Server is never contacted so the URL doesn't need to be reachable
+        let ctx = context(
+            Url::parse("http://127.0.0.1:1/").unwrap(),
+            vec![
+                Box::new(Recorder::new("a", &log)),
+                Box::new(Recorder {
+                    respond: true,
+                    ..Recorder::new("b", &log)
+                }),
+                Box::new(Recorder::new("c", &log)),
+            ],
+        );
+
+        let (status, body) = send(ctx).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "b");
+        assert_eq!(*log.lock(), ["a:request", "b:request"]);
+    }
+
+    /// Tests that errors pass through the middleware in reverse order
+    /// until a middleware provides a response
+    #[tokio::test]
+    async fn test_middleware_error() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let ctx = context(
+            url.clone(),
+            vec![
+                Box::new(Recorder::new("a", &log)),
+                Box::new(Recorder {
+                    recover: true,
+                    ..Recorder::new("b", &log)
+                }),
+                Box::new(Recorder::new("c", &log)),
+            ],
+        );
+
+        let (status, body) = send(ctx).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "b");
+        assert_eq!(
+            *log.lock(),
+            ["a:request", "b:request", "c:request", "c:error", "b:error"]
+        );
+
+        // Error response is used when no middleware recovers
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let ctx = context(url, vec![Box::new(Recorder::new("a", &log))]);
+
+        let (status, _) = send(ctx).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(*log.lock(), ["a:request", "a:error"]);
+    }
+
+    /// Tests that cached responses to requests proxied with a token are
+    /// kept per account and never written to disk
+    #[tokio::test]
+    async fn test_cache_per_account() {
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        // Server responding with the token the request was made with
+        let make_service = make_service_fn({
+            let requests = requests.clone();
+            move |_| {
+                let requests = requests.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let token = request.headers()[X_TOKEN].clone();
+                        async move {
+                            Response::builder()
+                                .header(header::CACHE_CONTROL, "max-age=60")
+                                .body(Body::from(token.as_bytes().to_vec()))
+                        }
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("pocket-ark-http-proxy-{}", process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+
+        let cache = HttpCache::new(HttpCacheConfig {
+            disk_dir: Some(dir.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let ctx = context(url, vec![Box::new(cache)]);
+
+        // Second request for the same account is served from the cache
+        assert_eq!(send(ctx.clone()).await, (StatusCode::OK, "token".into()));
+        assert_eq!(send(ctx.clone()).await, (StatusCode::OK, "token".into()));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Another account never receives the first account's response
+        ctx.api.set_token(Arc::from("other"));
+        assert_eq!(send(ctx.clone()).await, (StatusCode::OK, "other".into()));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        ctx.api.set_token(Arc::from("token"));
+        assert_eq!(send(ctx.clone()).await, (StatusCode::OK, "token".into()));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Allow time for any background disk write
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a body larger than the replay limit sent through the
+    /// handler reaches the server with its content length rather than
+    /// being chunked
+    #[tokio::test]
+    async fn test_streamed_body_content_length() {
+        // Responds with the framing headers the request was received with
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let headers = request.headers().clone();
+                let body = hyper::body::to_bytes(request.into_body()).await?;
+
+                let mut response = Response::new(Body::from(body));
+                for name in [header::CONTENT_LENGTH, header::TRANSFER_ENCODING] {
+                    if let Some(value) = headers.get(&name) {
+                        let name = format!("x-received-{}", name);
+                        response
+                            .headers_mut()
+                            .insert(header::HeaderName::try_from(name).unwrap(), value.clone());
+                    }
+                }
+                Ok::<_, hyper::Error>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+
+        let ctx = context(url, Vec::new());
+
+        let length = REPLAY_BODY_LIMIT as usize * 3 + 7;
+        let data: Vec<u8> = (0..length).map(|index| index as u8).collect();
+
+        let request = Request::post("/api/test")
+            .header(header::CONTENT_LENGTH, length)
+            .body(Body::from(data.clone()))
+            .unwrap();
+        let response = handle(request, ctx).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("x-received-content-length").unwrap(),
+            &length.to_string()
+        );
+        assert!(response
+            .headers()
+            .get("x-received-transfer-encoding")
+            .is_none());
+
+        let echoed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(echoed == data);
+    }
 }
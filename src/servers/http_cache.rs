@@ -0,0 +1,1228 @@
+//! Response cache for the HTTP proxy server, implemented as a
+//! [`HttpMiddleware`] that can be registered on the client context
+//!
+//! Only successful GET responses are cached. Responses are fresh for the
+//! `max-age` from their `Cache-Control` header, stale responses with an
+//! `ETag` or `Last-Modified` validator are revalidated using a conditional
+//! request. Responses marked `no-store`, responses with a `Vary` header or
+//! a `Set-Cookie` header and responses without a lifetime or a validator
+//! are never cached
+//!
+//! Entries are keyed by the server base URL and the request path so that
+//! servers never share entries, requests proxied with an authentication
+//! token are also keyed by a hash of the token so that accounts never share
+//! entries. Entries are kept in memory and optionally on disk, both have a
+//! size limit. The least recently used entries are evicted from memory first
+//! while the least recently written entries are removed from disk first.
+//! Responses marked `private` and responses to requests carrying credentials
+//! are only kept in memory so they never outlive the session or leak to
+//! another account through the disk cache
+
+use super::http_middleware::{HttpMiddleware, ProxyToken, RequestAction, RequestInfo, ServerUrl};
+use crate::api::{headers::X_TOKEN, ApiError};
+use bytes::{Bytes, BytesMut};
+use futures::{future::BoxFuture, Stream};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    http::Extensions,
+    Body, HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use log::{debug, warn};
+use openssl::sha::sha256;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+/// Request headers carrying credentials that the response may be specific to
+const CREDENTIAL_HEADERS: [&str; 3] = ["authorization", "cookie", X_TOKEN];
+
+/// Configuration for the HTTP cache
+#[derive(Debug, Clone)]
+pub struct HttpCacheConfig {
+    /// Maximum total size in bytes of the bodies kept in memory
+    pub memory_limit: u64,
+    /// Directory to store entries on disk, [None] to only cache in memory
+    pub disk_dir: Option<PathBuf>,
+    /// Maximum total size in bytes of the bodies stored on disk
+    pub disk_limit: u64,
+    /// Responses larger than this are not cached
+    pub max_entry_size: u64,
+    /// Whether to serve stale entries when the server is unreachable
+    pub serve_stale_on_error: bool,
+}
+
+impl Default for HttpCacheConfig {
+    fn default() -> Self {
+        Self {
+            memory_limit: 32 * 1024 * 1024,
+            disk_dir: None,
+            disk_limit: 256 * 1024 * 1024,
+            max_entry_size: 8 * 1024 * 1024,
+            serve_stale_on_error: false,
+        }
+    }
+}
+
+/// Metadata for a cached response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EntryMeta {
+    /// The cache key (Server base URL with the request path and query,
+    /// followed by the credentials hash for authenticated requests)
+    key: String,
+    /// The response status code
+    status: u16,
+    /// The response headers
+    headers: Vec<(String, String)>,
+    /// When the response was stored or last revalidated
+    stored_at: SystemTime,
+    /// How long the response is fresh for after being stored
+    max_age: Duration,
+    /// The response entity tag
+    etag: Option<String>,
+    /// The response last modified date
+    last_modified: Option<String>,
+    /// Whether the entry must only be kept in memory
+    #[serde(default)]
+    private: bool,
+}
+
+impl EntryMeta {
+    /// Whether the entry can be served without revalidating
+    fn is_fresh(&self) -> bool {
+        self.stored_at
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed < self.max_age)
+    }
+}
+
+/// Cached response
+#[derive(Debug)]
+struct CacheEntry {
+    /// The response metadata
+    meta: EntryMeta,
+    /// The response body
+    body: Bytes,
+}
+
+impl CacheEntry {
+    /// Creates a response from the entry
+    fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = StatusCode::from_u16(self.meta.status).unwrap_or(StatusCode::OK);
+
+        let headers = response.headers_mut();
+        for (name, value) in &self.meta.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+
+        response
+    }
+}
+
+/// Entry being revalidated, carried in the request extensions from
+/// [`HttpMiddleware::on_request`] to [`HttpMiddleware::on_response`]
+#[derive(Clone)]
+struct Revalidation(Arc<CacheEntry>);
+
+/// In memory entries
+#[derive(Default)]
+struct MemoryCache {
+    /// Entries along with the tick they were last used at
+    entries: HashMap<String, (Arc<CacheEntry>, u64)>,
+    /// Total size of the entry bodies
+    size: u64,
+    /// Counter used for tracking the least recently used entry
+    tick: u64,
+}
+
+/// HTTP response cache middleware
+pub struct HttpCache {
+    /// The cache configuration
+    config: HttpCacheConfig,
+    /// The in memory entries
+    memory: Arc<Mutex<MemoryCache>>,
+}
+
+impl HttpCache {
+    /// Creates a new cache, creates the disk directory if one is set
+    ///
+    /// ## Arguments
+    /// * `config` - The cache configuration
+    pub fn new(config: HttpCacheConfig) -> std::io::Result<Self> {
+        if let Some(dir) = &config.disk_dir {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        Ok(Self {
+            config,
+            memory: Default::default(),
+        })
+    }
+
+    /// Removes all entries from memory and disk, only the files written
+    /// by the cache are removed from the disk cache directory
+    pub fn clear(&self) -> std::io::Result<()> {
+        *self.memory.lock() = MemoryCache::default();
+
+        if let Some(dir) = &self.config.disk_dir {
+            for file in std::fs::read_dir(dir)? {
+                let path = file?.path();
+                if is_entry_file(&path, "json") || is_entry_file(&path, "body") {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the entry for the provided key checking the memory
+    /// cache then the disk cache
+    ///
+    /// ## Arguments
+    /// * `key` - The cache key
+    async fn get(&self, key: &str) -> Option<Arc<CacheEntry>> {
+        {
+            let memory = &mut *self.memory.lock();
+            memory.tick += 1;
+            let tick = memory.tick;
+
+            if let Some((entry, last_used)) = memory.entries.get_mut(key) {
+                *last_used = tick;
+                return Some(entry.clone());
+            }
+        }
+
+        let dir = self.config.disk_dir.as_ref()?;
+        let entry = match read_disk_entry(dir, key).await {
+            Ok(value) => value?,
+            Err(err) => {
+                warn!("Failed to read cache entry from disk: {}", err);
+                return None;
+            }
+        };
+
+        let entry = Arc::new(entry);
+        insert_memory(&self.memory, self.config.memory_limit, entry.clone());
+        Some(entry)
+    }
+
+    /// Stores an entry in memory and on disk
+    ///
+    /// ## Arguments
+    /// * `entry` - The entry to store
+    fn store(&self, entry: CacheEntry) {
+        store_entry(&self.memory, &self.config, Arc::new(entry));
+    }
+
+    /// Finds a stale entry that can be served when the server is unreachable
+    ///
+    /// ## Arguments
+    /// * `request` - The request that failed
+    async fn stale_response(&self, request: &RequestInfo) -> Option<Response<Body>> {
+        if !self.config.serve_stale_on_error || request.method != Method::GET {
+            return None;
+        }
+
+        let key = cache_key(&request.extensions, &request.headers, &request.uri)?;
+        let entry = self.get(&key).await?;
+
+        warn!(
+            "Server unreachable, serving stale cached response for {}",
+            key
+        );
+        Some(entry.to_response())
+    }
+}
+
+impl HttpMiddleware for HttpCache {
+    fn on_request<'a>(&'a self, request: &'a mut Request<Body>) -> BoxFuture<'a, RequestAction> {
+        Box::pin(async move {
+            if request.method() != Method::GET {
+                return RequestAction::Continue;
+            }
+
+            let directives = CacheControl::from_headers(request.headers());
+            if directives.no_store {
+                return RequestAction::Continue;
+            }
+
+            let Some(key) = cache_key(request.extensions(), request.headers(), request.uri())
+            else {
+                return RequestAction::Continue;
+            };
+
+            let Some(entry) = self.get(&key).await else {
+                return RequestAction::Continue;
+            };
+
+            if entry.meta.is_fresh() && !directives.no_cache {
+                debug!("Serving cached response for {}", key);
+                return RequestAction::Respond(entry.to_response());
+            }
+
+            // Revalidate the entry unless the game is making its own conditional request
+            let headers = request.headers_mut();
+            if headers.contains_key(header::IF_NONE_MATCH)
+                || headers.contains_key(header::IF_MODIFIED_SINCE)
+            {
+                return RequestAction::Continue;
+            }
+
+            let mut conditional = false;
+
+            if let Some(value) = entry
+                .meta
+                .etag
+                .as_deref()
+                .and_then(|value| HeaderValue::from_str(value).ok())
+            {
+                headers.insert(header::IF_NONE_MATCH, value);
+                conditional = true;
+            }
+
+            if let Some(value) = entry
+                .meta
+                .last_modified
+                .as_deref()
+                .and_then(|value| HeaderValue::from_str(value).ok())
+            {
+                headers.insert(header::IF_MODIFIED_SINCE, value);
+                conditional = true;
+            }
+
+            if conditional {
+                request.extensions_mut().insert(Revalidation(entry));
+            }
+
+            RequestAction::Continue
+        })
+    }
+
+    fn on_response<'a>(
+        &'a self,
+        request: &'a RequestInfo,
+        response: &'a mut Response<Body>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if request.method != Method::GET {
+                return;
+            }
+
+            let Some(key) = cache_key(&request.extensions, &request.headers, &request.uri) else {
+                return;
+            };
+
+            let status = response.status();
+
+            // Gateway errors indicate the server itself is unreachable
+            if matches!(
+                status,
+                StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ) {
+                if let Some(stale) = self.stale_response(request).await {
+                    *response = stale;
+                }
+                return;
+            }
+
+            // Only responses to the cache's own conditional requests are replaced,
+            // a 304 for the game's own conditional request is passed through
+            if status == StatusCode::NOT_MODIFIED {
+                if let Some(Revalidation(entry)) = request.extensions.get::<Revalidation>() {
+                    revalidated(self, entry, response);
+                }
+                return;
+            }
+
+            if status != StatusCode::OK {
+                return;
+            }
+
+            let request_directives = CacheControl::from_headers(&request.headers);
+            let directives = CacheControl::from_headers(response.headers());
+            let headers = response.headers();
+
+            let etag = header_string(headers, &header::ETAG);
+            let last_modified = header_string(headers, &header::LAST_MODIFIED);
+            let max_age = directives.max_age();
+
+            let storable = !request_directives.no_store
+                && !directives.no_store
+                && !headers.contains_key(header::VARY)
+                && !headers.contains_key(header::SET_COOKIE)
+                && (!max_age.is_zero() || etag.is_some() || last_modified.is_some())
+                && response_length(headers)
+                    .map_or(true, |length| length <= self.config.max_entry_size);
+
+            if !storable {
+                return;
+            }
+
+            let meta = EntryMeta {
+                key,
+                status: status.as_u16(),
+                headers: stored_headers(headers),
+                stored_at: SystemTime::now(),
+                max_age,
+                etag,
+                last_modified,
+                private: directives.private
+                    || has_credentials(&request.extensions, &request.headers),
+            };
+
+            // Store the body once its finished streaming to the game
+            let memory = self.memory.clone();
+            let config = self.config.clone();
+            let body = std::mem::take(response.body_mut());
+
+            *response.body_mut() = Body::wrap_stream(TeeBody {
+                inner: body,
+                buffer: Some(BytesMut::new()),
+                limit: self.config.max_entry_size,
+                on_complete: Some(Box::new(move |body| {
+                    store_entry(&memory, &config, Arc::new(CacheEntry { meta, body }));
+                })),
+            });
+        })
+    }
+
+    fn on_error<'a>(
+        &'a self,
+        request: &'a RequestInfo,
+        _error: &'a ApiError,
+    ) -> BoxFuture<'a, Option<Response<Body>>> {
+        Box::pin(self.stale_response(request))
+    }
+}
+
+/// Handles a not modified response to a revalidation request, refreshes
+/// the entry and replaces the response with the cached response
+///
+/// ## Arguments
+/// * `cache`    - The cache
+/// * `entry`    - The entry that was revalidated
+/// * `response` - The not modified response
+fn revalidated(cache: &HttpCache, entry: &CacheEntry, response: &mut Response<Body>) {
+    let mut meta = entry.meta.clone();
+    meta.stored_at = SystemTime::now();
+
+    if response.headers().contains_key(header::CACHE_CONTROL) {
+        meta.max_age = CacheControl::from_headers(response.headers()).max_age();
+    }
+
+    debug!("Revalidated cached response for {}", meta.key);
+
+    let entry = CacheEntry {
+        meta,
+        body: entry.body.clone(),
+    };
+    *response = entry.to_response();
+    cache.store(entry);
+}
+
+/// Stores an entry in memory and writes it to the disk in the background
+///
+/// ## Arguments
+/// * `memory` - The in memory entries
+/// * `config` - The cache configuration
+/// * `entry`  - The entry to store
+fn store_entry(memory: &Mutex<MemoryCache>, config: &HttpCacheConfig, entry: Arc<CacheEntry>) {
+    insert_memory(memory, config.memory_limit, entry.clone());
+
+    if entry.meta.private {
+        return;
+    }
+
+    let Some(dir) = config.disk_dir.clone() else {
+        return;
+    };
+
+    let disk_limit = config.disk_limit;
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = write_disk_entry(&dir, &entry, disk_limit) {
+            warn!("Failed to write cache entry to disk: {}", err);
+        }
+    });
+}
+
+/// Inserts an entry into the memory cache evicting the least recently
+/// used entries until the cache is within its limit
+///
+/// ## Arguments
+/// * `memory` - The in memory entries
+/// * `limit`  - The memory size limit
+/// * `entry`  - The entry to insert
+fn insert_memory(memory: &Mutex<MemoryCache>, limit: u64, entry: Arc<CacheEntry>) {
+    let size = entry.body.len() as u64;
+    if size > limit {
+        return;
+    }
+
+    let memory = &mut *memory.lock();
+    memory.tick += 1;
+
+    if let Some((previous, _)) = memory
+        .entries
+        .insert(entry.meta.key.clone(), (entry, memory.tick))
+    {
+        memory.size -= previous.body.len() as u64;
+    }
+    memory.size += size;
+
+    while memory.size > limit {
+        let Some(key) = memory
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+
+        if let Some((entry, _)) = memory.entries.remove(&key) {
+            memory.size -= entry.body.len() as u64;
+        }
+    }
+}
+
+/// Paths of the metadata and body files for an entry, file names are
+/// the SHA-256 hash of the key and the key is checked when loading
+///
+/// ## Arguments
+/// * `dir` - The cache directory
+/// * `key` - The cache key
+fn disk_paths(dir: &Path, key: &str) -> (PathBuf, PathBuf) {
+    let name = hex_sha256(key.as_bytes());
+
+    (
+        dir.join(format!("{}.json", name)),
+        dir.join(format!("{}.body", name)),
+    )
+}
+
+/// Checks whether the path is an entry file written by the cache, named
+/// using the hash of the key with the provided extension
+///
+/// ## Arguments
+/// * `path`      - The file path
+/// * `extension` - The expected extension
+fn is_entry_file(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|value| value == extension)
+        && path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| {
+                stem.len() == 64
+                    && stem
+                        .bytes()
+                        .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+            })
+}
+
+/// Reads an entry from the disk cache
+///
+/// ## Arguments
+/// * `dir` - The cache directory
+/// * `key` - The cache key
+async fn read_disk_entry(dir: &Path, key: &str) -> std::io::Result<Option<CacheEntry>> {
+    let (meta_path, body_path) = disk_paths(dir, key);
+
+    let meta = match tokio::fs::read(&meta_path).await {
+        Ok(value) => value,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let meta: EntryMeta = serde_json::from_slice(&meta)?;
+    if meta.key != key {
+        return Ok(None);
+    }
+
+    let body = tokio::fs::read(&body_path).await?;
+
+    Ok(Some(CacheEntry {
+        meta,
+        body: Bytes::from(body),
+    }))
+}
+
+/// Writes an entry to the disk cache then removes the least recently
+/// modified entries until the cache is within its limit
+///
+/// ## Arguments
+/// * `dir`   - The cache directory
+/// * `entry` - The entry to write
+/// * `limit` - The disk size limit
+fn write_disk_entry(dir: &Path, entry: &CacheEntry, limit: u64) -> std::io::Result<()> {
+    let (meta_path, body_path) = disk_paths(dir, &entry.meta.key);
+
+    // Body is written first so the metadata never refers to a missing body
+    std::fs::write(&body_path, &entry.body)?;
+    std::fs::write(&meta_path, serde_json::to_vec(&entry.meta)?)?;
+
+    let mut bodies = Vec::new();
+    let mut size = 0;
+
+    for file in std::fs::read_dir(dir)? {
+        let path = file?.path();
+        if !is_entry_file(&path, "body") {
+            continue;
+        }
+
+        let metadata = std::fs::metadata(&path)?;
+        size += metadata.len();
+        bodies.push((metadata.modified()?, metadata.len(), path));
+    }
+
+    bodies.sort_by_key(|(modified, _, _)| *modified);
+
+    for (_, length, path) in bodies {
+        if size <= limit {
+            break;
+        }
+
+        _ = std::fs::remove_file(path.with_extension("json"));
+        std::fs::remove_file(&path)?;
+        size -= length;
+    }
+
+    Ok(())
+}
+
+/// Parsed `Cache-Control` directives that the cache uses
+#[derive(Default)]
+struct CacheControl {
+    /// Response must not be stored
+    no_store: bool,
+    /// Response must be revalidated before use
+    no_cache: bool,
+    /// Response is specific to the user
+    private: bool,
+    /// Number of seconds the response is fresh for
+    max_age: Option<u64>,
+}
+
+impl CacheControl {
+    /// Parses the directives from the `Cache-Control` headers
+    ///
+    /// ## Arguments
+    /// * `headers` - The headers to parse from
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+
+        let values = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for directive in values {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = value.and_then(|value| value.parse().ok()),
+                _ => {}
+            }
+        }
+
+        directives
+    }
+
+    /// How long a response is fresh for, zero when the response
+    /// must always be revalidated
+    fn max_age(&self) -> Duration {
+        if self.no_cache {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs(self.max_age.unwrap_or_default())
+    }
+}
+
+/// Hex encoded SHA-256 hash of the provided data
+///
+/// ## Arguments
+/// * `data` - The data to hash
+fn hex_sha256(data: &[u8]) -> String {
+    sha256(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Obtains the cache key for a request from the base URL of the server
+/// and the request path and query. Requests carrying credentials have a
+/// hash of the proxy token and the credential headers appended as a
+/// fragment, which never appears in the request path, so responses aren't
+/// shared between credentials and the credentials themselves aren't kept
+/// in the key
+///
+/// ## Arguments
+/// * `extensions` - The request extensions containing the [`ServerUrl`]
+///   and the [`ProxyToken`]
+/// * `headers`    - The request headers
+/// * `uri`        - The request URI
+fn cache_key(extensions: &Extensions, headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    let ServerUrl(server) = extensions.get::<ServerUrl>()?;
+    let path = uri.path_and_query()?;
+    let server = server.as_str().trim_end_matches('/');
+
+    if !has_credentials(extensions, headers) {
+        return Some(format!("{}{}", server, path.as_str()));
+    }
+
+    let mut credentials = Vec::new();
+
+    if let Some(ProxyToken(token)) = extensions.get::<ProxyToken>() {
+        credentials.extend_from_slice(token.as_bytes());
+    }
+
+    for name in CREDENTIAL_HEADERS {
+        for value in headers.get_all(name) {
+            credentials.push(b'\n');
+            credentials.extend_from_slice(name.as_bytes());
+            credentials.extend_from_slice(b": ");
+            credentials.extend_from_slice(value.as_bytes());
+        }
+    }
+
+    Some(format!(
+        "{}{}#{}",
+        server,
+        path.as_str(),
+        hex_sha256(&credentials)
+    ))
+}
+
+/// Checks whether a request carries credentials that the response
+/// may be specific to, every request proxied with a token does
+///
+/// ## Arguments
+/// * `extensions` - The request extensions
+/// * `headers`    - The request headers
+fn has_credentials(extensions: &Extensions, headers: &HeaderMap) -> bool {
+    extensions.get::<ProxyToken>().is_some()
+        || CREDENTIAL_HEADERS
+            .iter()
+            .any(|name| headers.contains_key(*name))
+}
+
+/// Obtains a header value as a string
+///
+/// ## Arguments
+/// * `headers` - The headers
+/// * `name`    - The header name
+fn header_string(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Obtains the content length of a response if its known
+///
+/// ## Arguments
+/// * `headers` - The response headers
+fn response_length(headers: &HeaderMap) -> Option<u64> {
+    header_string(headers, &header::CONTENT_LENGTH).and_then(|value| value.parse().ok())
+}
+
+/// Obtains the headers to store for a response excluding the headers
+/// that describe the transfer of the original body
+///
+/// ## Arguments
+/// * `headers` - The response headers
+fn stored_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            !matches!(
+                *name,
+                &header::CONTENT_LENGTH | &header::TRANSFER_ENCODING | &header::CONNECTION
+            )
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// Callback for a body that finished streaming
+type OnComplete = Box<dyn FnOnce(Bytes) + Send>;
+
+/// Body stream that passes through the chunks of another body while
+/// collecting them, the collected body is provided to the callback
+/// if the body completes without exceeding the limit
+struct TeeBody {
+    /// The body being streamed
+    inner: Body,
+    /// Collected body, [None] if the body exceeded the limit or failed
+    buffer: Option<BytesMut>,
+    /// Maximum length of the collected body
+    limit: u64,
+    /// Callback for the completed body
+    on_complete: Option<OnComplete>,
+}
+
+impl Stream for TeeBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let result = ready!(Pin::new(&mut this.inner).poll_next(cx));
+
+        match &result {
+            Some(Ok(chunk)) => {
+                if let Some(buffer) = &mut this.buffer {
+                    if buffer.len() as u64 + chunk.len() as u64 > this.limit {
+                        this.buffer = None;
+                    } else {
+                        buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Some(Err(_)) => this.buffer = None,
+            None => {
+                if let (Some(buffer), Some(on_complete)) =
+                    (this.buffer.take(), this.on_complete.take())
+                {
+                    on_complete(buffer.freeze());
+                }
+            }
+        }
+
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        disk_paths, insert_memory, CacheControl, CacheEntry, EntryMeta, HttpCache, HttpCacheConfig,
+        MemoryCache, TeeBody,
+    };
+    use crate::{
+        servers::http_middleware::{HttpMiddleware, RequestAction, RequestInfo, ServerUrl},
+        session::test::test_dir,
+    };
+    use bytes::{Bytes, BytesMut};
+    use futures::{stream, StreamExt};
+    use hyper::{header, Body, HeaderMap, Request, Response, StatusCode};
+    use parking_lot::Mutex;
+    use std::{
+        path::Path,
+        process,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    /// Creates an entry for the provided key and body
+    fn entry(key: &str, body: &'static [u8], max_age: Duration, etag: Option<&str>) -> CacheEntry {
+        CacheEntry {
+            meta: EntryMeta {
+                key: key.to_string(),
+                status: 200,
+                headers: vec![("content-type".to_string(), "text/plain".to_string())],
+                stored_at: SystemTime::now(),
+                max_age,
+                etag: etag.map(str::to_string),
+                last_modified: None,
+                private: false,
+            },
+            body: Bytes::from_static(body),
+        }
+    }
+
+    /// Creates a GET request for the path proxied to the test server
+    fn request(path: &str) -> Request<Body> {
+        let mut request = Request::get(path).body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ServerUrl("http://127.0.0.1/".parse().unwrap()));
+        request
+    }
+
+    /// Creates the request info for a request once the middleware has handled it
+    fn request_info(request: Request<Body>) -> RequestInfo {
+        let (parts, _) = request.into_parts();
+        RequestInfo {
+            method: parts.method,
+            uri: parts.uri,
+            headers: parts.headers,
+            extensions: Arc::new(parts.extensions),
+        }
+    }
+
+    /// Tests parsing the directives from multiple headers with
+    /// varying case, quoting and invalid values
+    #[test]
+    fn test_cache_control_from_headers() {
+        let parse = |values: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(header::CACHE_CONTROL, value.parse().unwrap());
+            }
+            CacheControl::from_headers(&headers)
+        };
+
+        let directives = parse(&[]);
+        assert!(!directives.no_store && !directives.no_cache);
+        assert_eq!(directives.max_age(), Duration::ZERO);
+
+        let directives = parse(&["public, max-age=60"]);
+        assert!(!directives.no_store && !directives.no_cache);
+        assert_eq!(directives.max_age(), Duration::from_secs(60));
+
+        let directives = parse(&["max-age=\"30\"", "No-Store"]);
+        assert!(directives.no_store);
+        assert_eq!(directives.max_age, Some(30));
+
+        // No cache always requires revalidation
+        let directives = parse(&["max-age=60, no-cache"]);
+        assert!(directives.no_cache);
+        assert_eq!(directives.max_age(), Duration::ZERO);
+
+        let directives = parse(&["max-age=invalid"]);
+        assert_eq!(directives.max_age, None);
+
+        let directives = parse(&["Private, max-age=60"]);
+        assert!(directives.private);
+        assert_eq!(directives.max_age(), Duration::from_secs(60));
+    }
+
+    /// Tests that the least recently used entries are evicted once the
+    /// memory limit is exceeded and that oversized entries are skipped
+    #[tokio::test]
+    async fn test_insert_memory_eviction() {
+        let cache = HttpCache::new(HttpCacheConfig {
+            memory_limit: 10,
+            ..Default::default()
+        })
+        .unwrap();
+        let insert = |key, body| {
+            insert_memory(
+                &cache.memory,
+                10,
+                Arc::new(entry(key, body, Duration::ZERO, None)),
+            )
+        };
+        let keys = |memory: &Mutex<MemoryCache>| {
+            let mut keys: Vec<String> = memory.lock().entries.keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+
+        insert("a", b"aaaa");
+        insert("b", b"bbbb");
+
+        // Use the first entry so the second becomes the least recently used
+        assert!(cache.get("a").await.is_some());
+
+        insert("c", b"cccc");
+        assert_eq!(keys(&cache.memory), ["a", "c"]);
+        assert_eq!(cache.memory.lock().size, 8);
+
+        // Replacing an entry only counts the new body
+        insert("a", b"aa");
+        assert_eq!(cache.memory.lock().size, 6);
+
+        // Entries larger than the limit are never stored
+        insert("d", b"ddddddddddd");
+        assert_eq!(keys(&cache.memory), ["a", "c"]);
+        assert_eq!(cache.memory.lock().size, 6);
+    }
+
+    /// Collects a body through a [`TeeBody`] with the provided limit returning
+    /// the body streamed to the game and the body provided to the callback
+    async fn collect_tee(chunks: &[&'static [u8]], limit: u64) -> (Bytes, Option<Bytes>) {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        let completed = Arc::new(Mutex::new(None));
+
+        let tee = TeeBody {
+            inner: Body::wrap_stream(stream::iter(chunks)),
+            buffer: Some(BytesMut::new()),
+            limit,
+            on_complete: Some(Box::new({
+                let completed = completed.clone();
+                move |body| *completed.lock() = Some(body)
+            })),
+        };
+
+        let streamed: Vec<Bytes> = tee.map(|chunk| chunk.unwrap()).collect().await;
+        let completed = completed.lock().take();
+        (Bytes::from(streamed.concat()), completed)
+    }
+
+    /// Tests that bodies within the limit are collected and bodies over
+    /// the limit are still streamed but never collected
+    #[tokio::test]
+    async fn test_tee_body_limit() {
+        let (streamed, completed) = collect_tee(&[b"hello ", b"world"], 11).await;
+        assert_eq!(streamed, "hello world");
+        assert_eq!(completed.as_deref(), Some(&b"hello world"[..]));
+
+        let (streamed, completed) = collect_tee(&[b"hello ", b"world", b"!"], 11).await;
+        assert_eq!(streamed, "hello world!");
+        assert_eq!(completed, None);
+    }
+
+    /// Tests that a stale entry is revalidated using a conditional request and
+    /// that the not modified response is replaced with the refreshed entry
+    #[tokio::test]
+    async fn test_not_modified_revalidation() {
+        let cache = HttpCache::new(HttpCacheConfig::default()).unwrap();
+        cache.store(entry(
+            "http://127.0.0.1/file",
+            b"cached",
+            Duration::ZERO,
+            Some("\"v1\""),
+        ));
+
+        let mut game_request = request("/file");
+        assert!(matches!(
+            cache.on_request(&mut game_request).await,
+            RequestAction::Continue
+        ));
+        assert_eq!(game_request.headers()[header::IF_NONE_MATCH], "\"v1\"");
+
+        let info = request_info(game_request);
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+
+        cache.on_response(&info, &mut response).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "cached");
+
+        // Refreshed entry is fresh so its served without contacting the server
+        let mut game_request = request("/file");
+        assert!(matches!(
+            cache.on_request(&mut game_request).await,
+            RequestAction::Respond(_)
+        ));
+    }
+
+    /// Tests that a not modified response to the game's own conditional
+    /// request is passed through unchanged
+    #[tokio::test]
+    async fn test_not_modified_game_conditional() {
+        let cache = HttpCache::new(HttpCacheConfig::default()).unwrap();
+        cache.store(entry(
+            "http://127.0.0.1/file",
+            b"cached",
+            Duration::ZERO,
+            Some("\"v1\""),
+        ));
+
+        let mut game_request = request("/file");
+        game_request
+            .headers_mut()
+            .insert(header::IF_NONE_MATCH, "\"v1\"".parse().unwrap());
+        cache.on_request(&mut game_request).await;
+
+        let info = request_info(game_request);
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+
+        cache.on_response(&info, &mut response).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    /// Passes a response to the request through the cache and streams
+    /// the response body so that the response can be stored
+    async fn respond(cache: &HttpCache, request: Request<Body>, headers: &[(&str, &str)]) {
+        let info = request_info(request);
+        let mut response = Response::new(Body::from("body"));
+        for (name, value) in headers {
+            response.headers_mut().append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+
+        cache.on_response(&info, &mut response).await;
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+
+    /// Lists the names of the files in the cache directory
+    fn disk_files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Tests that responses setting cookies are never stored
+    #[tokio::test]
+    async fn test_set_cookie_not_stored() {
+        let cache = HttpCache::new(HttpCacheConfig::default()).unwrap();
+
+        respond(
+            &cache,
+            request("/file"),
+            &[("cache-control", "max-age=60"), ("set-cookie", "id=1")],
+        )
+        .await;
+        assert!(cache.memory.lock().entries.is_empty());
+
+        respond(&cache, request("/file"), &[("cache-control", "max-age=60")]).await;
+        assert_eq!(cache.memory.lock().entries.len(), 1);
+    }
+
+    /// Tests that responses to requests carrying credential headers are
+    /// only served to requests with the same credentials
+    #[tokio::test]
+    async fn test_credentials_not_shared() {
+        let cache = HttpCache::new(HttpCacheConfig::default()).unwrap();
+
+        let with_header = |name: header::HeaderName, value: &str| {
+            let mut request = request("/file");
+            request.headers_mut().insert(name, value.parse().unwrap());
+            request
+        };
+
+        respond(
+            &cache,
+            with_header(header::COOKIE, "id=1"),
+            &[("cache-control", "max-age=60")],
+        )
+        .await;
+
+        let mut matching = with_header(header::COOKIE, "id=1");
+        assert!(matches!(
+            cache.on_request(&mut matching).await,
+            RequestAction::Respond(_)
+        ));
+
+        for mut request in [
+            request("/file"),
+            with_header(header::COOKIE, "id=2"),
+            with_header(header::AUTHORIZATION, "id=1"),
+        ] {
+            assert!(matches!(
+                cache.on_request(&mut request).await,
+                RequestAction::Continue
+            ));
+        }
+    }
+
+    /// Tests that clearing the cache only removes the entry files from
+    /// the cache directory
+    #[tokio::test]
+    async fn test_clear_entry_files() {
+        let dir = test_dir("http-cache-clear");
+
+        let cache = HttpCache::new(HttpCacheConfig {
+            disk_dir: Some(dir.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (meta_path, body_path) = disk_paths(&dir, "http://127.0.0.1/file");
+        std::fs::write(&meta_path, b"{}").unwrap();
+        std::fs::write(&body_path, b"body").unwrap();
+        std::fs::write(dir.join("notes.json"), b"{}").unwrap();
+        std::fs::write(dir.join("other.txt"), b"other").unwrap();
+
+        cache.clear().unwrap();
+
+        assert_eq!(disk_files(&dir), ["notes.json", "other.txt"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that private responses and responses to requests carrying
+    /// credential headers are kept in memory but never written to disk
+    #[tokio::test]
+    async fn test_private_memory_only() {
+        let dir = std::env::temp_dir().join(format!("pocket-ark-http-cache-{}", process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+
+        let cache = HttpCache::new(HttpCacheConfig {
+            disk_dir: Some(dir.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        respond(
+            &cache,
+            request("/private"),
+            &[("cache-control", "private, max-age=60")],
+        )
+        .await;
+
+        for (name, value) in [
+            (header::AUTHORIZATION, "Bearer token"),
+            (header::COOKIE, "id=1"),
+        ] {
+            let mut request = request(&format!("/{}", name));
+            request.headers_mut().insert(name, value.parse().unwrap());
+            respond(&cache, request, &[("cache-control", "max-age=60")]).await;
+        }
+
+        respond(
+            &cache,
+            request("/public"),
+            &[("cache-control", "max-age=60")],
+        )
+        .await;
+
+        {
+            let memory = cache.memory.lock();
+            assert_eq!(memory.entries.len(), 4);
+            assert!(memory
+                .entries
+                .iter()
+                .all(|(key, (entry, _))| entry.meta.private != key.ends_with("/public")));
+        }
+
+        // Only the public entry is written to disk, waits for the background write
+        let (meta_path, body_path) = disk_paths(&dir, "http://127.0.0.1/public");
+        for _ in 0..100 {
+            if meta_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut expected: Vec<String> = [meta_path, body_path]
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        expected.sort();
+        assert_eq!(disk_files(&dir), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Middleware for the HTTP proxy server, allows client variants to patch
+//! the game HTTP requests locally such as injecting headers, rewriting
+//! responses from specific endpoints or blocking requests entirely
+//!
+//! Middleware is registered on the [`crate::ctx::ClientContext`]. Requests
+//! pass through the middleware in registration order before being proxied,
+//! responses and errors pass through the middleware in reverse order
+
+use crate::api::{ApiError, AuthToken};
+use futures::future::{ready, BoxFuture};
+use hyper::{http::Extensions, Body, HeaderMap, Method, Request, Response, Uri};
+use std::sync::Arc;
+use url::Url;
+
+/// Base URL of the server that a request is proxied to, inserted into
+/// the request extensions before the request reaches the middleware
+#[derive(Debug, Clone)]
+pub struct ServerUrl(pub Url);
+
+/// Authentication token that a request is proxied with, inserted into the
+/// request extensions alongside the [`ServerUrl`] when the client has
+/// authenticated. The token is added to the request after the middleware
+/// so responses to these requests may be specific to the account
+#[derive(Debug, Clone)]
+pub struct ProxyToken(pub AuthToken);
+
+/// Details of the request that a response or error belongs to,
+/// includes any changes made by the middleware
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    /// The request method
+    pub method: Method,
+    /// The request URI from the game
+    pub uri: Uri,
+    /// The request headers
+    pub headers: HeaderMap,
+    /// The request extensions, middleware can use these to carry
+    /// state from [`HttpMiddleware::on_request`] to the later hooks
+    pub extensions: Arc<Extensions>,
+}
+
+/// Action to take after a middleware has handled a request
+pub enum RequestAction {
+    /// Continue passing the request along to the next middleware
+    /// and then the server
+    Continue,
+    /// Respond to the game with a local response without contacting
+    /// the server, the remaining middleware is skipped
+    Respond(Response<Body>),
+}
+
+/// Middleware for the HTTP proxy server, every hook is optional
+pub trait HttpMiddleware: Send + Sync {
+    /// Inspects and modifies a request before it is proxied to the
+    /// server, can short-circuit the request with a local response
+    ///
+    /// ## Arguments
+    /// * `request` - The request from the game
+    fn on_request<'a>(&'a self, request: &'a mut Request<Body>) -> BoxFuture<'a, RequestAction> {
+        _ = request;
+        Box::pin(ready(RequestAction::Continue))
+    }
+
+    /// Inspects and modifies a response from the server before it
+    /// is sent to the game
+    ///
+    /// ## Arguments
+    /// * `request`  - The request the response is for
+    /// * `response` - The response from the server
+    fn on_response<'a>(
+        &'a self,
+        request: &'a RequestInfo,
+        response: &'a mut Response<Body>,
+    ) -> BoxFuture<'a, ()> {
+        _ = (request, response);
+        Box::pin(ready(()))
+    }
+
+    /// Handles a request that failed to be proxied, can provide a
+    /// response to send to the game instead of the error response
+    ///
+    /// ## Arguments
+    /// * `request` - The request that failed
+    /// * `error`   - The proxy error
+    fn on_error<'a>(
+        &'a self,
+        request: &'a RequestInfo,
+        error: &'a ApiError,
+    ) -> BoxFuture<'a, Option<Response<Body>>> {
+        _ = (request, error);
+        Box::pin(ready(None))
+    }
+}
@@ -1,72 +1,140 @@
 //! Different servers that the clients can spawn and manage
 
-use futures::Future;
-use parking_lot::Mutex;
-use tokio::task::AbortHandle;
+use self::{
+    supervisor::{ServerKind, ServerSupervisor},
+    tunnel_manager::TunnelManager,
+};
+use crate::{ctx::ClientContext, ssl::create_ssl_context};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
 
 pub mod blaze;
+pub mod blaze_frame;
 pub mod http;
+pub mod http_cache;
+pub mod http_middleware;
+pub mod preflight;
 pub mod qos;
 pub mod redirector;
+pub mod supervisor;
 pub mod tunnel;
+pub mod tunnel_manager;
 pub mod udp_tunnel;
 
-/// The port the Redirector server will bind to
+/// The default port the Redirector server will bind to
 pub const REDIRECTOR_PORT: u16 = 42230;
-/// The port the Blaze server will bind to
+/// The default port the Blaze server will bind to
 pub const BLAZE_PORT: u16 = 42128;
-/// The port the Quality of Service server will bind to
+/// The default port the Quality of Service server will bind to
 pub const QOS_PORT: u16 = 42130;
-/// The port the HTTP server will bind to
+/// The default port the HTTP server will bind to
 pub const HTTP_PORT: u16 = 443;
-/// The port used for the host socket
+/// The default port used for the host socket
 pub const TUNNEL_HOST_PORT: u16 = 42132;
 /// Port that the OS may choose
 pub const RANDOM_PORT: u16 = 0;
-/// Port that the game itself is on
+/// The default port that the game itself is on
 pub const GAME_HOST_PORT: u16 = 3659;
 
-// Shared set of abort handles to server tasks
-static SERVER_TASK_COLLECTION: Mutex<Vec<AbortHandle>> = Mutex::new(Vec::new());
+/// Configuration for the addresses the local servers bind to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the Redirector server will bind to
+    pub redirector: SocketAddr,
+    /// Address the Blaze server will bind to, this address is
+    /// advertised to the game by the Redirector server
+    pub blaze: SocketAddr,
+    /// Address the Quality of Service server will bind to
+    pub qos: SocketAddr,
+    /// Address the HTTP server will bind to
+    pub http: SocketAddr,
+    /// Address used for the tunnel host socket, the other tunnel
+    /// pool sockets bind to the same IP using an OS chosen port
+    pub tunnel_host: SocketAddr,
+    /// Address of the game itself that tunnel sockets send packets to
+    pub game_host: SocketAddr,
+}
 
-/// Returns whether there are running server tasks
-pub fn has_server_tasks() -> bool {
-    let values = &mut *SERVER_TASK_COLLECTION.lock();
-    !values.is_empty()
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            redirector: SocketAddr::from((Ipv4Addr::LOCALHOST, REDIRECTOR_PORT)),
+            blaze: SocketAddr::from((Ipv4Addr::LOCALHOST, BLAZE_PORT)),
+            qos: SocketAddr::from((Ipv4Addr::LOCALHOST, QOS_PORT)),
+            http: SocketAddr::from((Ipv4Addr::LOCALHOST, HTTP_PORT)),
+            tunnel_host: SocketAddr::from((Ipv4Addr::LOCALHOST, TUNNEL_HOST_PORT)),
+            game_host: SocketAddr::from((Ipv4Addr::LOCALHOST, GAME_HOST_PORT)),
+        }
+    }
 }
 
-/// Spawns a server related task future onto tokios runtime and
-/// adds the abort handle for the task to the task collection
-///
-/// ## Arguments
-/// * `task` - The task future to spawn
-#[inline]
-pub fn spawn_server_task<F>(task: F)
-where
-    F: Future<Output = ()> + Send + 'static,
-{
-    let handle = tokio::spawn(task);
-    add_server_task(handle.abort_handle());
+impl ServerConfig {
+    /// Address for a tunnel pool socket that isn't the host socket,
+    /// uses the tunnel host IP with an OS chosen port
+    pub fn tunnel_pool_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.tunnel_host.ip(), RANDOM_PORT)
+    }
 }
 
-/// Append an abort handle to the server task collection
+/// Handle to the full set of local servers started by [`start_all_servers`]
+pub struct LocalServers {
+    /// Supervisor for the individual servers
+    pub supervisor: ServerSupervisor,
+    /// Manager for the tunnel, provides the active tunnel transport
+    pub tunnel: TunnelManager,
+}
+
+/// Starts the full set of local servers using the provided context
+/// and configuration. The tunnel is started through a [`TunnelManager`]
+/// which prefers the UDP tunnel when the server provides a tunnel port
+/// and falls back to the HTTP upgrade tunnel
+///
+/// Must be called from within a tokio runtime
 ///
 /// ## Arguments
-/// * `handle` - The abort handle for the task
-pub fn add_server_task(handle: AbortHandle) {
-    let values = &mut *SERVER_TASK_COLLECTION.lock();
-    values.push(handle);
-}
+/// * `ctx`    - The client context
+/// * `config` - The server configuration
+pub fn start_all_servers(
+    ctx: Arc<ClientContext>,
+    config: Arc<ServerConfig>,
+) -> anyhow::Result<LocalServers> {
+    let ssl_context = create_ssl_context()?;
+    let supervisor = ServerSupervisor::new();
+    let tunnel = TunnelManager::new();
+
+    supervisor.start(ServerKind::Redirector, {
+        let ssl_context = ssl_context.clone();
+        let config = config.clone();
+        move |tasks| redirector::start_redirector_server(ssl_context.clone(), config.clone(), tasks)
+    });
+
+    supervisor.start(ServerKind::Blaze, {
+        let ctx = ctx.clone();
+        let config = config.clone();
+        move |tasks| blaze::start_blaze_server(ctx.clone(), config.clone(), tasks)
+    });
+
+    supervisor.start(ServerKind::Qos, {
+        let config = config.clone();
+        move |tasks| qos::start_qos_server(config.clone(), tasks)
+    });
+
+    supervisor.start(ServerKind::Http, {
+        let ctx = ctx.clone();
+        let config = config.clone();
+        move |tasks| {
+            http::start_http_server(ctx.clone(), ssl_context.clone(), config.clone(), tasks)
+        }
+    });
 
-/// Calls the abort handles for all tasks present
-/// in the task collection
-pub fn stop_server_tasks() {
-    // Take the current tasks list from the task collection
-    let values = {
-        let values = &mut *SERVER_TASK_COLLECTION.lock();
-        values.split_off(0)
-    };
+    supervisor.start(ServerKind::Tunnel, {
+        let tunnel = tunnel.clone();
+        move |tasks| tunnel.clone().run(ctx.clone(), config.clone(), tasks)
+    });
 
-    // Call abort on each of the handles
-    values.into_iter().for_each(|value| value.abort());
+    Ok(LocalServers { supervisor, tunnel })
 }
@@ -0,0 +1,382 @@
+//! Preflight checks for the local server ports, attempts to bind each of
+//! the required ports before the servers are started so that conflicts with
+//! other programs can be reported with more detail than the bind error
+//!
+//! On Linux the process holding a conflicting port is resolved using the
+//! socket tables in `/proc/net` and the file descriptors in `/proc/*/fd`
+
+use super::{supervisor::ServerKind, ServerConfig};
+use std::{
+    fmt::{Display, Formatter},
+    net::{SocketAddr, TcpListener, UdpSocket},
+};
+
+/// Transport protocol a port is bound with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortProtocol {
+    /// TCP port
+    Tcp,
+    /// UDP port
+    Udp,
+}
+
+impl Display for PortProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PortProtocol::Tcp => "TCP",
+            PortProtocol::Udp => "UDP",
+        })
+    }
+}
+
+/// Process that owns a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortOwner {
+    /// ID of the owning process
+    pub pid: u32,
+    /// Name of the owning process if it could be read
+    pub name: Option<String>,
+}
+
+/// Port required by a server that could not be bound
+#[derive(Debug, Clone)]
+pub struct PortConflict {
+    /// The server that requires the port
+    pub kind: ServerKind,
+    /// The protocol the port is bound with
+    pub protocol: PortProtocol,
+    /// The address that could not be bound
+    pub addr: SocketAddr,
+    /// The bind error message
+    pub error: String,
+    /// The process holding the port, only resolved on Linux
+    pub owner: Option<PortOwner>,
+}
+
+impl Display for PortConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} server cannot bind {} {}: {}",
+            self.kind, self.protocol, self.addr, self.error
+        )?;
+
+        if let Some(owner) = &self.owner {
+            match &owner.name {
+                Some(name) => write!(f, " (in use by {} pid {})", name, owner.pid)?,
+                None => write!(f, " (in use by pid {})", owner.pid)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Report produced by [`check_ports`]
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    /// Ports that could not be bound
+    pub conflicts: Vec<PortConflict>,
+}
+
+impl PreflightReport {
+    /// Whether all the required ports are available
+    pub fn is_ok(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Checks that each of the ports required by the local servers can be
+/// bound, the sockets are released again immediately after binding
+///
+/// ## Arguments
+/// * `config` - The server configuration to check
+pub fn check_ports(config: &ServerConfig) -> PreflightReport {
+    let required = [
+        (ServerKind::Redirector, PortProtocol::Tcp, config.redirector),
+        (ServerKind::Blaze, PortProtocol::Tcp, config.blaze),
+        (ServerKind::Qos, PortProtocol::Udp, config.qos),
+        (ServerKind::Http, PortProtocol::Tcp, config.http),
+        (ServerKind::Tunnel, PortProtocol::Udp, config.tunnel_host),
+    ];
+
+    let conflicts = required
+        .into_iter()
+        .filter_map(|(kind, protocol, addr)| {
+            let result = match protocol {
+                PortProtocol::Tcp => TcpListener::bind(addr).map(drop),
+                PortProtocol::Udp => UdpSocket::bind(addr).map(drop),
+            };
+
+            let error = result.err()?;
+
+            Some(PortConflict {
+                kind,
+                protocol,
+                addr,
+                error: error.to_string(),
+                owner: find_port_owner(protocol, addr),
+            })
+        })
+        .collect();
+
+    PreflightReport { conflicts }
+}
+
+/// Finds the process that owns the socket bound to the provided address
+///
+/// ## Arguments
+/// * `protocol` - The protocol of the socket
+/// * `addr`     - The address the socket is bound to
+#[cfg(target_os = "linux")]
+fn find_port_owner(protocol: PortProtocol, addr: SocketAddr) -> Option<PortOwner> {
+    let inode = linux::find_socket_inode(protocol, addr)?;
+    linux::find_inode_owner(inode)
+}
+
+/// Finds the process that owns the socket bound to the provided address,
+/// resolving the owner is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+fn find_port_owner(_protocol: PortProtocol, _addr: SocketAddr) -> Option<PortOwner> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{PortOwner, PortProtocol};
+    use std::{
+        fs::{read_dir, read_link, read_to_string},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    };
+
+    /// Socket state for a listening TCP socket
+    const TCP_LISTEN: &str = "0A";
+
+    /// Finds the inode of the socket bound to the provided address using
+    /// the socket tables in `/proc/net`
+    ///
+    /// ## Arguments
+    /// * `protocol` - The protocol of the socket
+    /// * `addr`     - The address the socket is bound to
+    pub fn find_socket_inode(protocol: PortProtocol, addr: SocketAddr) -> Option<u64> {
+        let tables: [&str; 2] = match protocol {
+            PortProtocol::Tcp => ["/proc/net/tcp", "/proc/net/tcp6"],
+            PortProtocol::Udp => ["/proc/net/udp", "/proc/net/udp6"],
+        };
+
+        tables
+            .into_iter()
+            .filter_map(|path| read_to_string(path).ok())
+            .find_map(|table| {
+                table
+                    .lines()
+                    // Skip the table header
+                    .skip(1)
+                    .find_map(|line| parse_socket_line(line, protocol, addr))
+            })
+    }
+
+    /// Parses a line from a `/proc/net` socket table returning the socket
+    /// inode if the socket is bound to an address that overlaps `addr`
+    ///
+    /// ## Arguments
+    /// * `line`     - The table line
+    /// * `protocol` - The protocol of the table
+    /// * `addr`     - The address to match
+    pub(super) fn parse_socket_line(
+        line: &str,
+        protocol: PortProtocol,
+        addr: SocketAddr,
+    ) -> Option<u64> {
+        // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local = fields.get(1)?;
+        let state = fields.get(3)?;
+        let inode: u64 = fields.get(9)?.parse().ok()?;
+
+        // Only listening TCP sockets hold the port
+        if protocol == PortProtocol::Tcp && *state != TCP_LISTEN {
+            return None;
+        }
+
+        let (ip, port) = local.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let ip = parse_hex_ip(ip)?;
+
+        let overlaps = ip.is_unspecified() || addr.ip().is_unspecified() || ip == addr.ip();
+
+        // Inode zero indicates the socket is no longer open
+        (port == addr.port() && overlaps && inode != 0).then_some(inode)
+    }
+
+    /// Parses an IP address from the hex encoding used by the socket tables,
+    /// the address is stored as 32bit words in host byte order
+    ///
+    /// ## Arguments
+    /// * `value` - The hex encoded address
+    pub(super) fn parse_hex_ip(value: &str) -> Option<IpAddr> {
+        // IPv4 addresses are a single word and IPv6 addresses are four words
+        if !matches!(value.len(), 8 | 32) {
+            return None;
+        }
+
+        let words = value
+            .as_bytes()
+            .chunks(8)
+            .map(|chunk| {
+                let chunk = std::str::from_utf8(chunk).ok()?;
+                u32::from_str_radix(chunk, 16).ok()
+            })
+            .collect::<Option<Vec<u32>>>()?;
+
+        let octets: Vec<u8> = words.into_iter().flat_map(u32::to_ne_bytes).collect();
+
+        match octets.len() {
+            4 => {
+                let octets: [u8; 4] = octets.try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            16 => {
+                let octets: [u8; 16] = octets.try_into().ok()?;
+                let ip = Ipv6Addr::from(octets);
+                // IPv4 mapped addresses are treated as IPv4
+                Some(match ip.to_ipv4_mapped() {
+                    Some(ip) => IpAddr::V4(ip),
+                    None => IpAddr::V6(ip),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the process holding a file descriptor for the socket inode
+    /// by searching the file descriptors in `/proc/*/fd`. Processes owned
+    /// by other users cannot be searched without elevated permissions
+    ///
+    /// ## Arguments
+    /// * `inode` - The socket inode
+    pub fn find_inode_owner(inode: u64) -> Option<PortOwner> {
+        let target = format!("socket:[{}]", inode);
+
+        read_dir("/proc")
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+            .find(|pid| {
+                let Ok(fds) = read_dir(format!("/proc/{}/fd", pid)) else {
+                    return false;
+                };
+
+                fds.filter_map(Result::ok).any(|fd| {
+                    read_link(fd.path()).is_ok_and(|link| link.as_os_str() == target.as_str())
+                })
+            })
+            .map(|pid| PortOwner {
+                pid,
+                name: read_to_string(format!("/proc/{}/comm", pid))
+                    .ok()
+                    .map(|name| name.trim_end().to_string()),
+            })
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_endian = "little"))]
+mod test {
+    use super::{linux::*, PortProtocol};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    /// Lines from `/proc/net/tcp`
+    const TCP_LINES: [&str; 3] = [
+        "   0: 00000000:A4D6 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 662 1 00000000680b8413 100 0 0 10 0",
+        "   1: 0100007F:A4D0 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 926 1 000000006201b732 100 0 0 10 0",
+        "   2: 0100007F:A4D0 0100007F:D5EA 01 00000000:00000000 00:00000000 00000000  1000        0 75395 2 00000000224e6731 20 4 0 18 -1",
+    ];
+
+    /// Lines from `/proc/net/udp` and `/proc/net/udp6`
+    const UDP_LINES: [&str; 3] = [
+        "  101: 0100007F:A4D4 00000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 41234 2 0000000000000000 0",
+        "  102: 00000000000000000000000001000000:0E43 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 41235 2 0000000000000000 0",
+        "  103: 0000000000000000FFFF00000100007F:A4D6 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 0 2 0000000000000000 0",
+    ];
+
+    /// Tests parsing the hex encoded addresses from the socket tables
+    #[test]
+    fn test_parse_hex_ip() {
+        let cases: [(&str, Option<IpAddr>); 7] = [
+            ("0100007F", Some(IpAddr::V4(Ipv4Addr::LOCALHOST))),
+            ("00000000", Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))),
+            ("0101A8C0", Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)))),
+            (
+                "00000000000000000000000001000000",
+                Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            ),
+            // IPv4 mapped addresses are treated as IPv4
+            (
+                "0000000000000000FFFF00000100007F",
+                Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ),
+            ("0100007", None),
+            ("ZZZZZZZZ", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse_hex_ip(value), expected, "{}", value);
+        }
+    }
+
+    /// Tests finding the socket inode holding a port from the socket table lines
+    #[test]
+    fn test_parse_socket_line() {
+        let localhost = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let cases: [(&str, PortProtocol, SocketAddr, Option<u64>); 9] = [
+            // Listening on all interfaces overlaps localhost
+            (TCP_LINES[0], PortProtocol::Tcp, localhost(42198), Some(662)),
+            (TCP_LINES[1], PortProtocol::Tcp, localhost(42192), Some(926)),
+            // Unspecified address to bind overlaps any address
+            (
+                TCP_LINES[1],
+                PortProtocol::Tcp,
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 42192)),
+                Some(926),
+            ),
+            (TCP_LINES[1], PortProtocol::Tcp, localhost(42193), None),
+            (
+                TCP_LINES[1],
+                PortProtocol::Tcp,
+                SocketAddr::from((Ipv4Addr::new(192, 168, 1, 1), 42192)),
+                None,
+            ),
+            // Established connections don't hold the port
+            (TCP_LINES[2], PortProtocol::Tcp, localhost(42192), None),
+            (
+                UDP_LINES[0],
+                PortProtocol::Udp,
+                localhost(42196),
+                Some(41234),
+            ),
+            (
+                UDP_LINES[1],
+                PortProtocol::Udp,
+                SocketAddr::from((Ipv6Addr::LOCALHOST, 3651)),
+                Some(41235),
+            ),
+            // Inode zero is a closed socket
+            (UDP_LINES[2], PortProtocol::Udp, localhost(42198), None),
+        ];
+
+        for (line, protocol, addr, expected) in cases {
+            assert_eq!(
+                parse_socket_line(line, protocol, addr),
+                expected,
+                "{}",
+                line
+            );
+        }
+
+        // Malformed lines are skipped
+        assert_eq!(
+            parse_socket_line("   0: 0100007F", PortProtocol::Tcp, localhost(42192)),
+            None
+        );
+    }
+}
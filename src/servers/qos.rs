@@ -2,20 +2,33 @@
 //! but it atleast allows clients to sometimes obtain the correct address, usually
 //! the server can fix it
 
-use super::{spawn_server_task, QOS_PORT};
+use super::{
+    supervisor::{ServerKind, ServerTasks},
+    ServerConfig,
+};
+use crate::events::{emit, emit_bind_result, ServerEvent};
 use log::debug;
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
     time::{Duration, SystemTime},
 };
-use tokio::{net::UdpSocket, sync::RwLock};
+use tokio::{net::UdpSocket, select, sync::RwLock};
 
 /// Starts the Quality Of Service server which handles providing the public
 /// address values to the clients that connect.
-pub async fn start_qos_server() -> std::io::Result<()> {
+///
+/// ## Arguments
+/// * `config` - The server configuration
+/// * `tasks`  - Collection to spawn message handling tasks into
+pub async fn start_qos_server(
+    config: Arc<ServerConfig>,
+    tasks: ServerTasks,
+) -> std::io::Result<()> {
+    let addr = config.qos;
+
     // Bind the local socket for accepting messages
-    let socket: UdpSocket = UdpSocket::bind((Ipv4Addr::LOCALHOST, QOS_PORT)).await?;
+    let socket: UdpSocket = emit_bind_result(ServerKind::Qos, addr, UdpSocket::bind(addr).await)?;
     let socket: Arc<UdpSocket> = Arc::new(socket);
 
     // Buffer for reading incoming messages
@@ -23,11 +36,15 @@ pub async fn start_qos_server() -> std::io::Result<()> {
 
     // Accept messages
     loop {
-        let (count, addr) = socket.recv_from(&mut buffer).await?;
+        let (count, addr) = select! {
+            result = socket.recv_from(&mut buffer) => result?,
+            // Stop accepting messages when shutting down
+            _ = tasks.shutdown_requested() => return Ok(()),
+        };
         // Create an array from the data that was recieved
         let buffer: Box<[u8]> = Box::from(&buffer[..count]);
 
-        spawn_server_task(handle(socket.clone(), addr, buffer));
+        tasks.spawn(handle(socket.clone(), addr, buffer));
     }
 }
 
@@ -48,6 +65,11 @@ async fn handle(socket: Arc<UdpSocket>, socket_addr: SocketAddr, buffer: Box<[u8
 
     debug!("QoS: From: {} Resolved: {}", socket_addr, address);
 
+    emit(ServerEvent::QosAddressResolved {
+        peer: socket_addr,
+        address,
+    });
+
     // Create buffer to store the output response
     let mut output = Vec::with_capacity(
         buffer.len() + 4 /* addr */ + 2 /* port */ + 4, /* padding */
@@ -1,8 +1,12 @@
 //! Pocket Ark version of winter15.gosredirector.ea.com, informs the game clients
 //! where the blaze server is located, in this case it always reports the
-//! servers as localhost
+//! locally configured Blaze server address
 
-use super::{spawn_server_task, BLAZE_PORT, REDIRECTOR_PORT};
+use super::{
+    supervisor::{ServerKind, ServerTasks},
+    ServerConfig,
+};
+use crate::events::emit_bind_result;
 use anyhow::Context;
 use hyper::{
     header::{self, HeaderName, HeaderValue},
@@ -12,27 +16,51 @@ use hyper::{
 };
 use log::error;
 use openssl::ssl::{Ssl, SslContext};
-use std::{convert::Infallible, net::Ipv4Addr, pin::Pin};
-use tokio::net::{TcpListener, TcpStream};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+};
 use tokio_openssl::SslStream;
+use tokio_util::sync::CancellationToken;
 
 /// Starts the redirector server
 ///
 /// ## Arguments
 /// * `context` - The SSL context to use when accepting clients
-pub async fn start_redirector_server(ssl_context: SslContext) -> std::io::Result<()> {
+/// * `config`  - The server configuration
+/// * `tasks`   - Collection to spawn connection tasks into
+pub async fn start_redirector_server(
+    ssl_context: SslContext,
+    config: Arc<ServerConfig>,
+    tasks: ServerTasks,
+) -> std::io::Result<()> {
+    let addr = config.redirector;
+    let blaze_addr = config.blaze;
+
     // Bind the local tcp socket for accepting connections
-    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, REDIRECTOR_PORT)).await?;
+    let listener = emit_bind_result(ServerKind::Redirector, addr, TcpListener::bind(addr).await)?;
 
     // Accept connections
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, _) = select! {
+            result = listener.accept() => result?,
+            // Stop accepting connections when shutting down
+            _ = tasks.shutdown_requested() => return Ok(()),
+        };
 
         let ssl = Ssl::new(&ssl_context).map_err(std::io::Error::other)?;
         let stream = SslStream::new(ssl, stream).map_err(std::io::Error::other)?;
 
-        spawn_server_task(async move {
-            if let Err(err) = serve_connection(stream).await {
+        let shutdown = tasks.shutdown_token();
+
+        tasks.spawn(async move {
+            if let Err(err) = serve_connection(stream, blaze_addr, shutdown).await {
                 error!("Error while redirecting: {}", err);
             }
         });
@@ -40,19 +68,42 @@ pub async fn start_redirector_server(ssl_context: SslContext) -> std::io::Result
 }
 
 /// Handles serving an HTTP connection the provided `stream`, also
-/// completes the accept stream process
-pub async fn serve_connection(mut stream: SslStream<TcpStream>) -> anyhow::Result<()> {
+/// completes the accept stream process. When `shutdown` is cancelled
+/// the connection finishes any in-flight requests before closing
+///
+/// ## Arguments
+/// * `stream`     - The stream to serve
+/// * `blaze_addr` - The Blaze server address to advertise
+/// * `shutdown`   - Token cancelled when the server is shutting down
+pub async fn serve_connection(
+    mut stream: SslStream<TcpStream>,
+    blaze_addr: SocketAddr,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
     Pin::new(&mut stream).accept().await?;
 
-    Http::new()
-        .serve_connection(stream, service_fn(handle_redirect))
-        .await
-        .context("Serve error")?;
+    let connection = Http::new().serve_connection(
+        stream,
+        service_fn(move |request| handle_redirect(request, blaze_addr)),
+    );
+    tokio::pin!(connection);
+
+    select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    }
+    .context("Serve error")?;
 
     Ok(())
 }
 
-async fn handle_redirect(req: Request<hyper::body::Body>) -> Result<Response<Body>, Infallible> {
+async fn handle_redirect(
+    req: Request<hyper::body::Body>,
+    blaze_addr: SocketAddr,
+) -> Result<Response<Body>, Infallible> {
     // Handle unexpected requests
     if req.uri().path() != "/redirector/getServerInstance" {
         let mut response = Response::new(hyper::body::Body::empty());
@@ -61,15 +112,27 @@ async fn handle_redirect(req: Request<hyper::body::Body>) -> Result<Response<Bod
         return Ok(response);
     }
 
-    let ip = u32::from_be_bytes([127, 0, 0, 1]);
-    let port = BLAZE_PORT;
+    // Advertise the bound address unless its not a specific IPv4 address
+    let ip_addr = match blaze_addr.ip() {
+        IpAddr::V4(addr) if !addr.is_unspecified() => addr,
+        _ => Ipv4Addr::LOCALHOST,
+    };
+
+    let hostname = if ip_addr.is_loopback() {
+        "localhost".to_string()
+    } else {
+        ip_addr.to_string()
+    };
+
+    let ip = u32::from(ip_addr);
+    let port = blaze_addr.port();
 
     let body = format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
     <serverinstanceinfo>
         <address member="0">
             <valu>
-                <hostname>localhost</hostname>
+                <hostname>{hostname}</hostname>
                 <ip>{ip}</ip>
                 <port>{port}</port>
             </valu>
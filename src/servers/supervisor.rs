@@ -0,0 +1,775 @@
+//! Supervisor for the local servers, owns a handle to each running server
+//! along with the connection tasks that the server has spawned so that
+//! servers can be individually queried, stopped and restarted
+//!
+//! Servers can either be stopped immediately by aborting their tasks or
+//! gracefully shutdown. Graceful shutdown signals the servers through their
+//! [`ServerTasks`] to stop accepting and finish their current work, servers
+//! that haven't finished once the timeout expires are then aborted
+
+use crate::events::{emit, ServerEvent};
+use futures::{future::join_all, Future};
+use log::{error, warn};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::Notify,
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+/// The different local servers that can be supervised
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ServerKind {
+    /// Redirector server
+    Redirector,
+    /// Blaze server
+    Blaze,
+    /// Quality of Service server
+    Qos,
+    /// HTTP proxy server
+    Http,
+    /// HTTP upgrade based tunnel
+    Tunnel,
+    /// UDP based tunnel
+    UdpTunnel,
+}
+
+impl ServerKind {
+    /// All the server kinds
+    pub const ALL: [ServerKind; 6] = [
+        ServerKind::Redirector,
+        ServerKind::Blaze,
+        ServerKind::Qos,
+        ServerKind::Http,
+        ServerKind::Tunnel,
+        ServerKind::UdpTunnel,
+    ];
+
+    /// Human readable name for the server
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerKind::Redirector => "Redirector",
+            ServerKind::Blaze => "Blaze",
+            ServerKind::Qos => "QoS",
+            ServerKind::Http => "HTTP",
+            ServerKind::Tunnel => "Tunnel",
+            ServerKind::UdpTunnel => "UDP Tunnel",
+        }
+    }
+}
+
+impl Display for ServerKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Current status of a supervised server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerStatus {
+    /// Server has not been started or was stopped
+    Stopped,
+    /// Server is currently running
+    Running,
+    /// Server future completed without an error
+    Finished,
+    /// Server future completed with an error
+    Failed(String),
+}
+
+impl ServerStatus {
+    /// Whether the status is [`ServerStatus::Running`]
+    pub fn is_running(&self) -> bool {
+        matches!(self, ServerStatus::Running)
+    }
+}
+
+/// Collection of the child tasks spawned by a server (i.e connection
+/// handlers). Tasks remove themselves from the collection once they
+/// complete
+///
+/// Also carries the shutdown signal for the server, servers and their
+/// tasks should stop accepting new work and finish up when a shutdown
+/// is requested
+///
+/// Servers can create child collections using [`ServerTasks::child`] for
+/// groups of tasks that need to be stopped independently of the server
+#[derive(Clone, Default)]
+pub struct ServerTasks {
+    inner: Arc<Mutex<ServerTasksInner>>,
+    /// Notified when the last running task completes
+    empty: Arc<Notify>,
+    /// Token cancelled when a graceful shutdown is requested
+    shutdown: CancellationToken,
+    /// Kind the server is currently running as, reported by the
+    /// supervisor instead of the kind it was started as
+    reported_kind: Arc<Mutex<Option<ServerKind>>>,
+    /// Collection this is a child of, tasks spawned in a child are also
+    /// tracked by the parent so stopping the server stops them too
+    parent: Option<Box<ServerTasks>>,
+}
+
+#[derive(Default)]
+struct ServerTasksInner {
+    /// ID to use for the next spawned task
+    next_id: u64,
+    /// IDs of tasks that have been spawned but haven't had
+    /// their abort handle stored yet
+    pending: HashSet<u64>,
+    /// Abort handles for the tasks that are still running
+    handles: HashMap<u64, AbortHandle>,
+}
+
+/// Guard moved into a spawned task to remove the task from
+/// the collection once it completes or is dropped
+struct ServerTaskGuard {
+    tasks: ServerTasks,
+    id: u64,
+}
+
+impl Drop for ServerTaskGuard {
+    fn drop(&mut self) {
+        let inner = &mut *self.tasks.inner.lock();
+
+        // Task may have completed before its handle was stored
+        if inner.handles.remove(&self.id).is_none() {
+            inner.pending.remove(&self.id);
+        }
+
+        if inner.handles.is_empty() && inner.pending.is_empty() {
+            self.tasks.empty.notify_waiters();
+        }
+    }
+}
+
+impl ServerTasks {
+    /// Spawns a server related task future onto tokios runtime and
+    /// tracks it within this collection until it completes
+    ///
+    /// ## Arguments
+    /// * `task` - The task future to spawn
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        // Task is tracked by this collection and all of its parents
+        let guards: Vec<ServerTaskGuard> =
+            std::iter::successors(Some(self), |tasks| tasks.parent.as_deref())
+                .map(|tasks| ServerTaskGuard {
+                    tasks: tasks.clone(),
+                    id: tasks.reserve_id(),
+                })
+                .collect();
+
+        let entries: Vec<(ServerTasks, u64)> = guards
+            .iter()
+            .map(|guard| (guard.tasks.clone(), guard.id))
+            .collect();
+
+        let handle = tokio::spawn(async move {
+            let _guards = guards;
+            task.await
+        });
+
+        for (tasks, id) in entries {
+            let inner = &mut *tasks.inner.lock();
+
+            // Only store the handle if the task hasn't already completed
+            if inner.pending.remove(&id) {
+                inner.handles.insert(id, handle.abort_handle());
+            }
+        }
+    }
+
+    /// Reserves the ID for a task that is about to be spawned
+    fn reserve_id(&self) -> u64 {
+        let inner = &mut *self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id = inner.next_id.wrapping_add(1);
+        inner.pending.insert(id);
+        id
+    }
+
+    /// Creates a child collection, tasks spawned in the child are also
+    /// tracked by this collection and the child is shutdown along with
+    /// this collection
+    pub fn child(&self) -> ServerTasks {
+        ServerTasks {
+            shutdown: self.shutdown.child_token(),
+            reported_kind: self.reported_kind.clone(),
+            parent: Some(Box::new(self.clone())),
+            ..Default::default()
+        }
+    }
+
+    /// Requests a graceful shutdown of the tasks, aborting any tasks that
+    /// don't finish within the `timeout`, and waits until all the tasks
+    /// have completed
+    ///
+    /// ## Arguments
+    /// * `timeout` - Maximum time to wait for the tasks to finish
+    pub async fn stop(&self, timeout: Duration) {
+        self.request_shutdown();
+
+        if tokio::time::timeout(timeout, self.wait_empty())
+            .await
+            .is_err()
+        {
+            self.abort_all();
+            self.wait_empty().await;
+        }
+    }
+
+    /// Sets the kind the server is currently running as, used by servers
+    /// that switch between implementations (i.e the tunnel transports)
+    ///
+    /// ## Arguments
+    /// * `kind` - The kind the server is running as
+    pub fn report_kind(&self, kind: ServerKind) {
+        *self.reported_kind.lock() = Some(kind);
+    }
+
+    /// Returns the number of tasks that are still running
+    pub fn len(&self) -> usize {
+        self.inner.lock().handles.len()
+    }
+
+    /// Returns whether there are no running tasks
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Calls the abort handles for all the running tasks
+    pub fn abort_all(&self) {
+        // Take the current handles so the lock isn't held while aborting,
+        // handles are left in place until the aborted tasks are dropped
+        let handles: Vec<AbortHandle> = {
+            let inner = &*self.inner.lock();
+            inner.handles.values().cloned().collect()
+        };
+
+        handles.into_iter().for_each(|handle| handle.abort());
+    }
+
+    /// Waits until all the tasks have completed
+    pub async fn wait_empty(&self) {
+        loop {
+            // Register for the notification before checking to not miss it
+            let notified = self.empty.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let inner = &*self.inner.lock();
+                if inner.handles.is_empty() && inner.pending.is_empty() {
+                    return;
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Returns a clone of the shutdown token, for passing to tasks that
+    /// need to know when a graceful shutdown is requested
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Waits until a graceful shutdown is requested
+    pub fn shutdown_requested(&self) -> WaitForCancellationFuture<'_> {
+        self.shutdown.cancelled()
+    }
+
+    /// Returns whether a graceful shutdown has been requested
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Requests a graceful shutdown of the server and its tasks
+    fn request_shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Boxed future produced when starting a server
+type ServerFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+
+/// Factory function used to start (and restart) a server
+type ServerFactory = Arc<dyn Fn(ServerTasks) -> ServerFuture + Send + Sync>;
+
+/// State of a single run of a server
+struct ServerRun {
+    /// Handle for the server task itself
+    handle: JoinHandle<()>,
+    /// Tasks spawned by this run of the server
+    tasks: ServerTasks,
+    /// Status of this run, updated by the server task on completion
+    status: Arc<Mutex<ServerStatus>>,
+}
+
+impl ServerRun {
+    /// Aborts the server task and its child tasks
+    fn abort(&self) {
+        self.handle.abort();
+        self.tasks.abort_all();
+    }
+}
+
+/// Entry for a server registered with the supervisor
+struct ServerEntry {
+    /// Factory for starting the server
+    factory: ServerFactory,
+    /// The current run of the server if it has been started
+    run: Option<ServerRun>,
+}
+
+impl ServerEntry {
+    /// Stops the current run of the server and its child tasks
+    fn stop(&mut self) -> bool {
+        let Some(run) = self.run.take() else {
+            return false;
+        };
+
+        run.abort();
+        true
+    }
+
+    /// Starts a new run of the server, the current run should
+    /// be stopped before calling this
+    fn start(&mut self, kind: ServerKind) {
+        let status = Arc::new(Mutex::new(ServerStatus::Running));
+        let tasks = ServerTasks::default();
+        let future = (self.factory)(tasks.clone());
+
+        let handle = tokio::spawn({
+            let status = status.clone();
+            async move {
+                let result = future.await;
+                if let Err(err) = &result {
+                    error!("{} server stopped with error: {}", kind, err);
+                    emit(ServerEvent::FatalError {
+                        kind,
+                        error: err.to_string(),
+                    });
+                }
+
+                *status.lock() = match result {
+                    Ok(_) => ServerStatus::Finished,
+                    Err(err) => ServerStatus::Failed(err.to_string()),
+                };
+            }
+        });
+
+        self.run = Some(ServerRun {
+            handle,
+            tasks,
+            status,
+        });
+    }
+
+    /// Obtains the current status of the server
+    fn status(&self) -> ServerStatus {
+        match &self.run {
+            Some(run) => run.status.lock().clone(),
+            None => ServerStatus::Stopped,
+        }
+    }
+}
+
+/// Snapshot of the state of a supervised server
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    /// The server kind, servers that switch between implementations
+    /// report the kind they are currently running as
+    pub kind: ServerKind,
+    /// The current server status
+    pub status: ServerStatus,
+    /// Number of child tasks (connections) the server has running
+    pub tasks: usize,
+}
+
+/// Supervisor that owns the handles for each of the local servers
+#[derive(Default)]
+pub struct ServerSupervisor {
+    servers: Mutex<HashMap<ServerKind, ServerEntry>>,
+}
+
+impl ServerSupervisor {
+    /// Creates a new supervisor without any servers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a server using the provided factory, the factory is stored
+    /// so that the server can later be restarted. If a server of the same
+    /// kind is already running it will be stopped first
+    ///
+    /// ## Arguments
+    /// * `kind`    - The kind of server being started
+    /// * `factory` - Function creating the server future from its task collection
+    pub fn start<F, Fut>(&self, kind: ServerKind, factory: F)
+    where
+        F: Fn(ServerTasks) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<()>> + Send + 'static,
+    {
+        let factory: ServerFactory = Arc::new(move |tasks| Box::pin(factory(tasks)));
+
+        let servers = &mut *self.servers.lock();
+        let entry = servers.entry(kind).or_insert_with(|| ServerEntry {
+            factory: factory.clone(),
+            run: None,
+        });
+
+        entry.stop();
+        entry.factory = factory;
+        entry.start(kind);
+    }
+
+    /// Stops the server of the provided kind along with all its
+    /// connection tasks. Returns whether the server was running
+    ///
+    /// ## Arguments
+    /// * `kind` - The kind of server to stop
+    pub fn stop(&self, kind: ServerKind) -> bool {
+        let servers = &mut *self.servers.lock();
+        servers.get_mut(&kind).is_some_and(ServerEntry::stop)
+    }
+
+    /// Restarts the server of the provided kind using the factory
+    /// it was originally started with. Returns false if the server
+    /// was never started
+    ///
+    /// ## Arguments
+    /// * `kind` - The kind of server to restart
+    pub fn restart(&self, kind: ServerKind) -> bool {
+        let servers = &mut *self.servers.lock();
+        let Some(entry) = servers.get_mut(&kind) else {
+            return false;
+        };
+
+        entry.stop();
+        entry.start(kind);
+        true
+    }
+
+    /// Stops all the servers and their connection tasks
+    pub fn stop_all(&self) {
+        let servers = &mut *self.servers.lock();
+        servers.values_mut().for_each(|entry| {
+            entry.stop();
+        });
+    }
+
+    /// Gracefully shuts down all the servers. Servers are signaled to stop
+    /// accepting connections and finish their in-flight work, any servers
+    /// or connection tasks still running once the `timeout` expires are
+    /// aborted
+    ///
+    /// ## Arguments
+    /// * `timeout` - Maximum time to wait for the servers to finish
+    pub async fn shutdown(&self, timeout: Duration) {
+        // Take the current runs signalling each to shutdown
+        let mut runs: Vec<ServerRun> = {
+            let servers = &mut *self.servers.lock();
+            servers
+                .values_mut()
+                .filter_map(|entry| entry.run.take())
+                .inspect(|run| run.tasks.request_shutdown())
+                .collect()
+        };
+
+        let drain = join_all(runs.iter_mut().map(|run| async {
+            _ = (&mut run.handle).await;
+            run.tasks.wait_empty().await;
+        }));
+
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            warn!("Servers did not shutdown in time, aborting remaining tasks");
+            runs.iter().for_each(ServerRun::abort);
+        }
+    }
+
+    /// Returns the status of the server of the provided kind, the kind
+    /// can be the kind the server was started as or the kind it reports
+    ///
+    /// ## Arguments
+    /// * `kind` - The kind of server
+    pub fn status(&self, kind: ServerKind) -> ServerStatus {
+        let servers = &*self.servers.lock();
+        find_entry(servers, kind)
+            .map(ServerEntry::status)
+            .unwrap_or(ServerStatus::Stopped)
+    }
+
+    /// Returns the number of running connection tasks for the server
+    /// of the provided kind, the kind can be the kind the server was
+    /// started as or the kind it reports
+    ///
+    /// ## Arguments
+    /// * `kind` - The kind of server
+    pub fn task_count(&self, kind: ServerKind) -> usize {
+        let servers = &*self.servers.lock();
+        find_entry(servers, kind)
+            .and_then(|entry| entry.run.as_ref())
+            .map(|run| run.tasks.len())
+            .unwrap_or_default()
+    }
+
+    /// Returns whether any of the servers are running
+    pub fn is_running(&self) -> bool {
+        let servers = &*self.servers.lock();
+        servers.values().any(|entry| entry.status().is_running())
+    }
+
+    /// Creates a snapshot of the state of all the servers that
+    /// have been started
+    pub fn snapshot(&self) -> Vec<ServerInfo> {
+        let servers = &*self.servers.lock();
+        ServerKind::ALL
+            .into_iter()
+            .filter_map(|kind| {
+                let entry = servers.get(&kind)?;
+                let run = entry.run.as_ref();
+                Some(ServerInfo {
+                    kind: run
+                        .and_then(|run| *run.tasks.reported_kind.lock())
+                        .unwrap_or(kind),
+                    status: entry.status(),
+                    tasks: run.map(|run| run.tasks.len()).unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Finds the entry for the server started as the provided kind, falling
+/// back to the running server reporting the kind
+///
+/// ## Arguments
+/// * `servers` - The supervised servers
+/// * `kind`    - The kind of server
+fn find_entry(
+    servers: &HashMap<ServerKind, ServerEntry>,
+    kind: ServerKind,
+) -> Option<&ServerEntry> {
+    servers.get(&kind).or_else(|| {
+        servers.values().find(|entry| {
+            entry
+                .run
+                .as_ref()
+                .is_some_and(|run| *run.tasks.reported_kind.lock() == Some(kind))
+        })
+    })
+}
+
+impl Drop for ServerSupervisor {
+    fn drop(&mut self) {
+        self.stop_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ServerKind, ServerStatus, ServerSupervisor, ServerTasks};
+    use std::{
+        future::pending,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{sync::oneshot, time::timeout};
+
+    /// Maximum time to wait for something that should happen promptly
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Tests that tasks are removed from the collection once they complete
+    #[tokio::test]
+    async fn test_finished_tasks_removed() {
+        let tasks = ServerTasks::default();
+        let (tx, rx) = oneshot::channel::<()>();
+
+        tasks.spawn(async move {
+            _ = rx.await;
+        });
+        assert_eq!(tasks.len(), 1);
+
+        tx.send(()).unwrap();
+        timeout(WAIT, tasks.wait_empty()).await.unwrap();
+        assert!(tasks.is_empty());
+
+        // Tasks that complete immediately must not be left behind
+        tasks.spawn(async {});
+        timeout(WAIT, tasks.wait_empty()).await.unwrap();
+        assert!(tasks.is_empty());
+    }
+
+    /// Tests that tasks spawned in a child collection are tracked by
+    /// the parent and that the shutdown signal reaches the child
+    #[tokio::test]
+    async fn test_child_propagation() {
+        let tasks = ServerTasks::default();
+        let child = tasks.child();
+
+        let token = child.shutdown_token();
+        child.spawn(async move { token.cancelled().await });
+
+        assert_eq!(child.len(), 1);
+        assert_eq!(tasks.len(), 1);
+
+        // Kind reported by the child is shared with the parent
+        child.report_kind(ServerKind::UdpTunnel);
+        assert_eq!(*tasks.reported_kind.lock(), Some(ServerKind::UdpTunnel));
+
+        timeout(WAIT, tasks.stop(WAIT)).await.unwrap();
+        assert!(child.is_shutting_down());
+        assert!(child.is_empty());
+        assert!(tasks.is_empty());
+
+        // Stopping a child doesn't shutdown the parent
+        let tasks = ServerTasks::default();
+        let child = tasks.child();
+        timeout(WAIT, child.stop(WAIT)).await.unwrap();
+        assert!(child.is_shutting_down());
+        assert!(!tasks.is_shutting_down());
+    }
+
+    /// Tests that stopping the tasks lets well behaved tasks finish and
+    /// aborts tasks that ignore the shutdown once the timeout expires
+    #[tokio::test]
+    async fn test_stop_aborts_stragglers() {
+        let tasks = ServerTasks::default();
+        let graceful = Arc::new(AtomicUsize::new(0));
+
+        {
+            let token = tasks.shutdown_token();
+            let graceful = graceful.clone();
+            tasks.spawn(async move {
+                token.cancelled().await;
+                graceful.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        tasks.spawn(pending());
+        assert_eq!(tasks.len(), 2);
+
+        timeout(WAIT, tasks.stop(Duration::from_millis(50)))
+            .await
+            .unwrap();
+
+        assert!(tasks.is_empty());
+        assert_eq!(graceful.load(Ordering::SeqCst), 1);
+    }
+
+    /// Tests starting, stopping and restarting a supervised server
+    /// along with the snapshot of its state
+    #[tokio::test]
+    async fn test_supervisor_lifecycle() {
+        let supervisor = ServerSupervisor::new();
+        let starts = Arc::new(AtomicUsize::new(0));
+
+        supervisor.start(ServerKind::Tunnel, {
+            let starts = starts.clone();
+            move |tasks: ServerTasks| {
+                starts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    tasks.spawn(pending());
+                    tasks.report_kind(ServerKind::UdpTunnel);
+                    pending::<std::io::Result<()>>().await
+                }
+            }
+        });
+
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        assert_eq!(supervisor.status(ServerKind::Tunnel), ServerStatus::Running);
+        assert_eq!(supervisor.status(ServerKind::Blaze), ServerStatus::Stopped);
+        assert!(supervisor.is_running());
+
+        // Wait for the server to spawn its connection task
+        timeout(WAIT, async {
+            while supervisor.task_count(ServerKind::Tunnel) == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let snapshot = supervisor.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].kind, ServerKind::UdpTunnel);
+        assert_eq!(snapshot[0].status, ServerStatus::Running);
+        assert_eq!(snapshot[0].tasks, 1);
+
+        // Server can be queried by the kind it reports
+        assert_eq!(
+            supervisor.status(ServerKind::UdpTunnel),
+            ServerStatus::Running
+        );
+        assert_eq!(supervisor.task_count(ServerKind::UdpTunnel), 1);
+
+        assert!(supervisor.stop(ServerKind::Tunnel));
+        assert!(!supervisor.stop(ServerKind::Tunnel));
+        assert_eq!(supervisor.status(ServerKind::Tunnel), ServerStatus::Stopped);
+        assert_eq!(supervisor.task_count(ServerKind::Tunnel), 0);
+        assert_eq!(
+            supervisor.status(ServerKind::UdpTunnel),
+            ServerStatus::Stopped
+        );
+        assert_eq!(supervisor.task_count(ServerKind::UdpTunnel), 0);
+        assert!(!supervisor.is_running());
+
+        // Restarting uses the original factory
+        assert!(supervisor.restart(ServerKind::Tunnel));
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(supervisor.status(ServerKind::Tunnel), ServerStatus::Running);
+
+        // Servers that were never started can't be restarted
+        assert!(!supervisor.restart(ServerKind::Qos));
+
+        timeout(WAIT, supervisor.shutdown(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        assert!(!supervisor.is_running());
+        assert_eq!(supervisor.snapshot()[0].status, ServerStatus::Stopped);
+    }
+
+    /// Tests that the status reflects how the server future completed
+    #[tokio::test]
+    async fn test_supervisor_completion_status() {
+        let supervisor = ServerSupervisor::new();
+
+        supervisor.start(ServerKind::Qos, |_| async { Ok(()) });
+        supervisor.start(ServerKind::Http, |_| async {
+            Err(std::io::Error::other("bind failed"))
+        });
+
+        timeout(WAIT, async {
+            while supervisor.is_running() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(supervisor.status(ServerKind::Qos), ServerStatus::Finished);
+        assert_eq!(
+            supervisor.status(ServerKind::Http),
+            ServerStatus::Failed("bind failed".to_string())
+        );
+
+        let kinds: Vec<ServerKind> = supervisor
+            .snapshot()
+            .into_iter()
+            .map(|info| info.kind)
+            .collect();
+        assert_eq!(kinds, [ServerKind::Qos, ServerKind::Http]);
+    }
+}
@@ -8,45 +8,51 @@
 
 use self::codec::{TunnelCodec, TunnelMessage};
 use crate::{
-    api::create_server_tunnel,
+    capture::capture_udp,
     ctx::ClientContext,
-    servers::{spawn_server_task, GAME_HOST_PORT, RANDOM_PORT, TUNNEL_HOST_PORT},
+    events::{emit, ServerEvent},
+    servers::{
+        supervisor::{ServerKind, ServerTasks},
+        ServerConfig,
+    },
+    stats::{self, TrafficCounters},
+    transport::ServerStream,
 };
 use bytes::Bytes;
-use futures::{Sink, Stream};
+use futures::{future::try_join_all, Sink, SinkExt, Stream};
 use log::{debug, error};
-use reqwest::Upgraded;
 use std::{
     future::Future,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{ready, Context, Poll},
     time::Duration,
 };
-use tokio::{io::ReadBuf, net::UdpSocket, sync::mpsc, try_join};
+use tokio::{io::ReadBuf, net::UdpSocket, select, sync::mpsc, time::sleep};
 use tokio_util::codec::Framed;
 
-/// The fixed size of socket pool to use
+/// Size of the socket pool to use, servers may report a smaller max pool size
 const SOCKET_POOL_SIZE: usize = 4;
 /// Max tunnel creation attempts that can be an error before cancelling
 const MAX_ERROR_ATTEMPTS: u8 = 5;
 
-// Local address the client uses to send packets
-static LOCAL_SEND_TARGET: SocketAddr =
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, GAME_HOST_PORT));
-
 /// Starts the tunnel socket pool and creates the tunnel
 /// connection to the server
 ///
 /// ## Arguments
-/// * `ctx` - The client context
-pub async fn start_tunnel_server(ctx: Arc<ClientContext>) -> std::io::Result<()> {
-    let association = match Option::as_ref(&ctx.association) {
-        Some(value) => value,
-        // Don't try and tunnel without a token
-        None => return Ok(()),
-    };
+/// * `ctx`    - The client context
+/// * `config` - The server configuration
+/// * `tasks`  - Collection to spawn the socket tasks into
+pub async fn start_tunnel_server(
+    ctx: Arc<ClientContext>,
+    config: Arc<ServerConfig>,
+    tasks: ServerTasks,
+) -> std::io::Result<()> {
+    // Don't try and tunnel without a token
+    if ctx.api.association().is_none() {
+        return Ok(());
+    }
 
     // Last encountered error
     let mut last_error: Option<std::io::Error> = None;
@@ -56,7 +62,7 @@ pub async fn start_tunnel_server(ctx: Arc<ClientContext>) -> std::io::Result<()>
     // Looping to attempt reconnecting if lost
     while attempt_errors < MAX_ERROR_ATTEMPTS {
         // Create the tunnel (Future will end if tunnel stopped)
-        let reconnect_time = if let Err(err) = create_tunnel(ctx.clone(), association).await {
+        let reconnect_time = if let Err(err) = create_tunnel(&ctx, &config, &tasks).await {
             error!("Failed to create tunnel: {}", err);
 
             // Set last error
@@ -75,13 +81,31 @@ pub async fn start_tunnel_server(ctx: Arc<ClientContext>) -> std::io::Result<()>
             Duration::from_millis(1000)
         };
 
+        // Tunnel was stopped due to the server shutting down
+        if tasks.is_shutting_down() {
+            return Ok(());
+        }
+
         debug!(
             "Next tunnel create attempt in: {}s",
             reconnect_time.as_secs()
         );
 
+        emit(ServerEvent::TunnelReconnecting {
+            kind: ServerKind::Tunnel,
+            attempt_errors,
+            delay: reconnect_time,
+            error: last_error
+                .as_ref()
+                .filter(|_| attempt_errors > 0)
+                .map(|err| err.to_string()),
+        });
+
         // Wait before attempting to re-create the tunnel
-        tokio::time::sleep(reconnect_time).await;
+        select! {
+            _ = sleep(reconnect_time) => {}
+            _ = tasks.shutdown_requested() => return Ok(()),
+        }
     }
 
     Err(last_error.unwrap_or(std::io::Error::other("Reached error connect limit")))
@@ -90,11 +114,18 @@ pub async fn start_tunnel_server(ctx: Arc<ClientContext>) -> std::io::Result<()>
 /// Creates a new tunnel
 ///
 /// ## Arguments
-/// * `http_client` - The HTTP client passed around for connection upgrades
-/// * `base_url`    - The server base URL to connect clients to
-async fn create_tunnel(ctx: Arc<ClientContext>, association: &str) -> std::io::Result<()> {
+/// * `ctx`    - The client context
+/// * `config` - The server configuration
+/// * `tasks`  - Collection to spawn the socket tasks into
+async fn create_tunnel(
+    ctx: &ClientContext,
+    config: &ServerConfig,
+    tasks: &ServerTasks,
+) -> std::io::Result<()> {
     // Create the tunnel with the server
-    let io = create_server_tunnel(&ctx.http_client, &ctx.base_url, association)
+    let io = ctx
+        .api
+        .create_server_tunnel()
         .await
         // Wrap the tunnel with the [`TunnelCodec`] framing
         .map(|io| Framed::new(io, TunnelCodec::default()))
@@ -102,19 +133,34 @@ async fn create_tunnel(ctx: Arc<ClientContext>, association: &str) -> std::io::R
         .map_err(std::io::Error::other)?;
     debug!("Created server tunnel");
 
+    emit(ServerEvent::TunnelConnected {
+        kind: ServerKind::Tunnel,
+        tunnel_id: None,
+    });
+
     // Allocate the socket pool for the tunnel
     let (tx, rx) = mpsc::unbounded_channel();
-    let pool = Socket::allocate_pool(tx).await?;
+    let pool_size = ctx.capabilities.tunnel_pool_size(SOCKET_POOL_SIZE);
+    let pool = Socket::allocate_pool(tx, pool_size, config, tasks).await?;
     debug!("Allocated tunnel pool");
 
-    // Start the tunnel
-    Tunnel {
+    let mut tunnel = Tunnel {
         io,
         rx,
         pool,
         write_state: Default::default(),
+    };
+
+    // Start the tunnel
+    select! {
+        _ = &mut tunnel => {}
+        _ = tasks.shutdown_requested() => {
+            debug!("Closing tunnel for shutdown");
+
+            // Flush any buffered messages and close the connection to the server
+            _ = tunnel.io.close().await;
+        }
     }
-    .await;
 
     Ok(())
 }
@@ -124,12 +170,12 @@ async fn create_tunnel(ctx: Arc<ClientContext>, association: &str) -> std::io::R
 struct Tunnel {
     /// Tunnel connection to the Pocket Relay server for sending [`TunnelMessage`]s
     /// through the server to reach a specific peer
-    io: Framed<Upgraded, TunnelCodec>,
+    io: Framed<ServerStream, TunnelCodec>,
     /// Receiver for receiving messages from [`Socket`]s within the [`Tunnel::pool`]
     /// that need to be sent through [`Tunnel::io`]
     rx: mpsc::UnboundedReceiver<TunnelMessage>,
     /// Pool of [`Socket`]s that this tunnel can use for sending out messages
-    pool: [SocketHandle; SOCKET_POOL_SIZE],
+    pool: Vec<SocketHandle>,
     /// Current state of writing [`TunnelMessage`]s to the [`Tunnel::io`]
     write_state: TunnelWriteState,
 }
@@ -263,7 +309,7 @@ impl Future for Tunnel {
 }
 
 /// Handle to a [`Socket`] for sending [`TunnelMessage`]s that the
-/// socket should send to the [`LOCAL_SEND_TARGET`]
+/// socket should send to the [`ServerConfig::game_host`]
 #[derive(Clone)]
 struct SocketHandle(mpsc::UnboundedSender<TunnelMessage>);
 
@@ -291,6 +337,14 @@ struct Socket {
     read_buffer: [u8; READ_BUFFER_LENGTH],
     /// Current state of writing [`TunnelMessage`]s to the `socket`
     write_state: SocketWriteState,
+    /// Traffic counters for the socket index
+    stats: Arc<TrafficCounters>,
+    /// Local address of the `socket`
+    local_addr: SocketAddr,
+    /// The local game address the `socket` sends to
+    target: SocketAddr,
+    /// Comment attached to captured packets to identify the socket
+    capture_comment: String,
 }
 
 /// Holds the state for the current writing progress for a [`Socket`]
@@ -318,18 +372,31 @@ impl Socket {
     ///
     /// ## Arguments
     /// * `tun_tx` - The tunnel sender for sending [`TunnelMessage`]s through the tunnel
+    /// * `size`   - The number of sockets in the pool
+    /// * `config` - The server configuration
+    /// * `tasks`  - Collection to spawn the socket tasks into
     async fn allocate_pool(
         tun_tx: mpsc::UnboundedSender<TunnelMessage>,
-    ) -> std::io::Result<[SocketHandle; SOCKET_POOL_SIZE]> {
-        let sockets = try_join!(
-            // Host socket index *must* use a fixed port since its used on the server side
-            Socket::start(0, TUNNEL_HOST_PORT, tun_tx.clone()),
-            // Other sockets can used OS auto assigned port
-            Socket::start(1, RANDOM_PORT, tun_tx.clone()),
-            Socket::start(2, RANDOM_PORT, tun_tx.clone()),
-            Socket::start(3, RANDOM_PORT, tun_tx),
-        )?;
-        Ok(sockets.into())
+        size: usize,
+        config: &ServerConfig,
+        tasks: &ServerTasks,
+    ) -> std::io::Result<Vec<SocketHandle>> {
+        let host_addr = config.tunnel_host;
+        let pool_addr = config.tunnel_pool_addr();
+        let target = config.game_host;
+
+        let sockets = (0..size).map(|index| {
+            let addr = match index {
+                // Host socket index *must* use a fixed port since its used on the server side
+                0 => host_addr,
+                // Other sockets can used OS auto assigned port
+                _ => pool_addr,
+            };
+
+            Socket::start(index as u8, addr, target, tun_tx.clone(), tasks)
+        });
+
+        try_join_all(sockets).await
     }
 
     /// Starts a new tunnel socket returning a [`SocketHandle`] that can be used
@@ -337,29 +404,39 @@ impl Socket {
     ///
     /// ## Arguments
     /// * `index`  - The index of the socket
-    /// * `port`   - The port to bind the socket on
+    /// * `addr`   - The address to bind the socket on
+    /// * `target` - The local game address to send packets to
     /// * `tun_tx` - The tunnel sender for sending [`TunnelMessage`]s through the tunnel
+    /// * `tasks`  - Collection to spawn the socket task into
     async fn start(
         index: u8,
-        port: u16,
+        addr: SocketAddr,
+        target: SocketAddr,
         tun_tx: mpsc::UnboundedSender<TunnelMessage>,
+        tasks: &ServerTasks,
     ) -> std::io::Result<SocketHandle> {
         // Bind the socket
-        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let socket = UdpSocket::bind(addr).await?;
         // Set the socket send target
-        socket.connect(LOCAL_SEND_TARGET).await?;
+        socket.connect(target).await?;
+
+        let local_addr = socket.local_addr()?;
 
         // Create the message channel
         let (tx, rx) = mpsc::unbounded_channel();
 
         // Spawn the socket task
-        spawn_server_task(Socket {
+        tasks.spawn(Socket {
             index,
             socket,
             rx,
             tun_tx,
             read_buffer: [0; READ_BUFFER_LENGTH],
             write_state: Default::default(),
+            stats: stats::tunnel_socket(ServerKind::Tunnel, index),
+            local_addr,
+            target,
+            capture_comment: format!("Tunnel socket {}", index),
         });
 
         Ok(SocketHandle(tx))
@@ -376,6 +453,13 @@ impl Socket {
                 let result = ready!(Pin::new(&mut self.rx).poll_recv(cx));
 
                 if let Some(message) = result {
+                    self.stats.record_from_server(message.message.len());
+                    capture_udp(
+                        self.local_addr,
+                        self.target,
+                        &message.message,
+                        &self.capture_comment,
+                    );
                     SocketWriteState::Write(message.message)
                 } else {
                     // All writers have closed, tunnel must be closed (Future end)
@@ -385,6 +469,7 @@ impl Socket {
             SocketWriteState::Write(message) => {
                 // Try send the message to the local target
                 let Ok(count) = ready!(self.socket.poll_send(cx, message)) else {
+                    self.stats.record_error();
                     return Poll::Ready(SocketWriteState::Stop);
                 };
 
@@ -413,11 +498,14 @@ impl Socket {
 
         // Try receive a message from the socket
         if ready!(self.socket.poll_recv(cx, &mut read_buf)).is_err() {
+            self.stats.record_error();
             return Poll::Ready(SocketReadState::Stop);
         }
 
         // Get the received message
         let bytes = read_buf.filled();
+        self.stats.record_to_server(bytes.len());
+        capture_udp(self.target, self.local_addr, bytes, &self.capture_comment);
         let message = Bytes::copy_from_slice(bytes);
         let message = TunnelMessage {
             index: self.index,
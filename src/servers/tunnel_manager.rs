@@ -0,0 +1,542 @@
+//! Tunnel manager
+//!
+//! Selects between the UDP tunnel and the HTTP upgrade tunnel. The UDP tunnel
+//! is preferred when the server provides a tunnel port, persistent failures
+//! (i.e a firewall dropping UDP) will fall back to the HTTP tunnel while the
+//! UDP tunnel is periodically probed to check whether it has become usable
+//! again. Transports that the server reports as unsupported in its
+//! capabilities are never used
+
+use crate::{
+    ctx::ClientContext,
+    events::{emit, ServerEvent},
+    servers::{
+        supervisor::{ServerKind, ServerTasks},
+        tunnel::start_tunnel_server,
+        udp_tunnel::{probe_tunnel, run_tunnel},
+        ServerConfig,
+    },
+};
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    select,
+    time::{interval_at, sleep, Instant, MissedTickBehavior},
+};
+
+/// Number of consecutive UDP failures before falling back to the HTTP tunnel
+const UDP_FALLBACK_ATTEMPTS: u8 = 3;
+/// Interval between probing the UDP tunnel while the HTTP tunnel is active
+const UDP_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximum time to wait for the HTTP tunnel socket pool to stop before its
+/// tasks are aborted, the pool must release the local ports before the UDP
+/// tunnel binds them
+const POOL_RELEASE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Delay before restarting the fallback HTTP tunnel once it has reached
+/// its own error limit
+const HTTP_RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Transport used by the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TunnelTransport {
+    /// UDP tunnel
+    Udp,
+    /// HTTP upgrade tunnel
+    Http,
+}
+
+/// Manages the tunnel connection, clones of the manager share the
+/// same state so a clone can be kept to query the active transport
+#[derive(Clone, Default)]
+pub struct TunnelManager {
+    /// The currently active transport
+    active: Arc<Mutex<Option<TunnelTransport>>>,
+}
+
+/// Details required for connecting the UDP tunnel
+struct UdpTarget {
+    /// The tunnel server host
+    host: String,
+    /// The tunnel server port
+    port: u16,
+    /// The client association token
+    association: String,
+}
+
+/// Guard that clears the active transport when the manager
+/// stops running or is aborted
+struct ActiveGuard<'a>(&'a TunnelManager);
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set_active(None);
+    }
+}
+
+impl TunnelManager {
+    /// Creates a new tunnel manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the currently active tunnel transport, [None] if
+    /// the tunnel is not running
+    pub fn active_transport(&self) -> Option<TunnelTransport> {
+        *self.active.lock()
+    }
+
+    /// Updates the active transport emitting an event if it changed
+    ///
+    /// ## Arguments
+    /// * `transport` - The new active transport
+    fn set_active(&self, transport: Option<TunnelTransport>) {
+        let previous = std::mem::replace(&mut *self.active.lock(), transport);
+        if previous != transport {
+            emit(ServerEvent::TunnelTransportChanged { transport });
+        }
+    }
+
+    /// Updates the active transport and the server kind that the
+    /// supervisor reports the tunnel as
+    ///
+    /// ## Arguments
+    /// * `transport` - The new active transport
+    /// * `tasks`     - The tunnel task collection
+    fn activate(&self, transport: TunnelTransport, tasks: &ServerTasks) {
+        tasks.report_kind(match transport {
+            TunnelTransport::Udp => ServerKind::UdpTunnel,
+            TunnelTransport::Http => ServerKind::Tunnel,
+        });
+        self.set_active(Some(transport));
+    }
+
+    /// Runs the tunnel, using the UDP tunnel when the server provides
+    /// a tunnel port and falling back to the HTTP tunnel otherwise. Only
+    /// the transports supported by the server are used
+    ///
+    /// ## Arguments
+    /// * `ctx`    - The client context
+    /// * `config` - The server configuration
+    /// * `tasks`  - Collection to spawn the socket tasks into
+    pub async fn run(
+        self,
+        ctx: Arc<ClientContext>,
+        config: Arc<ServerConfig>,
+        tasks: ServerTasks,
+    ) -> std::io::Result<()> {
+        let _guard = ActiveGuard(&self);
+
+        let udp_supported = ctx.capabilities.supports_tunnel(TunnelTransport::Udp);
+        let http_supported = ctx.capabilities.supports_tunnel(TunnelTransport::Http);
+
+        let target = match (
+            ctx.tunnel_port.filter(|_| udp_supported),
+            ctx.api.base_url().host_str(),
+            ctx.api.association(),
+        ) {
+            (Some(port), Some(host), Some(association)) => UdpTarget {
+                host: host.to_string(),
+                port,
+                association: association.to_string(),
+            },
+            // Neither tunnel can be used with this server
+            _ if !http_supported => {
+                warn!("Server does not support any usable tunnel transport");
+                return Ok(());
+            }
+            // UDP tunnel isn't available only the HTTP tunnel can be used
+            _ => {
+                self.activate(TunnelTransport::Http, &tasks);
+                return start_tunnel_server(ctx, config, tasks).await;
+            }
+        };
+
+        // Connection obtained by a successful probe while using the HTTP tunnel
+        let mut probed: Option<(UdpSocket, u32)> = None;
+
+        loop {
+            self.run_udp(&target, &ctx, &config, &tasks, probed.take())
+                .await;
+
+            // UDP tunnel was stopped due to the server shutting down
+            if tasks.is_shutting_down() {
+                return Ok(());
+            }
+
+            // Server doesn't support the HTTP tunnel, wait for the UDP tunnel instead
+            if !http_supported {
+                warn!("UDP tunnel is unavailable and the server does not support the HTTP tunnel");
+                self.set_active(None);
+
+                select! {
+                    connection = probe_udp(&target) => {
+                        debug!("UDP tunnel probe succeeded, restarting UDP tunnel");
+                        probed = Some(connection);
+                    }
+                    _ = tasks.shutdown_requested() => return Ok(()),
+                }
+
+                continue;
+            }
+
+            warn!("UDP tunnel is unavailable, falling back to HTTP tunnel");
+            self.activate(TunnelTransport::Http, &tasks);
+
+            // HTTP tunnel sockets are spawned into a child collection so they
+            // can be stopped without stopping the tunnel server itself
+            let http_tasks = tasks.child();
+
+            select! {
+                // HTTP tunnel only stops once a shutdown is requested
+                _ = run_http_fallback(&ctx, &config, &http_tasks) => return Ok(()),
+                // UDP tunnel has become usable again
+                connection = probe_udp(&target) => {
+                    debug!("UDP tunnel probe succeeded, switching back to UDP tunnel");
+                    probed = Some(connection);
+                }
+            }
+
+            // Wait for the HTTP tunnel sockets to release the local ports
+            http_tasks.stop(POOL_RELEASE_TIMEOUT).await;
+        }
+    }
+
+    /// Runs the UDP tunnel until it has persistently failed or a shutdown
+    /// was requested. Errors never stop the tunnel server, once enough
+    /// attempts have failed the caller falls back to the HTTP tunnel
+    ///
+    /// ## Arguments
+    /// * `target`  - The UDP tunnel target
+    /// * `ctx`     - The client context
+    /// * `config`  - The server configuration
+    /// * `tasks`   - Collection to spawn the socket tasks into
+    /// * `initial` - Already connected tunnel to use for the first attempt
+    async fn run_udp(
+        &self,
+        target: &UdpTarget,
+        ctx: &ClientContext,
+        config: &ServerConfig,
+        tasks: &ServerTasks,
+        mut initial: Option<(UdpSocket, u32)>,
+    ) {
+        // Number of attempts that errored
+        let mut attempt_errors: u8 = 0;
+
+        loop {
+            let connection = match initial.take() {
+                Some(value) => Ok(value),
+                None => probe_tunnel(&target.host, target.port, &target.association).await,
+            };
+
+            let result = match connection {
+                Ok((socket, tunnel_id)) => {
+                    self.activate(TunnelTransport::Udp, tasks);
+                    run_tunnel(socket, tunnel_id, &ctx.capabilities, config, tasks).await
+                }
+                Err(err) => Err(err),
+            };
+
+            let error = match result {
+                Ok(_) => {
+                    attempt_errors = 0;
+                    None
+                }
+                Err(err) => {
+                    error!("UDP tunnel failed: {}", err);
+                    attempt_errors += 1;
+                    Some(err.to_string())
+                }
+            };
+
+            // Tunnel was stopped due to the server shutting down
+            if tasks.is_shutting_down() {
+                return;
+            }
+
+            if attempt_errors >= UDP_FALLBACK_ATTEMPTS {
+                return;
+            }
+
+            // Error should be delayed by the number of errors already hit
+            let reconnect_time = Duration::from_millis(1000 * (attempt_errors as u64).max(1));
+
+            debug!(
+                "Next tunnel create attempt in: {}s",
+                reconnect_time.as_secs()
+            );
+
+            emit(ServerEvent::TunnelReconnecting {
+                kind: ServerKind::UdpTunnel,
+                attempt_errors,
+                delay: reconnect_time,
+                error,
+            });
+
+            select! {
+                _ = sleep(reconnect_time) => {}
+                _ = tasks.shutdown_requested() => return,
+            }
+        }
+    }
+}
+
+/// Runs the HTTP tunnel while falling back from the UDP tunnel. When the
+/// HTTP tunnel reaches its own error limit its restarted after a delay
+/// rather than stopping, so that the UDP tunnel continues to be probed
+/// through temporary server outages. Only returns once a shutdown is
+/// requested
+///
+/// ## Arguments
+/// * `ctx`    - The client context
+/// * `config` - The server configuration
+/// * `tasks`  - Collection to spawn the socket tasks into
+async fn run_http_fallback(
+    ctx: &Arc<ClientContext>,
+    config: &Arc<ServerConfig>,
+    tasks: &ServerTasks,
+) {
+    loop {
+        if let Err(err) = start_tunnel_server(ctx.clone(), config.clone(), tasks.clone()).await {
+            error!("HTTP tunnel failed: {}", err);
+        }
+
+        // Tunnel was stopped due to the server shutting down
+        if tasks.is_shutting_down() {
+            return;
+        }
+
+        debug!(
+            "Restarting HTTP tunnel in: {}s",
+            HTTP_RESTART_DELAY.as_secs()
+        );
+
+        select! {
+            _ = sleep(HTTP_RESTART_DELAY) => {}
+            _ = tasks.shutdown_requested() => return,
+        }
+    }
+}
+
+/// Periodically probes the UDP tunnel until a probe succeeds
+///
+/// ## Arguments
+/// * `target` - The UDP tunnel target
+async fn probe_udp(target: &UdpTarget) -> (UdpSocket, u32) {
+    let mut interval = interval_at(Instant::now() + UDP_PROBE_INTERVAL, UDP_PROBE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match probe_tunnel(&target.host, target.port, &target.association).await {
+            Ok(value) => return value,
+            Err(err) => debug!("UDP tunnel probe failed: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TunnelManager, TunnelTransport, UDP_FALLBACK_ATTEMPTS};
+    use crate::{
+        api::{create_http_client, HttpClientConfig, PocketArkApi, ServerCapabilities},
+        ctx::ClientContext,
+        events::{subscribe, ServerEvent},
+        servers::{
+            supervisor::{ServerKind, ServerTasks},
+            ServerConfig,
+        },
+    };
+    use pocket_relay_udp_tunnel::{deserialize_message, serialize_message, TunnelMessage};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        net::{TcpListener, UdpSocket},
+        time::timeout,
+    };
+    use url::Url;
+
+    /// Starts a fake UDP tunnel server that answers the first `failures`
+    /// handshakes with an unexpected packet and accepts the handshakes after
+    /// that. Returns the server port, the number of handshakes received and
+    /// the number of failures which can be changed while the server runs
+    async fn start_udp_server(failures: usize) -> (u16, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let handshakes = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(failures));
+
+        tokio::spawn({
+            let handshakes = handshakes.clone();
+            let failures = failures.clone();
+            async move {
+                let mut buffer = [0u8; u16::MAX as usize];
+                while let Ok((count, addr)) = socket.recv_from(&mut buffer).await {
+                    let Ok(packet) = deserialize_message(&buffer[..count]) else {
+                        continue;
+                    };
+                    if !matches!(packet.message, TunnelMessage::Initiate { .. }) {
+                        continue;
+                    }
+
+                    let message = if handshakes.fetch_add(1, Ordering::SeqCst)
+                        < failures.load(Ordering::SeqCst)
+                    {
+                        TunnelMessage::KeepAlive
+                    } else {
+                        TunnelMessage::Initiated { tunnel_id: 7 }
+                    };
+
+                    _ = socket.send_to(&serialize_message(7, &message), addr).await;
+                }
+            }
+        });
+
+        (port, handshakes, failures)
+    }
+
+    /// Creates a context for the HTTP server on `http_port` with the UDP
+    /// tunnel server on `udp_port`
+    fn context(http_port: u16, udp_port: u16) -> Arc<ClientContext> {
+        let base_url = Url::parse(&format!("http://127.0.0.1:{http_port}/")).unwrap();
+        let api = PocketArkApi::new(
+            create_http_client(HttpClientConfig::default()).unwrap(),
+            base_url,
+            Some("association".to_string()),
+        );
+        Arc::new(ClientContext {
+            api,
+            tunnel_port: Some(udp_port),
+            capabilities: ServerCapabilities::default(),
+            http_middleware: Vec::new(),
+        })
+    }
+
+    /// Server configuration binding the tunnel sockets to free ports
+    fn config() -> Arc<ServerConfig> {
+        let local = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        Arc::new(ServerConfig {
+            tunnel_host: local,
+            game_host: local,
+            ..Default::default()
+        })
+    }
+
+    /// Waits for the next change of the active tunnel transport
+    async fn next_transport(
+        rx: &mut tokio::sync::broadcast::Receiver<ServerEvent>,
+    ) -> Option<TunnelTransport> {
+        loop {
+            if let ServerEvent::TunnelTransportChanged { transport } = rx.recv().await.unwrap() {
+                return transport;
+            }
+        }
+    }
+
+    /// Tests that failing UDP tunnel handshakes fall back to the HTTP tunnel
+    /// without stopping the tunnel server, and that the UDP tunnel is used
+    /// again once a later probe succeeds
+    #[tokio::test(start_paused = true)]
+    async fn test_udp_fallback_and_reprobe() {
+        let (udp_port, handshakes, _) = start_udp_server(UDP_FALLBACK_ATTEMPTS as usize).await;
+
+        // HTTP tunnel server that never responds keeps the HTTP tunnel
+        // waiting on its upgrade for the duration of the test
+        let http_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let http_port = http_listener.local_addr().unwrap().port();
+
+        let mut rx = subscribe();
+        let manager = TunnelManager::new();
+        let tasks = ServerTasks::default();
+        let handle = tokio::spawn(manager.clone().run(
+            context(http_port, udp_port),
+            config(),
+            tasks.clone(),
+        ));
+
+        // Handshakes failing with protocol errors fall back to HTTP
+        assert_eq!(next_transport(&mut rx).await, Some(TunnelTransport::Http));
+        assert_eq!(
+            handshakes.load(Ordering::SeqCst),
+            UDP_FALLBACK_ATTEMPTS as usize
+        );
+        assert!(!handle.is_finished());
+
+        // Probe succeeds and the UDP tunnel becomes active again
+        assert_eq!(next_transport(&mut rx).await, Some(TunnelTransport::Udp));
+        assert_eq!(
+            handshakes.load(Ordering::SeqCst),
+            UDP_FALLBACK_ATTEMPTS as usize + 1
+        );
+        assert_eq!(manager.active_transport(), Some(TunnelTransport::Udp));
+
+        tasks.stop(super::POOL_RELEASE_TIMEOUT).await;
+        handle.await.unwrap().unwrap();
+        assert_eq!(manager.active_transport(), None);
+        drop(http_listener);
+    }
+
+    /// Tests that the fallback HTTP tunnel reaching its own error limit is
+    /// restarted rather than stopping the tunnel server, and that the UDP
+    /// tunnel is still probed and used once it becomes available again
+    #[tokio::test(start_paused = true)]
+    async fn test_http_fallback_failure() {
+        // UDP tunnel is unavailable until the failures are cleared
+        let (udp_port, _, failures) = start_udp_server(usize::MAX).await;
+
+        // HTTP tunnel server that refuses connections
+        let http_listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let http_port = http_listener.local_addr().unwrap().port();
+        drop(http_listener);
+
+        let mut rx = subscribe();
+        let manager = TunnelManager::new();
+        let tasks = ServerTasks::default();
+        let handle = tokio::spawn(manager.clone().run(
+            context(http_port, udp_port),
+            config(),
+            tasks.clone(),
+        ));
+
+        assert_eq!(next_transport(&mut rx).await, Some(TunnelTransport::Http));
+
+        // Wait for the HTTP tunnel to reach its error limit and restart,
+        // restarting resets the number of errors without a success
+        let restarted = async {
+            let mut previous_errors = 0;
+            loop {
+                if let ServerEvent::TunnelReconnecting {
+                    kind: ServerKind::Tunnel,
+                    attempt_errors,
+                    ..
+                } = rx.recv().await.unwrap()
+                {
+                    if attempt_errors == 1 && previous_errors > 1 {
+                        break;
+                    }
+                    previous_errors = attempt_errors;
+                }
+            }
+        };
+        timeout(Duration::from_secs(600), restarted).await.unwrap();
+        assert!(!handle.is_finished());
+        assert_eq!(manager.active_transport(), Some(TunnelTransport::Http));
+
+        // UDP tunnel is probed and used again once it becomes available
+        failures.store(0, Ordering::SeqCst);
+        assert_eq!(next_transport(&mut rx).await, Some(TunnelTransport::Udp));
+
+        tasks.stop(super::POOL_RELEASE_TIMEOUT).await;
+        handle.await.unwrap().unwrap();
+        assert_eq!(manager.active_transport(), None);
+    }
+}
@@ -5,16 +5,24 @@
 //! faced when trying to connect. This is the faster UDP implementation
 
 use crate::{
+    api::ServerCapabilities,
+    capture::capture_udp,
     ctx::ClientContext,
-    servers::{spawn_server_task, GAME_HOST_PORT, RANDOM_PORT, TUNNEL_HOST_PORT},
+    events::{emit, ServerEvent},
+    servers::{
+        supervisor::{ServerKind, ServerTasks},
+        ServerConfig,
+    },
+    stats::{self, TrafficCounters},
 };
+use futures::future::try_join_all;
 use log::{debug, error};
 use pocket_relay_udp_tunnel::{
     deserialize_message, serialize_message, MessageError, TunnelMessage,
 };
 use std::{
     future::Future,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{Ipv4Addr, SocketAddr},
     pin::Pin,
     sync::Arc,
     task::{ready, Context, Poll},
@@ -24,20 +32,16 @@ use thiserror::Error;
 use tokio::{
     io::ReadBuf,
     net::UdpSocket,
+    select,
     sync::mpsc,
     time::{interval_at, sleep, timeout, Instant, Interval, MissedTickBehavior},
-    try_join,
 };
 
-/// The fixed size of socket pool to use
+/// Size of the socket pool to use, servers may report a smaller max pool size
 const SOCKET_POOL_SIZE: usize = 4;
 /// Max tunnel creation attempts that can be an error before cancelling
 const MAX_ERROR_ATTEMPTS: u8 = 5;
 
-// Local address the client uses to send packets
-static LOCAL_SEND_TARGET: SocketAddr =
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, GAME_HOST_PORT));
-
 /// Errors that can occur while creating a UDP tunnel
 #[derive(Debug, Error)]
 pub enum UdpTunnelError {
@@ -87,17 +91,21 @@ pub enum UdpTunnelError {
 /// ## Arguments
 /// * `ctx`         - The client context
 /// * `tunnel_port` - The UDP tunnel server port to connect to
+/// * `config`      - The server configuration
+/// * `tasks`       - Collection to spawn the socket tasks into
 pub async fn start_udp_tunnel_server(
     ctx: Arc<ClientContext>,
     tunnel_port: u16,
+    config: Arc<ServerConfig>,
+    tasks: ServerTasks,
 ) -> std::io::Result<()> {
-    let host = match ctx.base_url.host() {
+    let host = match ctx.api.base_url().host() {
         Some(value) => value.to_string(),
         // Cannot form a tunnel without a host
         None => return Ok(()),
     };
 
-    let association = match Option::as_ref(&ctx.association) {
+    let association = match ctx.api.association() {
         Some(value) => value,
         // Don't try and tunnel without a token
         None => return Ok(()),
@@ -111,7 +119,15 @@ pub async fn start_udp_tunnel_server(
     // Looping to attempt reconnecting if lost
     while attempt_errors < MAX_ERROR_ATTEMPTS {
         // Create the tunnel (Future will end if tunnel stopped)
-        let reconnect_time = if let Err(err) = create_tunnel(&host, tunnel_port, association).await
+        let reconnect_time = if let Err(err) = create_tunnel(
+            &host,
+            tunnel_port,
+            association,
+            &ctx.capabilities,
+            &config,
+            &tasks,
+        )
+        .await
         {
             error!("Failed to create tunnel: {}", err);
 
@@ -131,13 +147,31 @@ pub async fn start_udp_tunnel_server(
             Duration::from_millis(1000)
         };
 
+        // Tunnel was stopped due to the server shutting down
+        if tasks.is_shutting_down() {
+            return Ok(());
+        }
+
         debug!(
             "Next tunnel create attempt in: {}s",
             reconnect_time.as_secs()
         );
 
+        emit(ServerEvent::TunnelReconnecting {
+            kind: ServerKind::UdpTunnel,
+            attempt_errors,
+            delay: reconnect_time,
+            error: last_error
+                .as_ref()
+                .filter(|_| attempt_errors > 0)
+                .map(|err| err.to_string()),
+        });
+
         // Wait before attempting to re-create the tunnel
-        tokio::time::sleep(reconnect_time).await;
+        select! {
+            _ = sleep(reconnect_time) => {}
+            _ = tasks.shutdown_requested() => return Ok(()),
+        }
     }
 
     Err(last_error
@@ -150,12 +184,31 @@ pub async fn start_udp_tunnel_server(
 /// ## Arguments
 /// * `host`        - The host for connecting the tunnel
 /// * `tunnel_port` - The port the tunnel is running on
-/// * `association` - The client association token
+/// * `association`  - The client association token
+/// * `capabilities` - The server capabilities
+/// * `config`       - The server configuration
+/// * `tasks`        - Collection to spawn the socket tasks into
 async fn create_tunnel(
     host: &str,
     tunnel_port: u16,
     association: &str,
+    capabilities: &ServerCapabilities,
+    config: &ServerConfig,
+    tasks: &ServerTasks,
 ) -> Result<(), UdpTunnelError> {
+    let socket = connect_tunnel_socket(host, tunnel_port).await?;
+
+    let tunnel_id = attempt_tunnel_handshake(&socket, association).await?;
+
+    run_tunnel(socket, tunnel_id, capabilities, config, tasks).await
+}
+
+/// Binds a local socket and connects it to the remote tunnel server
+///
+/// ## Arguments
+/// * `host`        - The host for connecting the tunnel
+/// * `tunnel_port` - The port the tunnel is running on
+async fn connect_tunnel_socket(host: &str, tunnel_port: u16) -> Result<UdpSocket, UdpTunnelError> {
     // Bind a local udp socket
     let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
         .await
@@ -169,13 +222,58 @@ async fn create_tunnel(
 
     debug!("initiating tunnel: {}:{}", host, tunnel_port);
 
-    let tunnel_id = attempt_tunnel_handshake(&socket, association).await?;
+    Ok(socket)
+}
 
+/// Probes whether the UDP tunnel is usable by attempting a single
+/// handshake with the server, provides the connected socket and
+/// tunnel ID on success which can be passed to [`run_tunnel`]
+///
+/// ## Arguments
+/// * `host`        - The host for connecting the tunnel
+/// * `tunnel_port` - The port the tunnel is running on
+/// * `association` - The client association token
+pub(crate) async fn probe_tunnel(
+    host: &str,
+    tunnel_port: u16,
+    association: &str,
+) -> Result<(UdpSocket, u32), UdpTunnelError> {
+    let socket = connect_tunnel_socket(host, tunnel_port).await?;
+
+    let tunnel_id = timeout(HANDSHAKE_TIMEOUT, handshake_tunnel(&socket, association))
+        .await
+        .map_err(|_| UdpTunnelError::HandshakeTimeout)??;
+
+    Ok((socket, tunnel_id))
+}
+
+/// Runs a tunnel that has completed its handshake, allocates the
+/// socket pool and forwards messages until the tunnel stops
+///
+/// ## Arguments
+/// * `socket`       - The socket connected to the tunnel server
+/// * `tunnel_id`    - The tunnel ID obtained from the handshake
+/// * `capabilities` - The server capabilities
+/// * `config`       - The server configuration
+/// * `tasks`        - Collection to spawn the socket tasks into
+pub(crate) async fn run_tunnel(
+    socket: UdpSocket,
+    tunnel_id: u32,
+    capabilities: &ServerCapabilities,
+    config: &ServerConfig,
+    tasks: &ServerTasks,
+) -> Result<(), UdpTunnelError> {
     debug!("created server tunnel: {}", tunnel_id);
 
+    emit(ServerEvent::TunnelConnected {
+        kind: ServerKind::UdpTunnel,
+        tunnel_id: Some(tunnel_id),
+    });
+
     // Allocate the socket pool for the tunnel
     let (tx, rx) = mpsc::unbounded_channel();
-    let pool = Socket::allocate_pool(tx)
+    let pool_size = capabilities.tunnel_pool_size(SOCKET_POOL_SIZE);
+    let pool = Socket::allocate_pool(tx, pool_size, config, tasks)
         .await
         .map_err(UdpTunnelError::AllocateSocketPool)?;
     debug!("Allocated tunnel pool");
@@ -188,8 +286,7 @@ async fn create_tunnel(
 
     keep_alive_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-    // Start the tunnel
-    Tunnel {
+    let mut tunnel = Tunnel {
         socket,
         tunnel_id,
         rx,
@@ -198,8 +295,14 @@ async fn create_tunnel(
         read_buffer: [0u8; u16::MAX as usize],
         last_keep_alive: now,
         keep_alive_interval,
+    };
+
+    // Start the tunnel, dropping the tunnel on shutdown closes the
+    // socket and the socket pool
+    select! {
+        _ = &mut tunnel => {}
+        _ = tasks.shutdown_requested() => debug!("Closing tunnel for shutdown"),
     }
-    .await;
 
     Ok(())
 }
@@ -297,7 +400,7 @@ struct Tunnel {
     /// that need to be sent through [`Tunnel::io`]
     rx: mpsc::UnboundedReceiver<TunnelMessage>,
     /// Pool of [`Socket`]s that this tunnel can use for sending out messages
-    pool: [SocketHandle; SOCKET_POOL_SIZE],
+    pool: Vec<SocketHandle>,
     /// Current state of writing [`TunnelMessage`]s to the [`Tunnel::io`]
     write_state: TunnelWriteState,
     /// Buffer for reading
@@ -485,7 +588,7 @@ impl Future for Tunnel {
 }
 
 /// Handle to a [`Socket`] for sending [`TunnelMessage`]s that the
-/// socket should send to the [`LOCAL_SEND_TARGET`]
+/// socket should send to the [`ServerConfig::game_host`]
 #[derive(Clone)]
 struct SocketHandle(mpsc::UnboundedSender<Vec<u8>>);
 
@@ -513,6 +616,14 @@ struct Socket {
     read_buffer: [u8; READ_BUFFER_LENGTH],
     /// Current state of writing [`TunnelMessage`]s to the `socket`
     write_state: SocketWriteState,
+    /// Traffic counters for the socket index
+    stats: Arc<TrafficCounters>,
+    /// Local address of the `socket`
+    local_addr: SocketAddr,
+    /// The local game address the `socket` sends to
+    target: SocketAddr,
+    /// Comment attached to captured packets to identify the socket
+    capture_comment: String,
 }
 
 /// Holds the state for the current writing progress for a [`Socket`]
@@ -540,18 +651,31 @@ impl Socket {
     ///
     /// ## Arguments
     /// * `tun_tx` - The tunnel sender for sending [`TunnelMessage`]s through the tunnel
+    /// * `size`   - The number of sockets in the pool
+    /// * `config` - The server configuration
+    /// * `tasks`  - Collection to spawn the socket tasks into
     async fn allocate_pool(
         tun_tx: mpsc::UnboundedSender<TunnelMessage>,
-    ) -> std::io::Result<[SocketHandle; SOCKET_POOL_SIZE]> {
-        let sockets = try_join!(
-            // Host socket index *must* use a fixed port since its used on the server side
-            Socket::start(0, TUNNEL_HOST_PORT, tun_tx.clone()),
-            // Other sockets can used OS auto assigned port
-            Socket::start(1, RANDOM_PORT, tun_tx.clone()),
-            Socket::start(2, RANDOM_PORT, tun_tx.clone()),
-            Socket::start(3, RANDOM_PORT, tun_tx),
-        )?;
-        Ok(sockets.into())
+        size: usize,
+        config: &ServerConfig,
+        tasks: &ServerTasks,
+    ) -> std::io::Result<Vec<SocketHandle>> {
+        let host_addr = config.tunnel_host;
+        let pool_addr = config.tunnel_pool_addr();
+        let target = config.game_host;
+
+        let sockets = (0..size).map(|index| {
+            let addr = match index {
+                // Host socket index *must* use a fixed port since its used on the server side
+                0 => host_addr,
+                // Other sockets can used OS auto assigned port
+                _ => pool_addr,
+            };
+
+            Socket::start(index as u8, addr, target, tun_tx.clone(), tasks)
+        });
+
+        try_join_all(sockets).await
     }
 
     /// Starts a new tunnel socket returning a [`SocketHandle`] that can be used
@@ -559,29 +683,39 @@ impl Socket {
     ///
     /// ## Arguments
     /// * `index`  - The index of the socket
-    /// * `port`   - The port to bind the socket on
+    /// * `addr`   - The address to bind the socket on
+    /// * `target` - The local game address to send packets to
     /// * `tun_tx` - The tunnel sender for sending [`TunnelMessage`]s through the tunnel
+    /// * `tasks`  - Collection to spawn the socket task into
     async fn start(
         index: u8,
-        port: u16,
+        addr: SocketAddr,
+        target: SocketAddr,
         tun_tx: mpsc::UnboundedSender<TunnelMessage>,
+        tasks: &ServerTasks,
     ) -> std::io::Result<SocketHandle> {
         // Bind the socket
-        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let socket = UdpSocket::bind(addr).await?;
         // Set the socket send target
-        socket.connect(LOCAL_SEND_TARGET).await?;
+        socket.connect(target).await?;
+
+        let local_addr = socket.local_addr()?;
 
         // Create the message channel
         let (tx, rx) = mpsc::unbounded_channel();
 
         // Spawn the socket task
-        spawn_server_task(Socket {
+        tasks.spawn(Socket {
             index,
             socket,
             rx,
             tun_tx,
             read_buffer: [0; READ_BUFFER_LENGTH],
             write_state: Default::default(),
+            stats: stats::tunnel_socket(ServerKind::UdpTunnel, index),
+            local_addr,
+            target,
+            capture_comment: format!("UDP tunnel socket {}", index),
         });
 
         Ok(SocketHandle(tx))
@@ -598,6 +732,13 @@ impl Socket {
                 let result = ready!(Pin::new(&mut self.rx).poll_recv(cx));
 
                 if let Some(message) = result {
+                    self.stats.record_from_server(message.len());
+                    capture_udp(
+                        self.local_addr,
+                        self.target,
+                        &message,
+                        &self.capture_comment,
+                    );
                     SocketWriteState::Write(message)
                 } else {
                     // All writers have closed, tunnel must be closed (Future end)
@@ -607,6 +748,7 @@ impl Socket {
             SocketWriteState::Write(message) => {
                 // Try send the message to the local target
                 let Ok(count) = ready!(self.socket.poll_send(cx, message)) else {
+                    self.stats.record_error();
                     return Poll::Ready(SocketWriteState::Stop);
                 };
 
@@ -635,11 +777,14 @@ impl Socket {
 
         // Try receive a message from the socket
         if ready!(self.socket.poll_recv(cx, &mut read_buf)).is_err() {
+            self.stats.record_error();
             return Poll::Ready(SocketReadState::Stop);
         }
 
         // Get the received message
         let bytes = read_buf.filled();
+        self.stats.record_to_server(bytes.len());
+        capture_udp(self.target, self.local_addr, bytes, &self.capture_comment);
         let message = TunnelMessage::Forward {
             index: self.index,
             message: bytes.to_vec(),
@@ -0,0 +1,453 @@
+//! Traffic accounting for the local servers, tracks the bytes and packets
+//! flowing in each direction for the Blaze connections, proxied HTTP requests
+//! and tunnel pool sockets so that connection issues can be diagnosed
+//!
+//! Directions are relative to the local game client, traffic "to server" is
+//! traffic from the game heading to the Pocket Relay server and traffic
+//! "from server" is traffic from the Pocket Relay server heading to the game
+
+use crate::servers::supervisor::ServerKind;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of closed connections and completed requests that are
+/// kept for inclusion in snapshots
+const RECENT_LIMIT: usize = 32;
+
+/// Registry for the stats, created on first use
+static STATS: OnceLock<Mutex<StatsRegistry>> = OnceLock::new();
+
+/// Counters for traffic in each direction
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+    bytes_to_server: AtomicU64,
+    bytes_from_server: AtomicU64,
+    packets_to_server: AtomicU64,
+    packets_from_server: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl TrafficCounters {
+    /// Records a packet sent towards the server
+    ///
+    /// ## Arguments
+    /// * `bytes` - The length of the packet
+    pub(crate) fn record_to_server(&self, bytes: usize) {
+        self.bytes_to_server
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_to_server.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a packet received from the server
+    ///
+    /// ## Arguments
+    /// * `bytes` - The length of the packet
+    pub(crate) fn record_from_server(&self, bytes: usize) {
+        self.bytes_from_server
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_from_server.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an error
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Resets all the counters to zero
+    fn clear(&self) {
+        self.bytes_to_server.store(0, Ordering::Relaxed);
+        self.bytes_from_server.store(0, Ordering::Relaxed);
+        self.packets_to_server.store(0, Ordering::Relaxed);
+        self.packets_from_server.store(0, Ordering::Relaxed);
+        self.errors.store(0, Ordering::Relaxed);
+    }
+
+    /// Reads the current counter values
+    pub(crate) fn load(&self) -> TrafficStats {
+        TrafficStats {
+            bytes_to_server: self.bytes_to_server.load(Ordering::Relaxed),
+            bytes_from_server: self.bytes_from_server.load(Ordering::Relaxed),
+            packets_to_server: self.packets_to_server.load(Ordering::Relaxed),
+            packets_from_server: self.packets_from_server.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Traffic totals in each direction. For stream based connections such
+/// as Blaze each read from the stream is counted as a packet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Bytes sent from the game towards the server
+    pub bytes_to_server: u64,
+    /// Bytes sent from the server towards the game
+    pub bytes_from_server: u64,
+    /// Packets sent from the game towards the server
+    pub packets_to_server: u64,
+    /// Packets sent from the server towards the game
+    pub packets_from_server: u64,
+    /// Number of errors encountered
+    pub errors: u64,
+}
+
+impl TrafficStats {
+    /// Adds the values from `other` onto these stats
+    fn add(&mut self, other: &TrafficStats) {
+        self.bytes_to_server += other.bytes_to_server;
+        self.bytes_from_server += other.bytes_from_server;
+        self.packets_to_server += other.packets_to_server;
+        self.packets_from_server += other.packets_from_server;
+        self.errors += other.errors;
+    }
+}
+
+/// Stats for a single Blaze connection
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    /// Unique ID of the connection
+    pub id: u64,
+    /// Address of the game client
+    pub peer: SocketAddr,
+    /// Traffic for the connection
+    pub traffic: TrafficStats,
+    /// How long the connection has been open, or was open for
+    /// if the connection is closed
+    pub duration: Duration,
+    /// Whether the connection has been closed
+    pub closed: bool,
+    /// Error message if the connection closed due to an error
+    pub error: Option<String>,
+}
+
+/// Stats for a single proxied HTTP request
+#[derive(Debug, Clone)]
+pub struct HttpRequestStats {
+    /// The request method
+    pub method: String,
+    /// The request path and query
+    pub path: String,
+    /// The response status code, [None] if the request failed
+    pub status: Option<u16>,
+    /// Traffic for the request, each body chunk is counted as a packet
+    pub traffic: TrafficStats,
+    /// Time taken to complete the request including streaming the bodies
+    pub duration: Duration,
+}
+
+/// Totals across all the connections or requests of a type
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AggregateStats {
+    /// Number of connections or requests
+    pub count: u64,
+    /// Total traffic
+    pub traffic: TrafficStats,
+    /// Total duration
+    pub duration: Duration,
+}
+
+/// Stats for a tunnel pool socket, the counters are kept across
+/// reconnects of the tunnel
+#[derive(Debug, Clone)]
+pub struct TunnelSocketStats {
+    /// The tunnel the socket belongs to
+    pub kind: ServerKind,
+    /// Index of the socket within the pool
+    pub index: u8,
+    /// Traffic for the socket
+    pub traffic: TrafficStats,
+}
+
+/// Snapshot of the current stats
+#[derive(Debug, Clone, Default)]
+pub struct StatsSnapshot {
+    /// Currently open Blaze connections
+    pub blaze_active: Vec<ConnectionStats>,
+    /// Most recently closed Blaze connections, oldest first
+    pub blaze_recent: Vec<ConnectionStats>,
+    /// Totals for closed Blaze connections
+    pub blaze_totals: AggregateStats,
+    /// Most recently proxied HTTP requests, oldest first
+    pub http_recent: Vec<HttpRequestStats>,
+    /// Totals for proxied HTTP requests
+    pub http_totals: AggregateStats,
+    /// Tunnel pool sockets ordered by tunnel then index
+    pub tunnel_sockets: Vec<TunnelSocketStats>,
+}
+
+/// Open Blaze connection within the registry
+struct ActiveConnection {
+    peer: SocketAddr,
+    started: Instant,
+    counters: Arc<TrafficCounters>,
+}
+
+/// Registry storing the stats
+#[derive(Default)]
+struct StatsRegistry {
+    next_connection_id: u64,
+    blaze_active: HashMap<u64, ActiveConnection>,
+    blaze_recent: VecDeque<ConnectionStats>,
+    blaze_totals: AggregateStats,
+    http_recent: VecDeque<HttpRequestStats>,
+    http_totals: AggregateStats,
+    tunnel_sockets: BTreeMap<(ServerKind, u8), Arc<TrafficCounters>>,
+}
+
+/// Obtains the stats registry, creating it if it doesn't exist
+fn registry() -> &'static Mutex<StatsRegistry> {
+    STATS.get_or_init(Default::default)
+}
+
+/// Pushes a value onto a recent list removing the oldest value
+/// if the list is full
+fn push_recent<T>(list: &mut VecDeque<T>, value: T) {
+    if list.len() >= RECENT_LIMIT {
+        list.pop_front();
+    }
+    list.push_back(value);
+}
+
+/// Creates a snapshot of the current stats
+pub fn snapshot() -> StatsSnapshot {
+    let registry = registry().lock();
+
+    let mut blaze_active: Vec<ConnectionStats> = registry
+        .blaze_active
+        .iter()
+        .map(|(id, connection)| ConnectionStats {
+            id: *id,
+            peer: connection.peer,
+            traffic: connection.counters.load(),
+            duration: connection.started.elapsed(),
+            closed: false,
+            error: None,
+        })
+        .collect();
+    blaze_active.sort_by_key(|connection| connection.id);
+
+    StatsSnapshot {
+        blaze_active,
+        blaze_recent: registry.blaze_recent.iter().cloned().collect(),
+        blaze_totals: registry.blaze_totals,
+        http_recent: registry.http_recent.iter().cloned().collect(),
+        http_totals: registry.http_totals,
+        tunnel_sockets: registry
+            .tunnel_sockets
+            .iter()
+            .map(|((kind, index), counters)| TunnelSocketStats {
+                kind: *kind,
+                index: *index,
+                traffic: counters.load(),
+            })
+            .collect(),
+    }
+}
+
+/// Clears all the stats, open connections and tunnel sockets
+/// are kept but have their counters reset
+pub fn reset() {
+    let registry = &mut *registry().lock();
+
+    for connection in registry.blaze_active.values() {
+        connection.counters.clear();
+    }
+    for counters in registry.tunnel_sockets.values() {
+        counters.clear();
+    }
+
+    registry.blaze_recent.clear();
+    registry.blaze_totals = Default::default();
+    registry.http_recent.clear();
+    registry.http_totals = Default::default();
+}
+
+/// Handle to the stats for an open Blaze connection, the connection
+/// is moved to the closed connections when the handle is finished or
+/// dropped
+pub(crate) struct ConnectionStatsHandle {
+    id: u64,
+    counters: Arc<TrafficCounters>,
+    finished: bool,
+}
+
+impl ConnectionStatsHandle {
+    /// Counters for the connection traffic
+    pub(crate) fn counters(&self) -> &TrafficCounters {
+        &self.counters
+    }
+
+    /// Marks the connection as closed
+    ///
+    /// ## Arguments
+    /// * `error` - Error message if the connection closed due to an error
+    pub(crate) fn finish(mut self, error: Option<String>) {
+        self.close(error);
+    }
+
+    fn close(&mut self, error: Option<String>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        if error.is_some() {
+            self.counters.record_error();
+        }
+
+        let registry = &mut *registry().lock();
+        let Some(connection) = registry.blaze_active.remove(&self.id) else {
+            return;
+        };
+
+        let stats = ConnectionStats {
+            id: self.id,
+            peer: connection.peer,
+            traffic: connection.counters.load(),
+            duration: connection.started.elapsed(),
+            closed: true,
+            error,
+        };
+
+        registry.blaze_totals.count += 1;
+        registry.blaze_totals.traffic.add(&stats.traffic);
+        registry.blaze_totals.duration += stats.duration;
+
+        push_recent(&mut registry.blaze_recent, stats);
+    }
+}
+
+impl Drop for ConnectionStatsHandle {
+    fn drop(&mut self) {
+        self.close(None);
+    }
+}
+
+/// Registers a new open Blaze connection
+///
+/// ## Arguments
+/// * `peer` - Address of the game client
+pub(crate) fn blaze_connection(peer: SocketAddr) -> ConnectionStatsHandle {
+    let registry = &mut *registry().lock();
+
+    let id = registry.next_connection_id;
+    registry.next_connection_id += 1;
+
+    let counters = Arc::new(TrafficCounters::default());
+    registry.blaze_active.insert(
+        id,
+        ActiveConnection {
+            peer,
+            started: Instant::now(),
+            counters: counters.clone(),
+        },
+    );
+
+    ConnectionStatsHandle {
+        id,
+        counters,
+        finished: false,
+    }
+}
+
+/// Records a completed HTTP request
+///
+/// ## Arguments
+/// * `stats` - The request stats
+pub(crate) fn record_http_request(stats: HttpRequestStats) {
+    let registry = &mut *registry().lock();
+
+    registry.http_totals.count += 1;
+    registry.http_totals.traffic.add(&stats.traffic);
+    registry.http_totals.duration += stats.duration;
+
+    push_recent(&mut registry.http_recent, stats);
+}
+
+/// Obtains the counters for a tunnel pool socket
+///
+/// ## Arguments
+/// * `kind`  - The tunnel the socket belongs to
+/// * `index` - Index of the socket within the pool
+pub(crate) fn tunnel_socket(kind: ServerKind, index: u8) -> Arc<TrafficCounters> {
+    let registry = &mut *registry().lock();
+
+    registry
+        .tunnel_sockets
+        .entry((kind, index))
+        .or_default()
+        .clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{blaze_connection, snapshot};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    /// Tests that a connection is moved to the closed connections with
+    /// its traffic when the handle is dropped without being finished
+    #[test]
+    fn test_connection_recorded_on_drop() {
+        // Unique peer so the connection can be found in the shared registry
+        let peer = SocketAddr::from((Ipv4Addr::new(127, 0, 0, 8), 40008));
+
+        let handle = blaze_connection(peer);
+        handle.counters().record_to_server(10);
+        handle.counters().record_to_server(5);
+        handle.counters().record_from_server(7);
+
+        let active = snapshot()
+            .blaze_active
+            .into_iter()
+            .find(|connection| connection.peer == peer)
+            .expect("connection should be active");
+        assert!(!active.closed);
+        assert_eq!(active.traffic.bytes_to_server, 15);
+
+        drop(handle);
+
+        let snapshot = snapshot();
+        assert!(snapshot
+            .blaze_active
+            .iter()
+            .all(|connection| connection.peer != peer));
+
+        let closed = snapshot
+            .blaze_recent
+            .iter()
+            .find(|connection| connection.peer == peer)
+            .expect("connection should be recorded as closed");
+        assert!(closed.closed);
+        assert_eq!(closed.error, None);
+        assert_eq!(closed.traffic.bytes_to_server, 15);
+        assert_eq!(closed.traffic.packets_to_server, 2);
+        assert_eq!(closed.traffic.bytes_from_server, 7);
+        assert_eq!(closed.traffic.packets_from_server, 1);
+        assert_eq!(closed.traffic.errors, 0);
+    }
+
+    /// Tests that finishing with an error records the error once and
+    /// isn't recorded a second time when the handle is dropped
+    #[test]
+    fn test_connection_finished_with_error() {
+        let peer = SocketAddr::from((Ipv4Addr::new(127, 0, 0, 9), 40009));
+
+        blaze_connection(peer).finish(Some("reset".to_string()));
+
+        let closed: Vec<_> = snapshot()
+            .blaze_recent
+            .into_iter()
+            .filter(|connection| connection.peer == peer)
+            .collect();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].error.as_deref(), Some("reset"));
+        assert_eq!(closed[0].traffic.errors, 1);
+    }
+}
@@ -0,0 +1,197 @@
+//! Transports for the upgraded server connections used by the Blaze
+//! stream and the HTTP tunnel
+//!
+//! The connections are normally created using a raw HTTP upgrade to the
+//! `blaze` or `tunnel` protocol. Some reverse proxies and middleboxes strip
+//! or reject these upgrades so the same byte stream can instead be carried
+//! within the binary messages of a standard WebSocket connection, the
+//! protocol is then selected using the `Sec-WebSocket-Protocol` header
+
+use bytes::Bytes;
+use futures::{Sink, Stream};
+use std::{
+    io::ErrorKind,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+/// Transport used for upgrading the server connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeTransport {
+    /// Raw HTTP upgrade to the connection protocol
+    Raw,
+    /// WebSocket upgrade carrying the connection protocol
+    WebSocket,
+}
+
+/// Byte stream that can be used for a server connection
+pub trait ServerIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> ServerIo for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Upgraded server connection using either transport
+pub type ServerStream = Box<dyn ServerIo>;
+
+/// Byte stream over the binary messages of a WebSocket
+pub(crate) struct WebSocketIo<S> {
+    /// The underlying WebSocket
+    inner: WebSocketStream<S>,
+    /// Remaining bytes of the last message that haven't been read
+    pending: Bytes,
+}
+
+impl<S> WebSocketIo<S> {
+    /// Creates a byte stream from the provided WebSocket
+    ///
+    /// ## Arguments
+    /// * `inner` - The underlying WebSocket
+    pub(crate) fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+/// Converts a WebSocket error into an IO error
+fn io_error(err: tokio_tungstenite::tungstenite::Error) -> std::io::Error {
+    match err {
+        tokio_tungstenite::tungstenite::Error::Io(err) => err,
+        err => std::io::Error::other(err),
+    }
+}
+
+impl<S> AsyncRead for WebSocketIo<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.pending.is_empty() {
+            let message = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(value)) => value,
+                Some(Err(err)) => return Poll::Ready(Err(io_error(err))),
+                // Closed connections are treated as the end of the stream
+                None => return Poll::Ready(Ok(())),
+            };
+
+            this.pending = match message {
+                Message::Binary(data) => Bytes::from(data),
+                Message::Text(text) => Bytes::from(text),
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                // Pings are answered by the WebSocket itself
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            };
+        }
+
+        let count = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending.split_to(count));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WebSocketIo<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(io_error)?;
+
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+
+        // Messages are buffered by the WebSocket until flushed, start writing
+        // the message now so that callers that never flush aren't stalled. A
+        // pending flush is continued by the next write or flush
+        if let Poll::Ready(Err(err)) = Pin::new(&mut this.inner).poll_flush(cx) {
+            return Poll::Ready(Err(io_error(err)));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_close(cx)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            // Connection was already closed by the other side
+            Err(err) => match io_error(err) {
+                err if err.kind() == ErrorKind::NotConnected => Poll::Ready(Ok(())),
+                err => Poll::Ready(Err(err)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WebSocketIo;
+    use futures::{SinkExt, StreamExt};
+    use std::{net::Ipv4Addr, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+    use tokio_tungstenite::{accept_async, client_async, tungstenite::Message};
+
+    /// Maximum time to wait for something that should happen promptly
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Tests that bytes written to the stream round-trip through a local
+    /// WebSocket server without the writer flushing
+    #[tokio::test]
+    async fn test_round_trip() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Server that echos back binary messages
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = socket.next().await {
+                if let Message::Binary(data) = message {
+                    socket.send(Message::Binary(data)).await.unwrap();
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = client_async(format!("ws://{addr}/"), stream).await.unwrap();
+        let mut io = WebSocketIo::new(socket);
+
+        // Small exchanges must not wait for the write buffer to fill
+        for message in [&b"ping"[..], b"a longer second message"] {
+            io.write_all(message).await.unwrap();
+
+            let mut buffer = vec![0u8; message.len()];
+            timeout(WAIT, io.read_exact(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(buffer, message);
+        }
+
+        // Shutdown closes the WebSocket and ends the server
+        io.shutdown().await.unwrap();
+        timeout(WAIT, server).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+//! Trust configuration for the server connection, allows trusting extra
+//! root certificates and pinning the certificate of a server
+//!
+//! Servers using self-signed certificates can be pinned on first use, the
+//! certificate presented by the server is fetched without verification and
+//! its hash is stored with the server entry. Later connections compare the
+//! presented certificate against the pin, the pinned certificate is then
+//! provided as [`crate::api::HttpClientConfig::pinned_certificate`] so that
+//! the HTTP client trusts only that certificate
+
+use crate::proxy::ProxyConfig;
+use openssl::{
+    error::ErrorStack,
+    sha::sha256,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+    x509::{X509VerifyResult, X509},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter},
+    path::Path,
+    pin::Pin,
+};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use url::Url;
+
+/// Errors that can occur when loading root certificates
+#[derive(Debug, Error)]
+pub enum RootCertificateError {
+    /// Failed to read the certificate file
+    #[error("Failed to read root certificates: {0}")]
+    Read(#[from] std::io::Error),
+    /// Certificate file was malformed
+    #[error("Invalid root certificates: {0}")]
+    Invalid(#[from] ErrorStack),
+    /// Certificate file didn't contain any certificates
+    #[error("No certificates found in root certificate file")]
+    Empty,
+    /// Failed to create the certificate for the HTTP client
+    #[error("Failed to create root certificate: {0}")]
+    Create(#[from] reqwest::Error),
+}
+
+/// Reads the root certificates from a PEM file which may
+/// contain multiple certificates
+///
+/// ## Arguments
+/// * `path` - The path to the PEM file
+pub fn read_root_certificates(
+    path: &Path,
+) -> Result<Vec<reqwest::Certificate>, RootCertificateError> {
+    let bytes = std::fs::read(path)?;
+    let certificates = X509::stack_from_pem(&bytes)?;
+
+    if certificates.is_empty() {
+        return Err(RootCertificateError::Empty);
+    }
+
+    certificates
+        .iter()
+        .map(|certificate| {
+            let der = certificate.to_der()?;
+            Ok(reqwest::Certificate::from_der(&der)?)
+        })
+        .collect()
+}
+
+/// Part of the certificate that a pin is created from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PinKind {
+    /// The whole certificate, changes whenever the certificate is renewed
+    Certificate,
+    /// The certificate public key (SPKI), survives renewals using the same key
+    Spki,
+}
+
+/// Pinned server certificate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CertificatePin {
+    /// What the pin was created from
+    pub kind: PinKind,
+    /// Lowercase hex SHA-256 hash of the pinned data
+    pub sha256: String,
+}
+
+impl Display for CertificatePin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            PinKind::Certificate => "cert",
+            PinKind::Spki => "spki",
+        };
+
+        write!(f, "{}-sha256:{}", kind, self.sha256)
+    }
+}
+
+/// Errors that can occur when pinning a server certificate
+#[derive(Debug, Error)]
+pub enum PinError {
+    /// Server URL isn't using HTTPS so there's no certificate to pin
+    #[error("Server is not using HTTPS")]
+    NotHttps,
+    /// Failed to connect to the server
+    #[error("Failed to connect to server: {0}")]
+    Connect(#[from] std::io::Error),
+    /// TLS handshake with the server failed
+    #[error("TLS handshake failed: {0}")]
+    Handshake(String),
+    /// Server didn't present a certificate
+    #[error("Server did not present a certificate")]
+    MissingCertificate,
+    /// Failed to process the certificate
+    #[error("Invalid server certificate: {0}")]
+    Invalid(#[from] ErrorStack),
+    /// Server presented a certificate that doesn't match the pin
+    #[error("Server certificate does not match the pinned certificate (expected {expected}, got {actual})")]
+    Mismatch {
+        /// The stored pin
+        expected: CertificatePin,
+        /// Pin of the certificate the server presented
+        actual: CertificatePin,
+    },
+}
+
+/// Certificate presented by a server
+#[derive(Debug, Clone)]
+pub struct ServerCertificate {
+    /// The certificate
+    certificate: X509,
+}
+
+impl ServerCertificate {
+    /// Creates a pin for the certificate
+    ///
+    /// ## Arguments
+    /// * `kind` - What to create the pin from
+    pub fn pin(&self, kind: PinKind) -> Result<CertificatePin, ErrorStack> {
+        let der = match kind {
+            PinKind::Certificate => self.certificate.to_der()?,
+            PinKind::Spki => self.certificate.public_key()?.public_key_to_der()?,
+        };
+
+        let sha256 = sha256(&der)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Ok(CertificatePin { kind, sha256 })
+    }
+
+    /// Checks whether the certificate matches the provided pin
+    ///
+    /// ## Arguments
+    /// * `pin` - The pin to check against
+    pub fn verify(&self, pin: &CertificatePin) -> Result<(), PinError> {
+        let actual = self.pin(pin.kind)?;
+
+        if actual.sha256.eq_ignore_ascii_case(&pin.sha256) {
+            Ok(())
+        } else {
+            Err(PinError::Mismatch {
+                expected: pin.clone(),
+                actual,
+            })
+        }
+    }
+
+    /// Human readable subject of the certificate
+    pub fn subject(&self) -> String {
+        self.certificate
+            .subject_name()
+            .entries()
+            .filter_map(|entry| {
+                let name = entry.object().nid().short_name().ok()?;
+                let value = entry.data().to_string().ok()?;
+                Some(format!("{}={}", name, value))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Expiry date of the certificate
+    pub fn not_after(&self) -> String {
+        self.certificate.not_after().to_string()
+    }
+
+    /// Whether the certificate is self-signed, only self-signed certificates
+    /// can be trusted on their own using [`ServerCertificate::to_reqwest`] as
+    /// certificates issued by a CA need the rest of their chain to verify
+    pub fn is_self_signed(&self) -> bool {
+        self.certificate.issued(&self.certificate) == X509VerifyResult::OK
+            && self
+                .certificate
+                .public_key()
+                .and_then(|key| self.certificate.verify(&key))
+                .unwrap_or(false)
+    }
+
+    /// Creates a root certificate from this certificate so that the HTTP
+    /// client will trust the server, only for self-signed certificates,
+    /// see [`crate::api::HttpClientConfig::pinned_certificate`]
+    pub fn to_reqwest(&self) -> Result<reqwest::Certificate, RootCertificateError> {
+        let der = self.certificate.to_der()?;
+        Ok(reqwest::Certificate::from_der(&der)?)
+    }
+}
+
+/// Fetches the certificate presented by the server without verifying it,
+/// the certificate must only be trusted after checking it against a pin
+///
+/// ## Arguments
+/// * `url`   - The server URL
+/// * `proxy` - The proxy to connect through, should be the proxy used by the HTTP client
+pub async fn fetch_server_certificate(
+    url: &Url,
+    proxy: Option<&ProxyConfig>,
+) -> Result<ServerCertificate, PinError> {
+    if url.scheme() != "https" {
+        return Err(PinError::NotHttps);
+    }
+
+    let host = url.host_str().ok_or(PinError::NotHttps)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(host, port).await?,
+        None => TcpStream::connect((host, port)).await?,
+    };
+
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let ssl = builder
+        .build()
+        .configure()?
+        .verify_hostname(false)
+        .into_ssl(host)?;
+
+    let mut stream = SslStream::new(ssl, stream)?;
+    Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(|err| PinError::Handshake(err.to_string()))?;
+
+    let certificate = stream
+        .ssl()
+        .peer_certificate()
+        .ok_or(PinError::MissingCertificate)?;
+
+    Ok(ServerCertificate { certificate })
+}
+
+/// Checks the server certificate against the stored pin, when there is no
+/// stored pin the server certificate is pinned (Trust on first use)
+///
+/// ## Arguments
+/// * `url`   - The server URL
+/// * `proxy` - The proxy to connect through
+/// * `pin`   - The stored pin, replaced with the new pin on first use
+/// * `kind`  - What to create the pin from on first use
+pub async fn trust_on_first_use(
+    url: &Url,
+    proxy: Option<&ProxyConfig>,
+    pin: &mut Option<CertificatePin>,
+    kind: PinKind,
+) -> Result<ServerCertificate, PinError> {
+    let certificate = fetch_server_certificate(url, proxy).await?;
+
+    match pin {
+        Some(pin) => certificate.verify(pin)?,
+        None => *pin = Some(certificate.pin(kind)?),
+    }
+
+    Ok(certificate)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{
+        fetch_server_certificate, trust_on_first_use, CertificatePin, PinError, PinKind,
+        ServerCertificate,
+    };
+    use crate::{
+        api::{create_http_client, HttpClientConfig},
+        proxy::{ProxyConfig, ProxyKind},
+    };
+    use openssl::{
+        asn1::{Asn1Time, Asn1TimeRef},
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        ssl::{Ssl, SslAcceptor, SslMethod},
+        x509::{
+            extension::{BasicConstraints, SubjectAlternativeName},
+            X509NameBuilder, X509,
+        },
+    };
+    use std::{net::Ipv4Addr, pin::Pin, sync::Arc};
+    use tokio::{
+        io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_openssl::SslStream;
+    use url::Url;
+
+    /// Creates a self signed certificate for localhost and its private key,
+    /// shared with the tests of pinned profile imports and client identities
+    pub(crate) fn self_signed() -> (X509, PKey<Private>) {
+        let key = generate_key();
+        let cert = certificate(&key, None, &Asn1Time::days_from_now(1).unwrap());
+        (cert, key)
+    }
+
+    /// Generates a private key for a test certificate
+    pub(crate) fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// Creates a certificate for localhost using the key that expires at
+    /// `not_after`. The certificate is signed by the issuer certificate and
+    /// key, without an issuer the certificate is a self signed CA. The CA
+    /// uses a different common name so that issued certificates aren't
+    /// mistaken for self-issued certificates
+    pub(crate) fn certificate(
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        not_after: &Asn1TimeRef,
+    ) -> X509 {
+        let common_name = match issuer {
+            Some(_) => "localhost",
+            None => "localhost CA",
+        };
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::from_unix(0).unwrap())
+            .unwrap();
+        cert.set_not_after(not_after).unwrap();
+
+        let (issuer_cert, signing_key) = match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                cert.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                (Some(issuer_cert), issuer_key)
+            }
+            None => {
+                cert.set_issuer_name(&name).unwrap();
+                let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+                cert.append_extension(constraints).unwrap();
+                (None, key)
+            }
+        };
+
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(issuer_cert.map(|cert| &**cert), None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(signing_key, MessageDigest::sha256()).unwrap();
+
+        cert.build()
+    }
+
+    /// Creates a pin for the certificate
+    pub(crate) fn pin(certificate: X509, kind: PinKind) -> CertificatePin {
+        ServerCertificate { certificate }.pin(kind).unwrap()
+    }
+
+    /// Starts a HTTPS server presenting the certificate that responds
+    /// to every request with the body, provides the server URL
+    pub(crate) async fn start_server(cert: &X509, key: &PKey<Private>, body: &str) -> Url {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let response: Arc<[u8]> = Arc::from(response.into_bytes());
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_certificate(cert).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let response = response.clone();
+                tokio::spawn(async move {
+                    let mut stream = SslStream::new(ssl, stream).unwrap();
+                    if Pin::new(&mut stream).accept().await.is_err() {
+                        return;
+                    }
+
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read_u8().await {
+                            Ok(byte) => head.push(byte),
+                            Err(_) => return,
+                        }
+                    }
+
+                    _ = stream.write_all(&response).await;
+                    _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        format!("https://localhost:{}/", port).parse().unwrap()
+    }
+
+    /// Starts a HTTP proxy that answers CONNECT requests by connecting to
+    /// the target on the loopback address
+    async fn start_proxy() -> ProxyConfig {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+
+                let head = String::from_utf8(head).unwrap();
+                let target = head
+                    .strip_prefix("CONNECT localhost:")
+                    .and_then(|value| value.split_whitespace().next())
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .expect("Unexpected CONNECT request");
+
+                let mut upstream = TcpStream::connect((Ipv4Addr::LOCALHOST, target))
+                    .await
+                    .unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                _ = copy_bidirectional(&mut stream, &mut upstream).await;
+            }
+        });
+
+        ProxyConfig {
+            kind: ProxyKind::Http,
+            host: Ipv4Addr::LOCALHOST.to_string(),
+            port,
+            auth: None,
+        }
+    }
+
+    /// Tests pinning a certificate on first use, then accepting the same
+    /// certificate and rejecting a different certificate
+    #[tokio::test]
+    async fn test_trust_on_first_use() {
+        let (cert, key) = self_signed();
+        let (other, _) = self_signed();
+        let url = start_server(&cert, &key, "ok").await;
+
+        let mut pin = None;
+        trust_on_first_use(&url, None, &mut pin, PinKind::Spki)
+            .await
+            .unwrap();
+
+        let expected = ServerCertificate { certificate: cert }
+            .pin(PinKind::Spki)
+            .unwrap();
+        assert_eq!(pin.as_ref(), Some(&expected));
+
+        trust_on_first_use(&url, None, &mut pin, PinKind::Spki)
+            .await
+            .unwrap();
+
+        let mut pin = Some(
+            ServerCertificate { certificate: other }
+                .pin(PinKind::Certificate)
+                .unwrap(),
+        );
+        let result = trust_on_first_use(&url, None, &mut pin, PinKind::Certificate).await;
+        assert!(matches!(result, Err(PinError::Mismatch { .. })));
+    }
+
+    /// Tests fetching the server certificate through a HTTP proxy
+    #[tokio::test]
+    async fn test_fetch_through_proxy() {
+        let (cert, key) = self_signed();
+        let url = start_server(&cert, &key, "ok").await;
+        let proxy = start_proxy().await;
+
+        let certificate = fetch_server_certificate(&url, Some(&proxy)).await.unwrap();
+
+        assert_eq!(
+            certificate.certificate.to_der().unwrap(),
+            cert.to_der().unwrap()
+        );
+    }
+
+    /// Tests that only certificates signed by their own key are self-signed
+    #[test]
+    fn test_is_self_signed() {
+        let (ca, ca_key) = self_signed();
+        let leaf = certificate(
+            &generate_key(),
+            Some((&ca, &ca_key)),
+            &Asn1Time::days_from_now(1).unwrap(),
+        );
+
+        assert!(ServerCertificate { certificate: ca }.is_self_signed());
+        assert!(!ServerCertificate { certificate: leaf }.is_self_signed());
+    }
+
+    /// Tests that a client with a pinned certificate only connects to
+    /// servers presenting the pinned certificate
+    #[tokio::test]
+    async fn test_pinned_client() {
+        let (cert, key) = self_signed();
+        let (other, _) = self_signed();
+        let url = start_server(&cert, &key, "ok").await;
+
+        let client = |certificate: X509| {
+            create_http_client(HttpClientConfig {
+                pinned_certificate: Some(ServerCertificate { certificate }.to_reqwest().unwrap()),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        let body = client(cert)
+            .client()
+            .get(url.clone())
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "ok");
+
+        assert!(client(other).client().get(url).send().await.is_err());
+    }
+}